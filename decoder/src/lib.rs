@@ -0,0 +1,428 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Decoder for transactions of the streamflow timelock program,
+//! turning instruction data and log messages into typed values.
+use std::str::FromStr;
+
+use borsh::BorshDeserialize;
+use solana_program::pubkey::Pubkey;
+use thiserror::Error;
+
+pub use streamflow_timelock::event::StreamEvent;
+pub use streamflow_timelock::instruction::TimelockInstruction;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum DecodeError {
+    #[error("Invalid instruction data")]
+    InvalidInstruction,
+
+    #[error("Invalid event data: {0}")]
+    InvalidEvent(String),
+
+    #[error("Instruction data doesn't match the logged invocations")]
+    InvocationMismatch,
+}
+
+/// Event reconstructed from the `msg!` logs of program versions that
+/// didn't emit `StreamEvent`s. Amounts logged with their decimals
+/// applied are kept as the logged strings.
+#[derive(Clone, Debug, PartialEq)]
+pub enum LegacyEvent {
+    StreamCreated {
+        metadata: Pubkey,
+        sender: Pubkey,
+        recipient: Pubkey,
+        mint: Pubkey,
+        escrow_tokens: Pubkey,
+        deposited_amount: String,
+    },
+    Withdrawn {
+        mint: Pubkey,
+        amount: String,
+        remaining: String,
+    },
+    Canceled {
+        recipient_amount: u64,
+        sender_amount: u64,
+    },
+    /// Older versions log nothing about the new recipient
+    RecipientTransferred,
+    ToppedUp {
+        escrow_tokens: Pubkey,
+        sender: Pubkey,
+        amount: String,
+    },
+}
+
+/// Event of a single program invocation
+#[derive(Clone, Debug, PartialEq)]
+pub enum TimelockEvent {
+    Event(StreamEvent),
+    Legacy(LegacyEvent),
+}
+
+/// Events logged by one invocation of the program
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Invocation {
+    /// Invoke depth, 1 for instructions of the transaction itself
+    pub depth: usize,
+    /// Whether the invocation finished successfully. Events of failed
+    /// invocations never took effect.
+    pub success: bool,
+    pub events: Vec<TimelockEvent>,
+    logs: Vec<String>,
+}
+
+/// A decoded top-level instruction along with its events
+#[derive(Clone, Debug, PartialEq)]
+pub struct DecodedInstruction {
+    pub instruction: TimelockInstruction,
+    pub success: bool,
+    pub events: Vec<TimelockEvent>,
+}
+
+/// Decode the instruction data of a timelock instruction
+pub fn decode_instruction(data: &[u8]) -> Result<TimelockInstruction, DecodeError> {
+    TimelockInstruction::try_from_slice(data).map_err(|_| DecodeError::InvalidInstruction)
+}
+
+/// Decode the invocations of `program_id` from a transaction's log messages,
+/// in the order they were invoked.
+pub fn decode_logs(program_id: &Pubkey, logs: &[String]) -> Result<Vec<Invocation>, DecodeError> {
+    let program_id = program_id.to_string();
+    let mut invocations: Vec<Invocation> = vec![];
+    // Programs currently executing, with the index of their invocation
+    let mut stack: Vec<(String, Option<usize>)> = vec![];
+
+    for log in logs {
+        if let Some(rest) = log.strip_prefix("Program ") {
+            let mut words = rest.split(' ');
+            let id = words.next().unwrap_or_default();
+            match words.next() {
+                Some("invoke") => {
+                    let index = if id == program_id {
+                        invocations.push(Invocation {
+                            depth: stack.len() + 1,
+                            ..Invocation::default()
+                        });
+                        Some(invocations.len() - 1)
+                    } else {
+                        None
+                    };
+                    stack.push((id.to_string(), index));
+                    continue;
+                }
+                Some("success") | Some("failed:")
+                    if stack.last().map(|p| p.0.as_str()) == Some(id) =>
+                {
+                    if let Some((_, Some(index))) = stack.pop() {
+                        invocations[index].success = log.ends_with(" success");
+                    }
+                    continue;
+                }
+                _ => {}
+            }
+        }
+
+        let index = match stack.last() {
+            Some((_, Some(index))) => *index,
+            _ => continue,
+        };
+
+        if let Some(data) = log.strip_prefix("Program data: ") {
+            invocations[index]
+                .events
+                .push(TimelockEvent::Event(decode_event(data)?));
+        } else if let Some(message) = log.strip_prefix("Program log: ") {
+            invocations[index].logs.push(message.to_string());
+        }
+    }
+
+    for invocation in invocations.iter_mut() {
+        if invocation.events.is_empty() {
+            if let Some(event) = decode_legacy(&invocation.logs) {
+                invocation.events.push(TimelockEvent::Legacy(event));
+            }
+        }
+    }
+
+    Ok(invocations)
+}
+
+/// Decode the timelock instructions of a transaction, given in order, and
+/// pair each of them with the events it logged.
+pub fn decode_transaction(
+    program_id: &Pubkey,
+    instructions: &[&[u8]],
+    logs: &[String],
+) -> Result<Vec<DecodedInstruction>, DecodeError> {
+    let invocations: Vec<Invocation> = decode_logs(program_id, logs)?
+        .into_iter()
+        .filter(|i| i.depth == 1)
+        .collect();
+
+    // Logs of failed transactions stop at the failing instruction
+    if invocations.len() > instructions.len() {
+        return Err(DecodeError::InvocationMismatch);
+    }
+
+    let mut invocations = invocations.into_iter();
+    instructions
+        .iter()
+        .map(|data| {
+            let invocation = invocations.next().unwrap_or_default();
+            Ok(DecodedInstruction {
+                instruction: decode_instruction(data)?,
+                success: invocation.success,
+                events: invocation.events,
+            })
+        })
+        .collect()
+}
+
+/// Decode a `Program data:` log line into a `StreamEvent`
+pub fn decode_event(data: &str) -> Result<StreamEvent, DecodeError> {
+    let bytes = base64::decode(data).map_err(|e| DecodeError::InvalidEvent(e.to_string()))?;
+    StreamEvent::try_from_slice(&bytes).map_err(|e| DecodeError::InvalidEvent(e.to_string()))
+}
+
+/// Find the first log line starting with `prefix` and return the rest
+fn find<'a>(logs: &'a [String], prefix: &str) -> Option<&'a str> {
+    logs.iter().find_map(|l| l.strip_prefix(prefix))
+}
+
+/// Parse a "<amount> <mint> tokens" log
+fn amount_and_mint(s: &str) -> Option<(String, Pubkey)> {
+    let mut words = s.split(' ');
+    let amount = words.next()?.to_string();
+    let mint = Pubkey::from_str(words.next()?).ok()?;
+    Some((amount, mint))
+}
+
+fn decode_legacy(logs: &[String]) -> Option<LegacyEvent> {
+    match logs.first()?.as_str() {
+        "Initializing SPL token stream" => {
+            let created = find(logs, "Successfully initialized ")?;
+            let (deposited_amount, mint) = amount_and_mint(created)?;
+            let recipient = created.rsplit(' ').next()?;
+            Some(LegacyEvent::StreamCreated {
+                metadata: Pubkey::from_str(find(logs, "Metadata written in ")?).ok()?,
+                sender: Pubkey::from_str(find(logs, "Called by ")?).ok()?,
+                recipient: Pubkey::from_str(recipient).ok()?,
+                mint,
+                escrow_tokens: Pubkey::from_str(find(logs, "Funds locked in ")?).ok()?,
+                deposited_amount,
+            })
+        }
+        "Withdrawing from SPL token stream" => {
+            let (amount, mint) = amount_and_mint(find(logs, "Withdrawn: ")?)?;
+            let (remaining, _) = amount_and_mint(find(logs, "Remaining: ")?)?;
+            Some(LegacyEvent::Withdrawn {
+                mint,
+                amount,
+                remaining,
+            })
+        }
+        "Cancelling SPL token stream" => {
+            // "Deposited {} , withdrawn: {}, tokens remain {}"
+            let recipient_amount = find(logs, "Available ")?.parse().ok()?;
+            let remains = find(logs, "Deposited ")?.rsplit(' ').next()?;
+            Some(LegacyEvent::Canceled {
+                recipient_amount,
+                sender_amount: remains.parse().ok()?,
+            })
+        }
+        "Transferring stream recipient" => Some(LegacyEvent::RecipientTransferred),
+        "Topping up the escrow account" => {
+            // "Successfully topped up {} to token stream {} on behalf of {}"
+            let topped_up = find(logs, "Successfully topped up ")?;
+            let words: Vec<&str> = topped_up.split(' ').collect();
+            if words.len() != 9 {
+                return None;
+            }
+            Some(LegacyEvent::ToppedUp {
+                escrow_tokens: Pubkey::from_str(words[4]).ok()?,
+                sender: Pubkey::from_str(words[8]).ok()?,
+                amount: words[0].to_string(),
+            })
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use borsh::BorshSerialize;
+
+    fn logs(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn test_decode_events() {
+        let program_id = Pubkey::new_unique();
+        let other_program = Pubkey::new_unique();
+        let event = StreamEvent::Withdrawn {
+            metadata: Pubkey::new_unique(),
+            authority: Pubkey::new_unique(),
+            recipient_tokens: Pubkey::new_unique(),
+            amount: 15,
+            withdrawn_amount: 30,
+            timestamp: 1_600_000_000,
+        };
+        let data = base64::encode(event.try_to_vec().unwrap());
+        let other_event = base64::encode([1, 2, 3]);
+
+        let logs = logs(&[
+            &format!("Program {} invoke [1]", program_id),
+            "Program log: Withdrawing from SPL token stream",
+            &format!("Program {} invoke [2]", other_program),
+            &format!("Program data: {}", other_event),
+            &format!("Program {} success", other_program),
+            "Program log: Withdrawn: 0.00000015 So11111111111111111111111111111111111111112 tokens",
+            &format!("Program data: {}", data),
+            &format!(
+                "Program {} consumed 12345 of 200000 compute units",
+                program_id
+            ),
+            &format!("Program {} success", program_id),
+            &format!("Program {} invoke [1]", program_id),
+            "Program log: Cancelling SPL token stream",
+            &format!("Program {} failed: custom program error: 0x5", program_id),
+        ]);
+
+        let invocations = decode_logs(&program_id, &logs).unwrap();
+        assert_eq!(invocations.len(), 2);
+        assert!(invocations[0].success);
+        assert_eq!(invocations[0].depth, 1);
+        assert_eq!(
+            invocations[0].events,
+            vec![TimelockEvent::Event(event.clone())]
+        );
+        assert!(!invocations[1].success);
+        assert!(invocations[1].events.is_empty());
+
+        let withdraw = TimelockInstruction::Withdraw { amount: 0 }
+            .try_to_vec()
+            .unwrap();
+        let cancel = TimelockInstruction::Cancel.try_to_vec().unwrap();
+        let decoded = decode_transaction(&program_id, &[&withdraw, &cancel], &logs).unwrap();
+        assert_eq!(
+            decoded[0].instruction,
+            TimelockInstruction::Withdraw { amount: 0 }
+        );
+        assert_eq!(decoded[0].events, vec![TimelockEvent::Event(event)]);
+        assert_eq!(decoded[1].instruction, TimelockInstruction::Cancel);
+        assert!(!decoded[1].success);
+
+        assert_eq!(
+            decode_transaction(&program_id, &[&withdraw], &logs),
+            Err(DecodeError::InvocationMismatch)
+        );
+        assert_eq!(
+            decode_instruction(&[9]),
+            Err(DecodeError::InvalidInstruction)
+        );
+
+        let broken = vec![
+            format!("Program {} invoke [1]", program_id),
+            "Program data: AAAA".to_string(),
+        ];
+        assert!(matches!(
+            decode_logs(&program_id, &broken),
+            Err(DecodeError::InvalidEvent(_))
+        ));
+    }
+
+    #[test]
+    fn test_decode_legacy() {
+        let program_id = Pubkey::new_unique();
+        let [sender, recipient, mint, metadata, escrow] = [(); 5].map(|_| Pubkey::new_unique());
+
+        let logs = logs(&[
+            &format!("Program {} invoke [1]", program_id),
+            "Program log: Initializing SPL token stream",
+            &format!(
+                "Program log: Successfully initialized 10 {} token stream for {}",
+                mint, recipient
+            ),
+            &format!("Program log: Called by {}", sender),
+            &format!("Program log: Metadata written in {}", metadata),
+            &format!("Program log: Funds locked in {}", escrow),
+            "Program log: Stream duration is 0 days, 0 hours, 16 minutes, 40 seconds",
+            &format!("Program {} success", program_id),
+            &format!("Program {} invoke [1]", program_id),
+            "Program log: Withdrawing from SPL token stream",
+            &format!("Program log: Withdrawn: 1.5 {} tokens", mint),
+            &format!("Program log: Remaining: 8.5 {} tokens", mint),
+            &format!("Program {} success", program_id),
+            &format!("Program {} invoke [1]", program_id),
+            "Program log: Cancelling SPL token stream",
+            "Program log: Now: 1600000100, closable at 1600001000",
+            "Program log: Available 100000000",
+            "Program log: Amount 850000000",
+            "Program log: Amount 750000000",
+            "Program log: Deposited 1000000000 , withdrawn: 250000000, tokens remain 750000000",
+            &format!("Program {} success", program_id),
+            &format!("Program {} invoke [1]", program_id),
+            "Program log: Topping up the escrow account",
+            &format!(
+                "Program log: Successfully topped up 2 to token stream {} on behalf of {}",
+                escrow, sender
+            ),
+            &format!("Program {} success", program_id),
+            &format!("Program {} invoke [1]", program_id),
+            "Program log: Transferring stream recipient",
+            &format!("Program {} success", program_id),
+        ]);
+
+        let events: Vec<TimelockEvent> = decode_logs(&program_id, &logs)
+            .unwrap()
+            .into_iter()
+            .flat_map(|i| i.events)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                TimelockEvent::Legacy(LegacyEvent::StreamCreated {
+                    metadata,
+                    sender,
+                    recipient,
+                    mint,
+                    escrow_tokens: escrow,
+                    deposited_amount: "10".to_string(),
+                }),
+                TimelockEvent::Legacy(LegacyEvent::Withdrawn {
+                    mint,
+                    amount: "1.5".to_string(),
+                    remaining: "8.5".to_string(),
+                }),
+                TimelockEvent::Legacy(LegacyEvent::Canceled {
+                    recipient_amount: 100000000,
+                    sender_amount: 750000000,
+                }),
+                TimelockEvent::Legacy(LegacyEvent::ToppedUp {
+                    escrow_tokens: escrow,
+                    sender,
+                    amount: "2".to_string(),
+                }),
+                TimelockEvent::Legacy(LegacyEvent::RecipientTransferred),
+            ]
+        );
+    }
+}
@@ -19,18 +19,33 @@
 #
 # const buffer = borsh.serialize(StreamInstruction, value);
 # const de_value = borsh.deserialize(StreamInstruction, Struct, buffer);
+#
+# Enum fields are set as class instances naming the variant, e.g.
+#
+#     vesting_curve: new VestingCurve({
+#         enum: "halving",
+#         halving: new VestingCurveHalving({ halving_periods: new BN(12) }),
+#     }),
 
 import re
 import sys
 from os.path import join
 from subprocess import run
 
-files = ["src/state.rs"]
+files = [
+    "src/state.rs", "src/layout.rs", "src/config.rs", "src/mint_registry.rs"
+]
 skips = [
     "InitializeAccounts", "WithdrawAccounts", "CancelAccounts",
-    "TransferAccounts", "TopUpAccounts"
+    "TransferAccounts", "TopUpAccounts", "PauseAccounts",
+    "MigrateAccounts", "InitConfigAccounts", "UpdateConfigAccounts",
+    "SetPauseAccounts", "MintRegistryAccounts", "AcceptAccounts",
+    "SetDestinationAccounts"
 ]
 structs = {}
+enums = {}
+consts = {}
+errors = []
 
 lifetimes = ["<'a>"]
 
@@ -55,6 +70,8 @@ def camel_to_snake(name):
 
 
 def lookup_layout(t, n):
+    if t == "u8":
+        return f"['{n}', 'u8'],"
     if t == "u32":
         return f"['{n}', 'u32'],"
     if t == "u64":
@@ -67,31 +84,97 @@ def lookup_layout(t, n):
     if t == "String":
         return f"['{n}', 'string'],"
 
+    # Fixed-size byte arrays, e.g. `[u8; STREAM_NAME_SIZE]`
+    array = re.match(r"\[u8; (\w+)\]", t)
+    if array:
+        size = array.group(1)
+        return f"['{n}', [{consts.get(size, size)}]],"
+
     return None
 
 
-def generate_schema(struct_name):
+def generate_schema(struct_name, extra, indent="        "):
     for i in structs[struct_name]:
         bl = lookup_layout(i[1], i[0])
 
         if bl:
-            print(f"        {bl}")
+            print(f"{indent}{bl}")
             continue
 
         if i[1] in structs:
-            generate_schema(i[1])
+            generate_schema(i[1], extra, indent)
+            continue
+
+        if i[1] in enums:
+            print(f"{indent}['{i[0]}', {i[1]}],")
+            extra.append(i[1])
             continue
 
         raise Exception(f"Unknown schema for {i[1]}")
 
 
+def lookup_class_layout(t, n, extra):
+    bl = lookup_layout(t, n)
+    if bl:
+        return bl
+
+    if t.startswith("Vec<") and t[4:-1] in structs:
+        extra.append(t[4:-1])
+        return f"['{n}', [{t[4:-1]}]],"
+
+    raise Exception(f"Unknown schema for {t}")
+
+
+def generate_enum_schema(enum_name, extra):
+    """Print the class schemas needed to (de)serialize an enum"""
+    values = []
+    for variant, fields in enums[enum_name]:
+        values.append(f"['{camel_to_snake(variant)}', {enum_name}{variant}]")
+
+    print(f"    [{enum_name}, {{")
+    print("        kind: 'enum',")
+    print("        field: 'enum',")
+    print(f"        values: [{', '.join(values)}],")
+    print("    }],")
+
+    for variant, fields in enums[enum_name]:
+        layouts = [lookup_class_layout(t, n, extra)[:-1] for n, t in fields]
+        print(f"    [{enum_name}{variant}, {{")
+        print("        kind: 'struct',")
+        print(f"        fields: [{', '.join(layouts)}],")
+        print("    }],")
+
+
+def generate_class_schema(class_name, extra):
+    if class_name in enums:
+        generate_enum_schema(class_name, extra)
+        return
+
+    print(f"    [{class_name}, {{")
+    print("        kind: 'struct',")
+    print("        fields: [")
+    generate_schema(class_name, extra, "            ")
+    print("        ],")
+    print("    }],")
+
+
 def parse_structs(lines):
     found = False
     struct_name = None
+    borsh_skip = False
 
     for i in lines:
+        # Fields skipped by Borsh aren't part of the schema
+        if found and i.strip() == "#[borsh_skip]":
+            borsh_skip = True
+            continue
+
         if found and not i.strip().startswith(
                 "//") and not i.strip().startswith("}"):
+            if borsh_skip:
+                borsh_skip = False
+                continue
+
             element = i.strip()[4:-1]
 
             # Remove Rust lifetimes
@@ -117,6 +200,65 @@ def parse_structs(lines):
             found = False
 
 
+def parse_consts(lines):
+    for i in lines:
+        const = re.match(r"pub const (\w+): usize = (\d+);", i)
+        if const:
+            consts[const.group(1)] = const.group(2)
+
+
+def parse_enums(lines):
+    enum_name = None
+
+    for i in lines:
+        if i.startswith("pub enum"):
+            enum_name = i.split()[2]
+            enums[enum_name] = []
+            continue
+
+        if enum_name is None:
+            continue
+
+        if i.startswith("}"):
+            enum_name = None
+            continue
+
+        element = i.strip()
+        if element.startswith("//") or element.startswith("#"):
+            continue
+
+        # `Name,`, `Name(Type),` or `Name { field: Type, .. },`
+        variant = re.match(r"(\w+)\s*(?:\((.*)\)|\{(.*)\})?,", element)
+        if not variant:
+            raise Exception(f"Unknown enum variant {element}")
+
+        name, tuple_fields, named_fields = variant.groups()
+        fields = []
+        if tuple_fields:
+            for n, t in enumerate(tuple_fields.split(", ")):
+                fields.append((str(n), t))
+        if named_fields:
+            for field in named_fields.strip().split(", "):
+                fields.append(tuple(field.split(": ")))
+
+        enums[enum_name].append((name, fields))
+
+
+def parse_errors(lines):
+    message = None
+
+    for i in lines:
+        error = re.match(r'\s*#\[error\("(.*)"\)\]', i)
+        if error:
+            message = error.group(1)
+            continue
+
+        variant = re.match(r"\s*(\w+),", i)
+        if variant and message is not None:
+            errors.append((variant.group(1), message))
+            message = None
+
+
 def main():
     output = run(["git", "rev-parse", "--show-toplevel"], capture_output=True)
     toplevel = output.stdout.decode()[:-1]
@@ -127,18 +269,58 @@ def main():
         f = open(join(toplevel, src_file), "r")
         lines = f.readlines()
         f.close()
+        parse_consts(lines)
         parse_structs(lines)
+        parse_enums(lines)
+
+    # Custom program error codes, `ProgramError::Custom(code)`
+    f = open(join(toplevel, "src/error.rs"), "r")
+    parse_errors(f.readlines())
+    f.close()
+
+    # Structs nested in enum variants are (de)serialized as classes
+    nested = []
+    for i in enums:
+        for _, fields in enums[i]:
+            for _, t in fields:
+                t = t[4:-1] if t.startswith("Vec<") else t
+                if t in structs and t not in nested:
+                    nested.append(t)
+
+    for i in enums:
+        print(f"class {i} extends Assignable {{}}")
+        for variant, _ in enums[i]:
+            print(f"class {i}{variant} extends Assignable {{}}")
+    for i in nested:
+        print(f"class {i} extends Assignable {{}}")
+    print()
 
     for i in structs:
-        if i in skips:
+        if i in skips or i in nested:
             continue
 
+        extra = []
         print(f"const {i} = new Map([[Struct, {{")
         print("    kind: 'struct',")
         print("    fields: [")
-        generate_schema(i)
+        generate_schema(i, extra)
         print("    ]")
-        print("}]]);\n")
+        print("}],")
+
+        done = []
+        while extra:
+            e = extra.pop(0)
+            if e in done:
+                continue
+            done.append(e)
+            generate_class_schema(e, extra)
+
+        print("]);\n")
+
+    print("const StreamFlowError = {")
+    for code, (name, message) in enumerate(errors):
+        print(f"    {code}: {{ name: '{name}', msg: \"{message}\" }},")
+    print("};")
 
     return 0
 
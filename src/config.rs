@@ -0,0 +1,400 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use borsh::{BorshDeserialize, BorshSerialize};
+use bytemuck::{Pod, Zeroable};
+use solana_program::{account_info::AccountInfo, msg, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::error::StreamFlowError::{
+    InstructionPaused, InvalidConfig, InvalidConfigAccount, ProgramPaused, TokenProgramNotAllowed,
+};
+use crate::instruction::find_config_address;
+use crate::layout::STREAM_NAME_SIZE;
+use crate::mint_registry::MintRegistryMode;
+use crate::token_2022;
+
+/// Seed of the program config account
+pub const CONFIG_SEED: &[u8] = b"config";
+/// Layout version of the program config account
+pub const CONFIG_VERSION: u64 = 1;
+/// Size of the program config account
+pub const CONFIG_SIZE: usize = 352;
+/// Fees are given in basis points of the deposited amount
+pub const MAX_BASIS_POINTS: u64 = 10_000;
+
+/// Stream instructions the guardian can pause, as `paused_instructions` flags
+pub const PAUSE_CREATE: u64 = 1 << 0;
+pub const PAUSE_WITHDRAW: u64 = 1 << 1;
+pub const PAUSE_CANCEL: u64 = 1 << 2;
+pub const PAUSE_TRANSFER: u64 = 1 << 3;
+pub const PAUSE_TOPUP: u64 = 1 << 4;
+pub const PAUSE_ALL: u64 =
+    PAUSE_CREATE | PAUSE_WITHDRAW | PAUSE_CANCEL | PAUSE_TRANSFER | PAUSE_TOPUP;
+
+/// Program-wide settings, stored in the PDA from `find_config_address`.
+///
+/// Created once by the program's upgrade authority with `InitConfig`,
+/// changed by the admin with `UpdateConfig`. Every stream instruction
+/// reads it. Flags are 0 or 1.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct ProgramConfig {
+    pub magic: u64,
+    /// Wallet allowed to update the config
+    pub admin: Pubkey,
+    /// Wallet collecting protocol fees
+    pub fee_treasury: Pubkey,
+    /// Longest accepted stream name, at most `STREAM_NAME_SIZE`
+    pub max_name_size: u64,
+    /// Protocol fee, in basis points of the deposited amount
+    pub fee_basis_points: u64,
+    /// Stream instructions are rejected while set
+    pub paused: u8,
+    /// Streams of `spl_token` mints can be created
+    pub spl_token_allowed: u8,
+    /// Streams of Token-2022 mints can be created
+    pub token_2022_allowed: u8,
+    pub bump: u8,
+    /// Senders can still cancel their streams while cancel, or the whole
+    /// program, is paused
+    pub sender_cancel_while_paused: u8,
+    /// `MintRegistryMode` of the mint registry
+    pub mint_registry_mode: u8,
+    pub padding: [u8; 2],
+    /// Partner fee, in basis points of the deposited amount, paid to
+    /// streams created with a partner
+    pub partner_fee_basis_points: u64,
+    /// Wallet allowed to pause single instructions with `SetPause`
+    pub guardian: Pubkey,
+    /// `PAUSE_*` flags of the instructions currently paused
+    pub paused_instructions: u64,
+    pub reserved: [u8; 208],
+}
+
+/// Settings taken by `InitConfig` and `UpdateConfig`
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct ConfigParams {
+    /// New admin, set it to the current one to keep it
+    pub admin: Pubkey,
+    pub fee_treasury: Pubkey,
+    pub max_name_size: u64,
+    pub fee_basis_points: u64,
+    pub paused: bool,
+    pub spl_token_allowed: bool,
+    pub token_2022_allowed: bool,
+    pub partner_fee_basis_points: u64,
+    pub guardian: Pubkey,
+    pub sender_cancel_while_paused: bool,
+    pub mint_registry_mode: MintRegistryMode,
+}
+
+/// Fee of `basis_points` on `amount`, rounded down
+pub fn fee_amount(amount: u64, basis_points: u64) -> u64 {
+    (amount as u128 * basis_points as u128 / MAX_BASIS_POINTS as u128) as u64
+}
+
+impl ConfigParams {
+    /// Check the settings are within what the program supports
+    pub fn validate(&self) -> Result<(), ProgramError> {
+        if self.max_name_size == 0 || self.max_name_size > STREAM_NAME_SIZE as u64 {
+            msg!("Error: Max name size must be 1 to {}", STREAM_NAME_SIZE);
+            return Err(InvalidConfig.into());
+        }
+
+        if self
+            .fee_basis_points
+            .saturating_add(self.partner_fee_basis_points)
+            > MAX_BASIS_POINTS
+        {
+            msg!(
+                "Error: Fees can't be over {} basis points",
+                MAX_BASIS_POINTS
+            );
+            return Err(InvalidConfig.into());
+        }
+
+        Ok(())
+    }
+}
+
+impl ProgramConfig {
+    /// Overwrite the settings with validated `params`
+    pub fn apply(&mut self, params: &ConfigParams) -> Result<(), ProgramError> {
+        params.validate()?;
+
+        self.admin = params.admin;
+        self.fee_treasury = params.fee_treasury;
+        self.max_name_size = params.max_name_size;
+        self.fee_basis_points = params.fee_basis_points;
+        self.paused = params.paused.into();
+        self.spl_token_allowed = params.spl_token_allowed.into();
+        self.token_2022_allowed = params.token_2022_allowed.into();
+        self.partner_fee_basis_points = params.partner_fee_basis_points;
+        self.guardian = params.guardian;
+        self.sender_cancel_while_paused = params.sender_cancel_while_paused.into();
+        self.mint_registry_mode = params.mint_registry_mode as u8;
+
+        Ok(())
+    }
+
+    /// Mutably borrow the config stored in `data`
+    pub fn from_account_data_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        data.get_mut(..CONFIG_SIZE)
+            .and_then(|b| bytemuck::try_from_bytes_mut(b).ok())
+            .ok_or_else(|| InvalidConfigAccount.into())
+    }
+
+    /// Read the config from `account`, checking it's the config account
+    /// of `program_id`
+    pub fn load(program_id: &Pubkey, account: &AccountInfo) -> Result<Self, ProgramError> {
+        if account.owner != program_id {
+            msg!("Error: Program config is not initialized");
+            return Err(InvalidConfigAccount.into());
+        }
+
+        let config: Self = account
+            .data
+            .borrow()
+            .get(..CONFIG_SIZE)
+            .and_then(|b| bytemuck::try_from_bytes(b).ok().copied())
+            .ok_or(InvalidConfigAccount)?;
+
+        let address = Pubkey::create_program_address(&[CONFIG_SEED, &[config.bump]], program_id)
+            .map_err(|_| InvalidConfigAccount)?;
+        if config.magic != CONFIG_VERSION || account.key != &address {
+            return Err(InvalidConfigAccount.into());
+        }
+
+        Ok(config)
+    }
+
+    /// Read the config like `load`, or the defaults (nothing paused, no
+    /// fees) when `account` is missing or was never initialized. Only for
+    /// instructions clients used before the config existed.
+    pub fn load_or_default(
+        program_id: &Pubkey,
+        account: Option<&AccountInfo>,
+    ) -> Result<Self, ProgramError> {
+        match account {
+            Some(account) if account.owner == program_id => Self::load(program_id, account),
+            Some(account) if account.key != &find_config_address(program_id).0 => {
+                Err(InvalidConfigAccount.into())
+            }
+            _ => Ok(Self::zeroed()),
+        }
+    }
+
+    /// Fail while the program is paused
+    pub fn check_active(&self) -> Result<(), ProgramError> {
+        if self.paused != 0 {
+            msg!("Error: Program is paused");
+            return Err(ProgramPaused.into());
+        }
+
+        Ok(())
+    }
+
+    /// Fail if the guardian paused the instruction with the `flag`
+    pub fn check_unpaused(&self, flag: u64) -> Result<(), ProgramError> {
+        if self.paused_instructions & flag != 0 {
+            msg!("Error: Instruction is paused");
+            return Err(InstructionPaused.into());
+        }
+
+        Ok(())
+    }
+
+    /// Like `check_active` and `check_unpaused` for a cancel, letting
+    /// signing senders through if the config allows it
+    pub fn check_cancel_unpaused(&self, by_sender: bool) -> Result<(), ProgramError> {
+        if by_sender && self.sender_cancel_while_paused != 0 {
+            return Ok(());
+        }
+
+        self.check_active()?;
+        self.check_unpaused(PAUSE_CANCEL)
+    }
+
+    /// Fail if new streams can't use `token_program`
+    pub fn check_token_program(&self, token_program: &Pubkey) -> Result<(), ProgramError> {
+        let allowed = if token_2022::check_id(token_program) {
+            self.token_2022_allowed
+        } else {
+            self.spl_token_allowed
+        };
+
+        if allowed == 0 {
+            msg!("Error: Token program {} is not allowed", token_program);
+            return Err(TokenProgramNotAllowed.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params() -> ConfigParams {
+        ConfigParams {
+            admin: Pubkey::new_unique(),
+            fee_treasury: Pubkey::new_unique(),
+            max_name_size: STREAM_NAME_SIZE as u64,
+            fee_basis_points: 25,
+            paused: false,
+            spl_token_allowed: true,
+            token_2022_allowed: false,
+            partner_fee_basis_points: 10,
+            guardian: Pubkey::new_unique(),
+            sender_cancel_while_paused: true,
+            mint_registry_mode: MintRegistryMode::Denylist,
+        }
+    }
+
+    #[test]
+    fn test_config() {
+        assert_eq!(std::mem::size_of::<ProgramConfig>(), CONFIG_SIZE);
+
+        let mut config = ProgramConfig::zeroed();
+        let p = params();
+        config.apply(&p).unwrap();
+        assert_eq!(config.admin, p.admin);
+        assert_eq!(config.fee_basis_points, 25);
+        assert_eq!(config.mint_registry_mode, 2);
+        assert!(config.check_active().is_ok());
+        assert!(config.check_token_program(&spl_token::id()).is_ok());
+        assert_eq!(
+            config.check_token_program(&token_2022::id()),
+            Err(TokenProgramNotAllowed.into())
+        );
+
+        config
+            .apply(&ConfigParams {
+                paused: true,
+                ..p.clone()
+            })
+            .unwrap();
+        assert_eq!(config.check_active(), Err(ProgramPaused.into()));
+
+        for invalid in [
+            ConfigParams {
+                max_name_size: 0,
+                ..p.clone()
+            },
+            ConfigParams {
+                max_name_size: STREAM_NAME_SIZE as u64 + 1,
+                ..p.clone()
+            },
+            ConfigParams {
+                fee_basis_points: MAX_BASIS_POINTS + 1,
+                ..p.clone()
+            },
+            ConfigParams {
+                fee_basis_points: MAX_BASIS_POINTS,
+                ..p.clone()
+            },
+        ] {
+            assert_eq!(config.apply(&invalid), Err(InvalidConfig.into()));
+        }
+        assert!(config.paused != 0);
+
+        assert_eq!(fee_amount(1_000_000, 25), 2_500);
+        assert_eq!(fee_amount(399, 25), 0);
+        assert_eq!(fee_amount(u64::MAX, MAX_BASIS_POINTS), u64::MAX);
+    }
+
+    #[test]
+    fn test_paused_instructions() {
+        let mut config = ProgramConfig::zeroed();
+        config.apply(&params()).unwrap();
+        assert!(config.check_unpaused(PAUSE_ALL).is_ok());
+
+        config.paused_instructions = PAUSE_WITHDRAW | PAUSE_CANCEL;
+        assert_eq!(
+            config.check_unpaused(PAUSE_WITHDRAW),
+            Err(InstructionPaused.into())
+        );
+        assert!(config.check_unpaused(PAUSE_CREATE).is_ok());
+        assert!(config.check_cancel_unpaused(true).is_ok());
+        assert_eq!(
+            config.check_cancel_unpaused(false),
+            Err(InstructionPaused.into())
+        );
+
+        // Pausing the whole program doesn't lock senders out either
+        config.paused = 1;
+        assert!(config.check_cancel_unpaused(true).is_ok());
+        assert_eq!(
+            config.check_cancel_unpaused(false),
+            Err(ProgramPaused.into())
+        );
+
+        config.paused = 0;
+        config.sender_cancel_while_paused = 0;
+        assert_eq!(
+            config.check_cancel_unpaused(true),
+            Err(InstructionPaused.into())
+        );
+
+        // Settings updates keep the paused instructions
+        config.apply(&params()).unwrap();
+        assert_eq!(config.paused_instructions, PAUSE_WITHDRAW | PAUSE_CANCEL);
+    }
+
+    #[test]
+    fn test_load_or_default() {
+        let program_id = Pubkey::new_unique();
+        let system_program = Pubkey::default();
+        let (address, _) = find_config_address(&program_id);
+        let other = Pubkey::new_unique();
+        let (mut lamports, mut other_lamports) = (0, 0);
+        let (mut data, mut other_data) = (vec![], vec![]);
+        let config = AccountInfo::new(
+            &address,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &system_program,
+            false,
+            0,
+        );
+        let not_config = AccountInfo::new(
+            &other,
+            false,
+            false,
+            &mut other_lamports,
+            &mut other_data,
+            &system_program,
+            false,
+            0,
+        );
+
+        // Before `InitConfig`, or without the account at all
+        for account in [None, Some(&config)] {
+            let defaults = ProgramConfig::load_or_default(&program_id, account).unwrap();
+            assert!(defaults.check_active().is_ok());
+            assert!(defaults.check_unpaused(PAUSE_ALL).is_ok());
+            assert_eq!(defaults.fee_basis_points, 0);
+            assert_eq!(defaults.partner_fee_basis_points, 0);
+        }
+
+        assert_eq!(
+            ProgramConfig::load_or_default(&program_id, Some(&not_config)).unwrap_err(),
+            InvalidConfigAccount.into()
+        );
+    }
+}
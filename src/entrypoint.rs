@@ -13,28 +13,44 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
-use borsh::BorshDeserialize;
 use solana_program::{
     account_info::{next_account_info, AccountInfo},
-    entrypoint,
     entrypoint::ProgramResult,
-    program_error::ProgramError,
+    program_error::{PrintProgramError, ProgramError},
     pubkey::Pubkey,
 };
-use std::convert::TryInto;
 
+use crate::config::{ProgramConfig, PAUSE_CREATE, PAUSE_TOPUP, PAUSE_TRANSFER, PAUSE_WITHDRAW};
+use crate::error::StreamFlowError;
+use crate::instruction::TimelockInstruction;
 use crate::state::{
-    CancelAccounts, InitializeAccounts, StreamInstruction, TopUpAccounts, TransferAccounts,
-    WithdrawAccounts,
+    AcceptAccounts, CancelAccounts, InitConfigAccounts, InitializeAccounts, MigrateAccounts,
+    MintRegistryAccounts, PauseAccounts, SetDestinationAccounts, SetPauseAccounts, TopUpAccounts,
+    TransferAccounts, UpdateConfigAccounts, WithdrawAccounts,
+};
+use crate::token::{
+    accept, cancel, create, create_with_seed, init_config, migrate, pause, register_mint, resume,
+    set_pause, set_withdraw_destination, topup_stream, transfer_recipient, unregister_mint,
+    update_config, withdraw,
 };
-use crate::token::{cancel, create, topup_stream, transfer_recipient, withdraw};
 
-entrypoint!(process_instruction);
+#[cfg(not(feature = "no-entrypoint"))]
+solana_program::entrypoint!(process_instruction);
 pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> ProgramResult {
+    if let Err(error) = process(pid, acc, ix) {
+        // Log the error message so clients can show it alongside the code
+        error.print::<StreamFlowError>();
+        return Err(error);
+    }
+
+    Ok(())
+}
+
+fn process(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> ProgramResult {
     let ai = &mut acc.iter();
 
-    match ix[0] {
-        0 => {
+    match TimelockInstruction::unpack(ix)? {
+        TimelockInstruction::Create(si) => {
             let ia = InitializeAccounts {
                 sender: next_account_info(ai)?.clone(),
                 sender_tokens: next_account_info(ai)?.clone(),
@@ -47,13 +63,38 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 token_program: next_account_info(ai)?.clone(),
                 associated_token_program: next_account_info(ai)?.clone(),
                 system_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+                mint_registry: next_account_info(ai)?.clone(),
+                fee_treasury_tokens: next_account_info(ai)?.clone(),
+                partner_tokens: next_account_info(ai).ok().cloned(),
             };
+            let config = load_unpaused(pid, &ia.config, PAUSE_CREATE)?;
 
-            let si = StreamInstruction::try_from_slice(&ix[1..])?;
+            create(pid, &config, ia, si)
+        }
+        TimelockInstruction::CreateWithSeed { seed, ix: si } => {
+            let ia = InitializeAccounts {
+                sender: next_account_info(ai)?.clone(),
+                sender_tokens: next_account_info(ai)?.clone(),
+                recipient: next_account_info(ai)?.clone(),
+                recipient_tokens: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                escrow_tokens: next_account_info(ai)?.clone(),
+                mint: next_account_info(ai)?.clone(),
+                rent: next_account_info(ai)?.clone(),
+                token_program: next_account_info(ai)?.clone(),
+                associated_token_program: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+                mint_registry: next_account_info(ai)?.clone(),
+                fee_treasury_tokens: next_account_info(ai)?.clone(),
+                partner_tokens: next_account_info(ai).ok().cloned(),
+            };
+            let config = load_unpaused(pid, &ia.config, PAUSE_CREATE)?;
 
-            return create(pid, ia, si);
+            create_with_seed(pid, &config, ia, si, seed)
         }
-        1 => {
+        TimelockInstruction::Withdraw { amount } => {
             let wa = WithdrawAccounts {
                 withdraw_authority: next_account_info(ai)?.clone(),
                 sender: next_account_info(ai)?.clone(),
@@ -63,14 +104,17 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 escrow_tokens: next_account_info(ai)?.clone(),
                 mint: next_account_info(ai)?.clone(),
                 token_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai).ok().cloned(),
+                sender_tokens: next_account_info(ai).ok().cloned(),
+                keeper_tokens: next_account_info(ai).ok().cloned(),
             };
+            let config = ProgramConfig::load_or_default(pid, wa.config.as_ref())?;
+            config.check_active()?;
+            config.check_unpaused(PAUSE_WITHDRAW)?;
 
-            let amnt = u64::from_le_bytes(ix[1..].try_into().unwrap());
-
-            return withdraw(pid, wa, amnt);
+            withdraw(pid, wa, amount)
         }
-
-        2 => {
+        TimelockInstruction::Cancel => {
             let ca = CancelAccounts {
                 cancel_authority: next_account_info(ai)?.clone(),
                 sender: next_account_info(ai)?.clone(),
@@ -81,11 +125,18 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 escrow_tokens: next_account_info(ai)?.clone(),
                 mint: next_account_info(ai)?.clone(),
                 token_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai).ok().cloned(),
             };
+            let config = ProgramConfig::load_or_default(pid, ca.config.as_ref())?;
+            // Only a signing sender may cancel while paused, the cancel
+            // handler checks `sender` is the stream's sender
+            let by_sender =
+                ca.cancel_authority.is_signer && ca.cancel_authority.key == ca.sender.key;
+            config.check_cancel_unpaused(by_sender)?;
 
-            return cancel(pid, ca);
+            cancel(pid, ca)
         }
-        3 => {
+        TimelockInstruction::Transfer => {
             let ta = TransferAccounts {
                 authorized_wallet: next_account_info(ai)?.clone(),
                 new_recipient: next_account_info(ai)?.clone(),
@@ -97,11 +148,13 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 token_program: next_account_info(ai)?.clone(),
                 associated_token_program: next_account_info(ai)?.clone(),
                 system_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
             };
+            load_unpaused(pid, &ta.config, PAUSE_TRANSFER)?;
 
-            return transfer_recipient(pid, ta);
+            transfer_recipient(pid, ta)
         }
-        4 => {
+        TimelockInstruction::TopUp { amount } => {
             let ta = TopUpAccounts {
                 sender: next_account_info(ai)?.clone(),
                 sender_tokens: next_account_info(ai)?.clone(),
@@ -109,13 +162,130 @@ pub fn process_instruction(pid: &Pubkey, acc: &[AccountInfo], ix: &[u8]) -> Prog
                 escrow_tokens: next_account_info(ai)?.clone(),
                 mint: next_account_info(ai)?.clone(),
                 token_program: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+                mint_registry: next_account_info(ai)?.clone(),
+                fee_treasury_tokens: next_account_info(ai)?.clone(),
+                partner_tokens: next_account_info(ai).ok().cloned(),
+            };
+            let config = load_unpaused(pid, &ta.config, PAUSE_TOPUP)?;
+
+            topup_stream(pid, &config, ta, amount)
+        }
+        TimelockInstruction::Pause => {
+            let pa = PauseAccounts {
+                authority: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+            };
+
+            pause(pid, pa)
+        }
+        TimelockInstruction::Resume => {
+            let pa = PauseAccounts {
+                authority: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
             };
-            let amount = u64::from_le_bytes(ix[1..].try_into().unwrap());
 
-            return topup_stream(pid, ta, amount);
+            resume(pid, pa)
+        }
+        TimelockInstruction::Migrate => {
+            let ma = MigrateAccounts {
+                payer: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+            };
+
+            migrate(pid, ma)
+        }
+        TimelockInstruction::InitConfig(params) => {
+            let ia = InitConfigAccounts {
+                upgrade_authority: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+                program_data: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+            };
+
+            init_config(pid, ia, params)
+        }
+        TimelockInstruction::UpdateConfig(params) => {
+            let ua = UpdateConfigAccounts {
+                admin: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+            };
+
+            update_config(pid, ua, params)
+        }
+        TimelockInstruction::SetPause {
+            paused_instructions,
+        } => {
+            let sa = SetPauseAccounts {
+                authority: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+            };
+
+            set_pause(pid, sa, paused_instructions)
+        }
+        TimelockInstruction::RegisterMint { mint } => {
+            let ma = MintRegistryAccounts {
+                admin: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+                mint_registry: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+            };
+
+            register_mint(pid, ma, mint)
+        }
+        TimelockInstruction::UnregisterMint { mint } => {
+            let ma = MintRegistryAccounts {
+                admin: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+                mint_registry: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+            };
+
+            unregister_mint(pid, ma, mint)
+        }
+        TimelockInstruction::Accept => {
+            let aa = AcceptAccounts {
+                recipient: next_account_info(ai)?.clone(),
+                recipient_tokens: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                mint: next_account_info(ai)?.clone(),
+                rent: next_account_info(ai)?.clone(),
+                token_program: next_account_info(ai)?.clone(),
+                associated_token_program: next_account_info(ai)?.clone(),
+                system_program: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+            };
+
+            accept(pid, aa)
+        }
+        TimelockInstruction::SetWithdrawDestination => {
+            let sa = SetDestinationAccounts {
+                recipient: next_account_info(ai)?.clone(),
+                metadata: next_account_info(ai)?.clone(),
+                destination_tokens: next_account_info(ai)?.clone(),
+                config: next_account_info(ai)?.clone(),
+            };
+
+            set_withdraw_destination(pid, sa)
         }
-        _ => {}
     }
+}
+
+/// Load the program config for a stream instruction, failing while the
+/// program or the instruction with the pause `flag` is paused
+fn load_unpaused(
+    pid: &Pubkey,
+    config: &AccountInfo,
+    flag: u64,
+) -> Result<ProgramConfig, ProgramError> {
+    let config = ProgramConfig::load(pid, config)?;
+    config.check_active()?;
+    config.check_unpaused(flag)?;
 
-    Err(ProgramError::InvalidInstructionData)
+    Ok(config)
 }
@@ -1,8 +1,13 @@
+use num_derive::FromPrimitive;
+use solana_program::decode_error::DecodeError;
 use solana_program::msg;
-use solana_program::program_error::ProgramError;
+use solana_program::program_error::{PrintProgramError, ProgramError};
 use thiserror::Error;
 
-#[derive(Error, Debug, Copy, Clone)]
+/// Errors returned by the program as `ProgramError::Custom(code)`, where
+/// `code` is the variant index. Variants are append-only so client side
+/// error codes stay stable.
+#[derive(Error, Debug, Copy, Clone, PartialEq, Eq, FromPrimitive)]
 pub enum StreamFlowError {
     #[error("Accounts not writable!")]
     AccountsNotWritable,
@@ -18,11 +23,150 @@ pub enum StreamFlowError {
 
     #[error("Stream closed")]
     StreamClosed,
+
+    #[error("Arithmetic overflow or underflow")]
+    ArithmeticError,
+
+    #[error("Invalid vesting curve parameters")]
+    InvalidVestingCurve,
+
+    #[error("Stream not pausable by account")]
+    PauseNotAllowed,
+
+    #[error("Stream paused")]
+    StreamPaused,
+
+    #[error("Stream not paused")]
+    StreamNotPaused,
+
+    #[error("Stream not cancelable by account")]
+    CancelNotAllowed,
+
+    #[error("Stream not cancelable by sender")]
+    SenderCancelNotAllowed,
+
+    #[error("Stream not cancelable by recipient")]
+    RecipientCancelNotAllowed,
+
+    #[error("Stream not withdrawable by account")]
+    WithdrawNotAllowed,
+
+    #[error("Stream metadata must be migrated")]
+    MigrationRequired,
+
+    #[error("Escrow account does not match the derived address")]
+    InvalidEscrowAccount,
+
+    #[error("Token account is not the associated token account")]
+    InvalidTokenAccount,
+
+    #[error("Invalid program or sysvar account")]
+    InvalidProgramAccount,
+
+    #[error("Amount requested is more than what is available")]
+    AmountExceedsAvailable,
+
+    #[error("Given timestamps are invalid")]
+    InvalidTimestamps,
+
+    #[error("Stream name too long")]
+    StreamNameTooLong,
+
+    #[error("Amount can't be zero")]
+    ZeroAmount,
+
+    #[error("Metadata does not match given accounts")]
+    MetadataAccountMismatch,
+
+    #[error("Insufficient tokens in sender's wallet")]
+    InsufficientTokens,
+
+    #[error("Mint extension not supported")]
+    UnsupportedMintExtension,
+
+    #[error("Config account does not match the derived address")]
+    InvalidConfigAccount,
+
+    #[error("Invalid program config parameters")]
+    InvalidConfig,
+
+    #[error("Program config not updatable by account")]
+    ConfigUpdateNotAllowed,
+
+    #[error("Program paused")]
+    ProgramPaused,
+
+    #[error("Token program not allowed")]
+    TokenProgramNotAllowed,
+
+    #[error("Instruction paused by the guardian")]
+    InstructionPaused,
+
+    #[error("Mint not allowed by the mint registry")]
+    MintNotAllowed,
+
+    #[error("Mint registry account does not match the derived address")]
+    InvalidMintRegistry,
+
+    #[error("Stream is pending acceptance by the recipient")]
+    StreamPending,
+
+    #[error("Stream is not pending acceptance")]
+    StreamNotPending,
+
+    #[error("Acceptance deadline has passed")]
+    AcceptanceExpired,
+
+    #[error("Stream status does not allow this instruction")]
+    InvalidStatusTransition,
+
+    #[error("Invalid keeper bounty")]
+    InvalidKeeperBounty,
 }
 
 impl From<StreamFlowError> for ProgramError {
     fn from(e: StreamFlowError) -> Self {
-        msg!(&e.to_string());
         ProgramError::Custom(e as u32)
     }
 }
+
+impl<T> DecodeError<T> for StreamFlowError {
+    fn type_of() -> &'static str {
+        "StreamFlowError"
+    }
+}
+
+impl PrintProgramError for StreamFlowError {
+    fn print<E>(&self) {
+        msg!(&self.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use num_traits::FromPrimitive;
+
+    #[test]
+    fn test_error_codes() {
+        // Codes are part of the client API and must never change
+        assert_eq!(
+            ProgramError::from(StreamFlowError::AccountsNotWritable),
+            ProgramError::Custom(0)
+        );
+        assert_eq!(StreamFlowError::MigrationRequired as u32, 14);
+        assert_eq!(StreamFlowError::InvalidEscrowAccount as u32, 15);
+        assert_eq!(StreamFlowError::InsufficientTokens as u32, 23);
+        assert_eq!(StreamFlowError::UnsupportedMintExtension as u32, 24);
+        assert_eq!(StreamFlowError::TokenProgramNotAllowed as u32, 29);
+        assert_eq!(StreamFlowError::InstructionPaused as u32, 30);
+        assert_eq!(StreamFlowError::InvalidMintRegistry as u32, 32);
+        assert_eq!(StreamFlowError::InvalidKeeperBounty as u32, 37);
+
+        for code in 0..=37 {
+            let e = StreamFlowError::from_u32(code).unwrap();
+            assert_eq!(e as u32, code);
+        }
+        assert_eq!(StreamFlowError::from_u32(38), None);
+    }
+}
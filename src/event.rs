@@ -0,0 +1,159 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{log::sol_log_data, pubkey::Pubkey};
+
+/// Events emitted by the program handlers.
+///
+/// Each event is logged Borsh-encoded with `sol_log_data`, showing up as
+/// a single base64 `Program data:` log line. Variants and their fields are
+/// append-only, so indexers can keep decoding older transactions.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub enum StreamEvent {
+    /// A new stream was created
+    StreamCreated {
+        metadata: Pubkey,
+        sender: Pubkey,
+        recipient: Pubkey,
+        mint: Pubkey,
+        escrow_tokens: Pubkey,
+        deposited_amount: u64,
+        total_amount: u64,
+        start_time: u64,
+        end_time: u64,
+    },
+    /// Tokens were withdrawn to the recipient
+    Withdrawn {
+        metadata: Pubkey,
+        /// Account that invoked the withdrawal
+        authority: Pubkey,
+        /// Account the funds went to
+        recipient_tokens: Pubkey,
+        amount: u64,
+        /// Total amount withdrawn from the stream so far
+        withdrawn_amount: u64,
+        timestamp: u64,
+    },
+    /// The stream was canceled (or closed after it ended)
+    Canceled {
+        metadata: Pubkey,
+        /// Account that invoked the cancel
+        authority: Pubkey,
+        /// Amount transferred to the recipient
+        recipient_amount: u64,
+        /// Amount returned to the sender
+        sender_amount: u64,
+        timestamp: u64,
+    },
+    /// The stream was transferred to a new recipient
+    RecipientTransferred {
+        metadata: Pubkey,
+        old_recipient: Pubkey,
+        new_recipient: Pubkey,
+        new_recipient_tokens: Pubkey,
+    },
+    /// Tokens were added to the stream
+    ToppedUp {
+        metadata: Pubkey,
+        amount: u64,
+        /// Total amount deposited in the stream
+        deposited_amount: u64,
+        closable_at: u64,
+    },
+    /// The stream was paused
+    Paused { metadata: Pubkey, timestamp: u64 },
+    /// The stream was resumed
+    Resumed {
+        metadata: Pubkey,
+        timestamp: u64,
+        closable_at: u64,
+    },
+    /// The stream metadata was migrated to a newer layout
+    Migrated {
+        metadata: Pubkey,
+        old_version: u64,
+        new_version: u64,
+    },
+    /// Protocol and partner fees were charged on a deposit
+    FeesCharged {
+        metadata: Pubkey,
+        fee_treasury_tokens: Pubkey,
+        protocol_fee: u64,
+        /// Default pubkey when the stream has no partner
+        partner_tokens: Pubkey,
+        partner_fee: u64,
+    },
+    /// The recipient accepted the stream, which started vesting
+    Accepted {
+        metadata: Pubkey,
+        recipient: Pubkey,
+        timestamp: u64,
+        closable_at: u64,
+    },
+    /// The recipient registered the token account withdrawals go to
+    DestinationSet {
+        metadata: Pubkey,
+        destination_tokens: Pubkey,
+    },
+    /// A keeper cranked a public stream and was paid a bounty
+    Cranked {
+        metadata: Pubkey,
+        keeper: Pubkey,
+        bounty: u64,
+        bounty_left: u64,
+    },
+}
+
+impl StreamEvent {
+    /// Log the event with `sol_log_data`
+    pub fn emit(&self) {
+        // Serializing into a Vec can't fail
+        sol_log_data(&[&self.try_to_vec().unwrap()]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_encoding() {
+        let metadata = Pubkey::new_unique();
+        let event = StreamEvent::Withdrawn {
+            metadata,
+            authority: Pubkey::new_unique(),
+            recipient_tokens: Pubkey::new_unique(),
+            amount: 15,
+            withdrawn_amount: 30,
+            timestamp: 1_600_000_000,
+        };
+
+        let bytes = event.try_to_vec().unwrap();
+        // Tag, 3 pubkeys, 3 u64
+        assert_eq!(bytes.len(), 1 + 3 * 32 + 3 * 8);
+        assert_eq!(bytes[0], 1);
+        assert_eq!(&bytes[1..33], metadata.as_ref());
+        assert_eq!(StreamEvent::try_from_slice(&bytes).unwrap(), event);
+
+        let bytes = StreamEvent::Paused {
+            metadata,
+            timestamp: 0,
+        }
+        .try_to_vec()
+        .unwrap();
+        assert_eq!(bytes[0], 5);
+    }
+}
@@ -0,0 +1,980 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{
+    bpf_loader_upgradeable,
+    instruction::{AccountMeta, Instruction},
+    msg,
+    program_error::ProgramError,
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+use spl_token::native_mint;
+
+use crate::config::{ConfigParams, CONFIG_SEED};
+use crate::mint_registry::MINT_REGISTRY_SEED;
+use crate::state::StreamInstruction;
+use crate::token_2022::{self, get_associated_token_address};
+
+/// Instructions supported by the timelock program.
+///
+/// Encoded with Borsh, so the first byte is the variant tag followed by
+/// its payload. Variants are append-only, the tags are part of the wire
+/// format used by existing clients.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub enum TimelockInstruction {
+    /// Create a new stream, accounts as in `InitializeAccounts`
+    Create(StreamInstruction),
+    /// Withdraw from a stream, accounts as in `WithdrawAccounts`
+    Withdraw {
+        /// Amount to withdraw, 0 withdraws everything available
+        amount: u64,
+    },
+    /// Cancel a stream, accounts as in `CancelAccounts`
+    Cancel,
+    /// Transfer a stream to a new recipient, accounts as in `TransferAccounts`
+    Transfer,
+    /// Top up a stream, accounts as in `TopUpAccounts`
+    TopUp {
+        /// Amount to deposit into the stream
+        amount: u64,
+    },
+    /// Pause a stream, accounts as in `PauseAccounts`
+    Pause,
+    /// Resume a paused stream, accounts as in `PauseAccounts`
+    Resume,
+    /// Create a new stream with its metadata account derived with
+    /// `find_metadata_address`, accounts as in `InitializeAccounts`
+    CreateWithSeed {
+        /// Caller-chosen seed, distinguishing streams between the same parties
+        seed: u64,
+        /// Stream parameters
+        ix: StreamInstruction,
+    },
+    /// Migrate stream metadata to the current layout, accounts as in
+    /// `MigrateAccounts`
+    Migrate,
+    /// Create the program config, accounts as in `InitConfigAccounts`
+    InitConfig(ConfigParams),
+    /// Update the program config, accounts as in `UpdateConfigAccounts`
+    UpdateConfig(ConfigParams),
+    /// Pause or unpause stream instructions, accounts as in
+    /// `SetPauseAccounts`
+    SetPause {
+        /// `PAUSE_*` flags of the instructions to pause, replacing the
+        /// current ones. 0 unpauses everything.
+        paused_instructions: u64,
+    },
+    /// Add a mint to the mint registry, accounts as in `MintRegistryAccounts`
+    RegisterMint { mint: Pubkey },
+    /// Remove a mint from the mint registry, accounts as in
+    /// `MintRegistryAccounts`
+    UnregisterMint { mint: Pubkey },
+    /// Accept a stream created with `requires_acceptance`, accounts as in
+    /// `AcceptAccounts`
+    Accept,
+    /// Register the default token account withdrawals go to, accounts as
+    /// in `SetDestinationAccounts`
+    SetWithdrawDestination,
+}
+
+/// Seed prefix of metadata accounts created with `CreateWithSeed`
+pub const METADATA_SEED: &[u8] = b"strm";
+
+impl TimelockInstruction {
+    /// Decode instruction data, rejecting empty, short, unknown
+    /// or trailing input with `InvalidInstructionData`.
+    pub fn unpack(input: &[u8]) -> Result<Self, ProgramError> {
+        Self::try_from_slice(input).map_err(|e| {
+            msg!("Error: Malformed instruction data: {}", e);
+            ProgramError::InvalidInstructionData
+        })
+    }
+}
+
+/// Derive the escrow account address of a stream
+pub fn find_escrow_address(program_id: &Pubkey, metadata: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[metadata.as_ref()], program_id)
+}
+
+/// Derive the program config address
+pub fn find_config_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[CONFIG_SEED], program_id)
+}
+
+/// Derive the mint registry address
+pub fn find_mint_registry_address(program_id: &Pubkey) -> (Pubkey, u8) {
+    Pubkey::find_program_address(&[MINT_REGISTRY_SEED], program_id)
+}
+
+/// Every stream instruction takes the program config as its last account
+fn config_account(program_id: &Pubkey) -> AccountMeta {
+    AccountMeta::new_readonly(find_config_address(program_id).0, false)
+}
+
+/// Token account of `wallet` for `mint`: the wallet itself for native SOL
+/// streams, its associated token account under `token_program` otherwise
+pub fn find_tokens_address(wallet: &Pubkey, mint: &Pubkey, token_program: &Pubkey) -> Pubkey {
+    if native_mint::check_id(mint) {
+        return *wallet;
+    }
+
+    get_associated_token_address(wallet, mint, token_program)
+}
+
+/// The mint is writable for Token-2022 streams, so transfer fees withheld
+/// in the escrow can be harvested when it gets closed.
+fn mint_account(mint: &Pubkey, token_program: &Pubkey) -> AccountMeta {
+    if token_2022::check_id(token_program) {
+        return AccountMeta::new(*mint, false);
+    }
+
+    AccountMeta::new_readonly(*mint, false)
+}
+
+/// Derive the metadata address of a stream created with `CreateWithSeed`
+pub fn find_metadata_address(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    mint: &Pubkey,
+    seed: u64,
+) -> (Pubkey, u8) {
+    Pubkey::find_program_address(
+        &[
+            METADATA_SEED,
+            sender.as_ref(),
+            recipient.as_ref(),
+            mint.as_ref(),
+            &seed.to_le_bytes(),
+        ],
+        program_id,
+    )
+}
+
+/// Fee accounts following the config: the fee treasury's token account,
+/// then the partner's if there is one
+fn fee_accounts(
+    fee_treasury: &Pubkey,
+    partner: Option<&Pubkey>,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Vec<AccountMeta> {
+    let mut accounts = vec![AccountMeta::new(
+        find_tokens_address(fee_treasury, mint, token_program),
+        false,
+    )];
+    if let Some(partner) = partner {
+        accounts.push(AccountMeta::new(
+            find_tokens_address(partner, mint, token_program),
+            false,
+        ));
+    }
+
+    accounts
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_accounts(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    fee_treasury: &Pubkey,
+    partner: Option<&Pubkey>,
+    metadata_signer: bool,
+) -> Vec<AccountMeta> {
+    let (escrow_tokens, _) = find_escrow_address(program_id, metadata);
+    let mut accounts = vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new(find_tokens_address(sender, mint, token_program), false),
+        AccountMeta::new(*recipient, false),
+        AccountMeta::new(find_tokens_address(recipient, mint, token_program), false),
+        AccountMeta::new(*metadata, metadata_signer),
+        AccountMeta::new(escrow_tokens, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(sysvar::rent::id(), false),
+        AccountMeta::new_readonly(*token_program, false),
+        AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_account(program_id),
+        AccountMeta::new_readonly(find_mint_registry_address(program_id).0, false),
+    ];
+    accounts.extend(fee_accounts(fee_treasury, partner, mint, token_program));
+
+    accounts
+}
+
+/// Build a `Create` instruction. Both `sender` and `metadata` must sign,
+/// `metadata` being a new keypair that will hold the stream metadata.
+/// `token_program` is the program owning `mint`, either `spl_token` or
+/// Token-2022, the same goes for the other builders.
+/// `fee_treasury` is the config's fee treasury wallet, and `partner` the
+/// wallet receiving the partner fee, if any.
+#[allow(clippy::too_many_arguments)]
+pub fn create_stream(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    fee_treasury: &Pubkey,
+    partner: Option<&Pubkey>,
+    ix: StreamInstruction,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Create(ix),
+        create_accounts(
+            program_id,
+            sender,
+            recipient,
+            metadata,
+            mint,
+            token_program,
+            fee_treasury,
+            partner,
+            true,
+        ),
+    )
+}
+
+/// Build a `CreateWithSeed` instruction, only `sender` must sign.
+/// The metadata account is derived with `find_metadata_address`.
+#[allow(clippy::too_many_arguments)]
+pub fn create_stream_with_seed(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    fee_treasury: &Pubkey,
+    partner: Option<&Pubkey>,
+    seed: u64,
+    ix: StreamInstruction,
+) -> Instruction {
+    let (metadata, _) = find_metadata_address(program_id, sender, recipient, mint, seed);
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::CreateWithSeed { seed, ix },
+        create_accounts(
+            program_id,
+            sender,
+            recipient,
+            &metadata,
+            mint,
+            token_program,
+            fee_treasury,
+            partner,
+            false,
+        ),
+    )
+}
+
+/// Build a `Withdraw` instruction, `amount` 0 withdraws everything available.
+/// `withdraw_authority` must sign, it can differ from `recipient` only on
+/// streams with `withdrawal_public` set.
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw(
+    program_id: &Pubkey,
+    withdraw_authority: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    withdraw_to(
+        program_id,
+        withdraw_authority,
+        sender,
+        recipient,
+        metadata,
+        mint,
+        token_program,
+        &find_tokens_address(recipient, mint, token_program),
+        amount,
+    )
+}
+
+/// Build a `Withdraw` instruction paying into `destination_tokens`. Only
+/// the recipient can withdraw into an account other than the stream's
+/// `withdraw_destination`, any token account of `mint` will do.
+#[allow(clippy::too_many_arguments)]
+pub fn withdraw_to(
+    program_id: &Pubkey,
+    withdraw_authority: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    destination_tokens: &Pubkey,
+    amount: u64,
+) -> Instruction {
+    let (escrow_tokens, _) = find_escrow_address(program_id, metadata);
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Withdraw { amount },
+        vec![
+            AccountMeta::new(*withdraw_authority, true),
+            AccountMeta::new(*sender, false),
+            AccountMeta::new(*recipient, false),
+            AccountMeta::new(*destination_tokens, false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new(escrow_tokens, false),
+            mint_account(mint, token_program),
+            AccountMeta::new_readonly(*token_program, false),
+            config_account(program_id),
+            AccountMeta::new(find_tokens_address(sender, mint, token_program), false),
+        ],
+    )
+}
+
+/// Build a `Withdraw` instruction for `keeper` cranking a public stream
+/// into its `destination_tokens`. Keepers get the stream's bounty, if any,
+/// in their wallet or their token account of `mint`.
+#[allow(clippy::too_many_arguments)]
+pub fn crank(
+    program_id: &Pubkey,
+    keeper: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    destination_tokens: &Pubkey,
+) -> Instruction {
+    let mut ix = withdraw_to(
+        program_id,
+        keeper,
+        sender,
+        recipient,
+        metadata,
+        mint,
+        token_program,
+        destination_tokens,
+        0,
+    );
+    ix.accounts.push(AccountMeta::new(
+        find_tokens_address(keeper, mint, token_program),
+        false,
+    ));
+    ix
+}
+
+/// Build a `Cancel` instruction, `cancel_authority` must sign
+pub fn cancel(
+    program_id: &Pubkey,
+    cancel_authority: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    cancel_to(
+        program_id,
+        cancel_authority,
+        sender,
+        recipient,
+        metadata,
+        mint,
+        token_program,
+        &find_tokens_address(recipient, mint, token_program),
+    )
+}
+
+/// Build a `Cancel` instruction paying the recipient's share into
+/// `destination_tokens`. Same rules as for `withdraw_to`, except that
+/// anyone can also pay into the recipient's associated token account.
+#[allow(clippy::too_many_arguments)]
+pub fn cancel_to(
+    program_id: &Pubkey,
+    cancel_authority: &Pubkey,
+    sender: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    destination_tokens: &Pubkey,
+) -> Instruction {
+    let (escrow_tokens, _) = find_escrow_address(program_id, metadata);
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Cancel,
+        vec![
+            AccountMeta::new(*cancel_authority, true),
+            AccountMeta::new(*sender, false),
+            AccountMeta::new(find_tokens_address(sender, mint, token_program), false),
+            AccountMeta::new(*recipient, false),
+            AccountMeta::new(*destination_tokens, false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new(escrow_tokens, false),
+            mint_account(mint, token_program),
+            AccountMeta::new_readonly(*token_program, false),
+            config_account(program_id),
+        ],
+    )
+}
+
+/// Build a `Transfer` instruction, `authorized_wallet` must sign and pays
+/// for the new recipient's associated token account if it doesn't exist.
+pub fn transfer_recipient(
+    program_id: &Pubkey,
+    authorized_wallet: &Pubkey,
+    new_recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    let (escrow_tokens, _) = find_escrow_address(program_id, metadata);
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Transfer,
+        vec![
+            AccountMeta::new(*authorized_wallet, true),
+            AccountMeta::new(*new_recipient, false),
+            AccountMeta::new(
+                find_tokens_address(new_recipient, mint, token_program),
+                false,
+            ),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new(escrow_tokens, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(*token_program, false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            config_account(program_id),
+        ],
+    )
+}
+
+/// Build a `TopUp` instruction, `sender` must sign
+#[allow(clippy::too_many_arguments)]
+pub fn topup(
+    program_id: &Pubkey,
+    sender: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+    fee_treasury: &Pubkey,
+    partner: Option<&Pubkey>,
+    amount: u64,
+) -> Instruction {
+    let (escrow_tokens, _) = find_escrow_address(program_id, metadata);
+    let mut accounts = vec![
+        AccountMeta::new(*sender, true),
+        AccountMeta::new(find_tokens_address(sender, mint, token_program), false),
+        AccountMeta::new(*metadata, false),
+        AccountMeta::new(escrow_tokens, false),
+        AccountMeta::new_readonly(*mint, false),
+        AccountMeta::new_readonly(*token_program, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+        config_account(program_id),
+        AccountMeta::new_readonly(find_mint_registry_address(program_id).0, false),
+    ];
+    accounts.extend(fee_accounts(fee_treasury, partner, mint, token_program));
+
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::TopUp { amount },
+        accounts,
+    )
+}
+
+/// Build a `Pause` instruction, `authority` must sign
+pub fn pause(program_id: &Pubkey, authority: &Pubkey, metadata: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Pause,
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(*metadata, false),
+            config_account(program_id),
+        ],
+    )
+}
+
+/// Build a `Resume` instruction, `authority` must sign
+pub fn resume(program_id: &Pubkey, authority: &Pubkey, metadata: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Resume,
+        vec![
+            AccountMeta::new(*authority, true),
+            AccountMeta::new(*metadata, false),
+            config_account(program_id),
+        ],
+    )
+}
+
+/// Build a `Migrate` instruction, `payer` must sign
+pub fn migrate(program_id: &Pubkey, payer: &Pubkey, metadata: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Migrate,
+        vec![
+            AccountMeta::new(*payer, true),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            config_account(program_id),
+        ],
+    )
+}
+
+/// Build an `InitConfig` instruction, `upgrade_authority` must sign
+/// and be the program's upgrade authority
+pub fn init_config(
+    program_id: &Pubkey,
+    upgrade_authority: &Pubkey,
+    params: ConfigParams,
+) -> Instruction {
+    let (program_data, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::InitConfig(params),
+        vec![
+            AccountMeta::new(*upgrade_authority, true),
+            AccountMeta::new(find_config_address(program_id).0, false),
+            AccountMeta::new_readonly(program_data, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+        ],
+    )
+}
+
+/// Build an `UpdateConfig` instruction, `admin` must sign. The admin is
+/// rotated by passing a different `params.admin`.
+pub fn update_config(program_id: &Pubkey, admin: &Pubkey, params: ConfigParams) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::UpdateConfig(params),
+        vec![
+            AccountMeta::new_readonly(*admin, true),
+            AccountMeta::new(find_config_address(program_id).0, false),
+        ],
+    )
+}
+
+/// Build a `SetPause` instruction, `authority` must sign and be the
+/// config's guardian or admin
+pub fn set_pause(program_id: &Pubkey, authority: &Pubkey, paused_instructions: u64) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::SetPause {
+            paused_instructions,
+        },
+        vec![
+            AccountMeta::new_readonly(*authority, true),
+            AccountMeta::new(find_config_address(program_id).0, false),
+        ],
+    )
+}
+
+fn mint_registry_accounts(program_id: &Pubkey, admin: &Pubkey) -> Vec<AccountMeta> {
+    vec![
+        AccountMeta::new(*admin, true),
+        AccountMeta::new_readonly(find_config_address(program_id).0, false),
+        AccountMeta::new(find_mint_registry_address(program_id).0, false),
+        AccountMeta::new_readonly(system_program::id(), false),
+    ]
+}
+
+/// Build a `RegisterMint` instruction, `admin` must sign and pays for
+/// the registry growing
+pub fn register_mint(program_id: &Pubkey, admin: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::RegisterMint { mint: *mint },
+        mint_registry_accounts(program_id, admin),
+    )
+}
+
+/// Build an `UnregisterMint` instruction, `admin` must sign
+pub fn unregister_mint(program_id: &Pubkey, admin: &Pubkey, mint: &Pubkey) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::UnregisterMint { mint: *mint },
+        mint_registry_accounts(program_id, admin),
+    )
+}
+
+/// Build an `Accept` instruction, `recipient` must sign and pays for its
+/// associated token account if it doesn't exist
+pub fn accept(
+    program_id: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::Accept,
+        vec![
+            AccountMeta::new(*recipient, true),
+            AccountMeta::new(find_tokens_address(recipient, mint, token_program), false),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+            AccountMeta::new_readonly(*token_program, false),
+            AccountMeta::new_readonly(spl_associated_token_account::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            config_account(program_id),
+        ],
+    )
+}
+
+/// Build a `SetWithdrawDestination` instruction, `recipient` must sign
+pub fn set_withdraw_destination(
+    program_id: &Pubkey,
+    recipient: &Pubkey,
+    metadata: &Pubkey,
+    destination_tokens: &Pubkey,
+) -> Instruction {
+    Instruction::new_with_borsh(
+        *program_id,
+        &TimelockInstruction::SetWithdrawDestination,
+        vec![
+            AccountMeta::new_readonly(*recipient, true),
+            AccountMeta::new(*metadata, false),
+            AccountMeta::new_readonly(*destination_tokens, false),
+            config_account(program_id),
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mint_registry::MintRegistryMode;
+
+    #[test]
+    fn test_unpack() {
+        assert_eq!(
+            TimelockInstruction::unpack(&[1, 42, 0, 0, 0, 0, 0, 0, 0]),
+            Ok(TimelockInstruction::Withdraw { amount: 42 })
+        );
+        assert_eq!(
+            TimelockInstruction::unpack(&[2]),
+            Ok(TimelockInstruction::Cancel)
+        );
+        assert_eq!(
+            TimelockInstruction::unpack(&[6]),
+            Ok(TimelockInstruction::Resume)
+        );
+
+        let create = TimelockInstruction::Create(StreamInstruction::default());
+        let bytes = create.try_to_vec().unwrap();
+        assert_eq!(bytes[0], 0);
+        assert_eq!(TimelockInstruction::unpack(&bytes), Ok(create));
+
+        for malformed in [
+            &[][..],            // empty
+            &[1, 42, 0, 0][..], // short amount
+            &[4][..],           // missing amount
+            &[2, 1][..],        // trailing bytes
+            &[0, 1, 2][..],     // truncated stream instruction
+            &[9][..],           // missing config params
+            &[11][..],          // missing paused instructions
+            &[12, 1][..],       // short mint
+            &[14, 0][..],       // trailing bytes
+            &[15, 0][..],       // trailing bytes
+            &[16][..],          // unknown tag
+            &[255][..],         // unknown tag
+        ] {
+            assert_eq!(
+                TimelockInstruction::unpack(malformed),
+                Err(ProgramError::InvalidInstructionData)
+            );
+        }
+    }
+
+    #[test]
+    fn test_builders() {
+        let program_id = Pubkey::new_unique();
+        let sender = Pubkey::new_unique();
+        let recipient = Pubkey::new_unique();
+        let metadata = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let fee_treasury = Pubkey::new_unique();
+        let partner = Pubkey::new_unique();
+        let (escrow_tokens, _) = find_escrow_address(&program_id, &metadata);
+
+        let ix = create_stream(
+            &program_id,
+            &sender,
+            &recipient,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+            &fee_treasury,
+            None,
+            StreamInstruction::default(),
+        );
+        assert_eq!(ix.program_id, program_id);
+        assert_eq!(ix.accounts.len(), 14);
+        assert_eq!(ix.accounts[11].pubkey, find_config_address(&program_id).0);
+        assert_eq!(
+            ix.accounts[12].pubkey,
+            find_mint_registry_address(&program_id).0
+        );
+        assert_eq!(
+            ix.accounts[13].pubkey,
+            get_associated_token_address(&fee_treasury, &mint, &spl_token::id())
+        );
+        assert_eq!(
+            ix.accounts[3].pubkey,
+            get_associated_token_address(&recipient, &mint, &spl_token::id())
+        );
+        assert!(ix.accounts[4].is_signer);
+        assert_eq!(ix.accounts[5].pubkey, escrow_tokens);
+        assert_eq!(
+            TimelockInstruction::unpack(&ix.data),
+            Ok(TimelockInstruction::Create(StreamInstruction::default()))
+        );
+
+        let ix = withdraw(
+            &program_id,
+            &recipient,
+            &sender,
+            &recipient,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+            7,
+        );
+        assert_eq!(ix.data, vec![1, 7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts.len(), 10);
+        assert!(ix.accounts[0].is_signer);
+        assert_eq!(ix.accounts[5].pubkey, escrow_tokens);
+        assert_eq!(
+            ix.accounts[9].pubkey,
+            get_associated_token_address(&sender, &mint, &spl_token::id())
+        );
+
+        let keeper = Pubkey::new_unique();
+        let ix = crank(
+            &program_id,
+            &keeper,
+            &sender,
+            &recipient,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+            &get_associated_token_address(&recipient, &mint, &spl_token::id()),
+        );
+        assert_eq!(ix.data, vec![1, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts.len(), 11);
+        assert_eq!(ix.accounts[0].pubkey, keeper);
+        assert!(ix.accounts[0].is_writable);
+        assert_eq!(
+            ix.accounts[10].pubkey,
+            get_associated_token_address(&keeper, &mint, &spl_token::id())
+        );
+
+        let ix = cancel(
+            &program_id,
+            &sender,
+            &sender,
+            &recipient,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+        );
+        assert_eq!(ix.data, vec![2]);
+        assert_eq!(ix.accounts.len(), 10);
+        assert_eq!(
+            ix.accounts[2].pubkey,
+            get_associated_token_address(&sender, &mint, &spl_token::id())
+        );
+
+        let ix = transfer_recipient(
+            &program_id,
+            &recipient,
+            &sender,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+        );
+        assert_eq!(ix.data, vec![3]);
+        assert_eq!(ix.accounts.len(), 11);
+
+        let ix = topup(
+            &program_id,
+            &sender,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+            &fee_treasury,
+            Some(&partner),
+            7,
+        );
+        assert_eq!(ix.data, vec![4, 7, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(ix.accounts.len(), 11);
+        assert_eq!(
+            ix.accounts[10].pubkey,
+            get_associated_token_address(&partner, &mint, &spl_token::id())
+        );
+
+        // Native SOL streams use the wallets in place of token accounts
+        let ix = withdraw(
+            &program_id,
+            &recipient,
+            &sender,
+            &recipient,
+            &metadata,
+            &native_mint::id(),
+            &spl_token::id(),
+            0,
+        );
+        assert_eq!(ix.accounts[3].pubkey, recipient);
+        let ix = cancel(
+            &program_id,
+            &sender,
+            &sender,
+            &recipient,
+            &metadata,
+            &native_mint::id(),
+            &spl_token::id(),
+        );
+        assert_eq!(ix.accounts[2].pubkey, sender);
+        assert_eq!(ix.accounts[4].pubkey, recipient);
+
+        assert_eq!(pause(&program_id, &sender, &metadata).data, vec![5]);
+        assert_eq!(resume(&program_id, &sender, &metadata).data, vec![6]);
+
+        let ix = migrate(&program_id, &sender, &metadata);
+        assert_eq!(ix.data, vec![8]);
+        assert_eq!(ix.accounts.len(), 4);
+        assert!(ix.accounts[0].is_signer);
+
+        let ix = create_stream_with_seed(
+            &program_id,
+            &sender,
+            &recipient,
+            &mint,
+            &spl_token::id(),
+            &fee_treasury,
+            None,
+            42,
+            StreamInstruction::default(),
+        );
+        let (metadata, _) = find_metadata_address(&program_id, &sender, &recipient, &mint, 42);
+        let (escrow_tokens, _) = find_escrow_address(&program_id, &metadata);
+        assert_eq!(ix.data[0], 7);
+        assert_eq!(ix.accounts[4].pubkey, metadata);
+        assert!(!ix.accounts[4].is_signer);
+        assert_eq!(ix.accounts[5].pubkey, escrow_tokens);
+        assert_eq!(
+            TimelockInstruction::unpack(&ix.data),
+            Ok(TimelockInstruction::CreateWithSeed {
+                seed: 42,
+                ix: StreamInstruction::default()
+            })
+        );
+        assert_ne!(
+            find_metadata_address(&program_id, &sender, &recipient, &mint, 43).0,
+            metadata
+        );
+
+        let params = ConfigParams {
+            admin: recipient,
+            fee_treasury: sender,
+            max_name_size: 64,
+            fee_basis_points: 0,
+            paused: false,
+            spl_token_allowed: true,
+            token_2022_allowed: true,
+            partner_fee_basis_points: 0,
+            guardian: sender,
+            sender_cancel_while_paused: false,
+            mint_registry_mode: MintRegistryMode::Allowlist,
+        };
+        let ix = init_config(&program_id, &sender, params.clone());
+        assert_eq!(ix.data[0], 9);
+        assert_eq!(ix.accounts.len(), 4);
+        assert_eq!(ix.accounts[1].pubkey, find_config_address(&program_id).0);
+        assert!(ix.accounts[1].is_writable);
+        let ix = update_config(&program_id, &sender, params.clone());
+        assert_eq!(
+            TimelockInstruction::unpack(&ix.data),
+            Ok(TimelockInstruction::UpdateConfig(params))
+        );
+        assert!(ix.accounts[0].is_signer);
+
+        let ix = register_mint(&program_id, &sender, &mint);
+        assert_eq!(ix.data[0], 12);
+        assert_eq!(&ix.data[1..], mint.as_ref());
+        assert_eq!(
+            ix.accounts[2].pubkey,
+            find_mint_registry_address(&program_id).0
+        );
+        assert!(ix.accounts[2].is_writable);
+        assert_eq!(unregister_mint(&program_id, &sender, &mint).data[0], 13);
+
+        let ix = accept(&program_id, &recipient, &metadata, &mint, &spl_token::id());
+        assert_eq!(ix.data, vec![14]);
+        assert_eq!(ix.accounts.len(), 9);
+        assert!(ix.accounts[0].is_signer);
+        assert_eq!(
+            ix.accounts[1].pubkey,
+            get_associated_token_address(&recipient, &mint, &spl_token::id())
+        );
+
+        let destination = Pubkey::new_unique();
+        let ix = withdraw_to(
+            &program_id,
+            &recipient,
+            &sender,
+            &recipient,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+            &destination,
+            0,
+        );
+        assert_eq!(ix.accounts[3].pubkey, destination);
+        assert!(ix.accounts[3].is_writable);
+        let ix = cancel_to(
+            &program_id,
+            &sender,
+            &sender,
+            &recipient,
+            &metadata,
+            &mint,
+            &spl_token::id(),
+            &destination,
+        );
+        assert_eq!(ix.accounts[4].pubkey, destination);
+        assert!(ix.accounts[4].is_writable);
+        let ix = set_withdraw_destination(&program_id, &recipient, &metadata, &destination);
+        assert_eq!(ix.data, vec![15]);
+        assert!(ix.accounts[0].is_signer);
+        assert!(!ix.accounts[2].is_writable);
+
+        let ix = set_pause(&program_id, &sender, 3);
+        assert_eq!(ix.data, vec![11, 3, 0, 0, 0, 0, 0, 0, 0]);
+        assert!(ix.accounts[0].is_signer);
+        assert!(ix.accounts[1].is_writable);
+    }
+}
@@ -0,0 +1,321 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use bytemuck::{Pod, Zeroable};
+use solana_program::{msg, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::state::{StreamStatus, Tranche, PROGRAM_VERSION};
+
+/// Size of the fixed-width stream name, UTF-8 padded with zeroes
+pub const STREAM_NAME_SIZE: usize = 200;
+/// Size of the zeroed space reserved for future fields
+pub const RESERVED_SIZE: usize = 96;
+
+/// Fixed-size metadata account layout, used since `PROGRAM_VERSION` 3.
+///
+/// Every field lives at a stable offset (see the `*_OFFSET` constants),
+/// so accounts can be read in place and matched with `getProgramAccounts`
+/// memcmp filters. Integers are little-endian and flags are 0 or 1.
+/// A `VestingCurve::Tranches` stream stores its `curve_param` tranches
+/// right after the header.
+#[derive(Clone, Copy, Debug, Pod, Zeroable)]
+#[repr(C)]
+pub struct StreamMetadata {
+    pub magic: u64,
+    pub created_at: u64,
+    pub withdrawn_amount: u64,
+    pub canceled_at: u64,
+    pub closable_at: u64,
+    pub last_withdrawn_at: u64,
+    pub sender: Pubkey,
+    pub sender_tokens: Pubkey,
+    pub recipient: Pubkey,
+    pub recipient_tokens: Pubkey,
+    pub mint: Pubkey,
+    pub escrow_tokens: Pubkey,
+    pub start_time: u64,
+    pub end_time: u64,
+    pub deposited_amount: u64,
+    pub total_amount: u64,
+    pub period: u64,
+    pub cliff: u64,
+    pub cliff_amount: u64,
+    pub release_rate: u64,
+    pub paused_at: u64,
+    pub paused_duration: u64,
+    /// `halving_periods`, `balloon_amount` or the number of tranches,
+    /// depending on `vesting_curve`
+    pub curve_param: u64,
+    pub cancelable_by_sender: u8,
+    pub cancelable_by_recipient: u8,
+    pub withdrawal_public: u8,
+    pub transferable_by_sender: u8,
+    pub transferable_by_recipient: u8,
+    pub pausable_by_sender: u8,
+    pub escrow_tokens_bump: u8,
+    /// `VestingCurve` variant, in declaration order
+    pub vesting_curve: u8,
+    pub stream_name: [u8; STREAM_NAME_SIZE],
+    /// Token account receiving partner fees, zeroes without a partner
+    pub partner_tokens: Pubkey,
+    pub protocol_fee_basis_points: u64,
+    pub partner_fee_basis_points: u64,
+    /// Protocol fees paid on all deposits so far
+    pub protocol_fee_amount: u64,
+    /// Partner fees paid on all deposits so far
+    pub partner_fee_amount: u64,
+    /// Zero when there is no acceptance deadline
+    pub acceptance_deadline: u64,
+    pub accepted_at: u64,
+    pub requires_acceptance: u8,
+    /// `StreamStatus`, 0 for accounts written before it was stored
+    pub status: u8,
+    pub keeper_bounty_in_tokens: u8,
+    pub padding: [u8; 5],
+    /// Zeroes when withdrawals go to `recipient_tokens`
+    pub destination_tokens: Pubkey,
+    pub keeper_bounty: u64,
+    pub keeper_bounty_pool: u64,
+    pub crank_interval: u64,
+    pub keeper_bounty_left: u64,
+    /// Token-2022 transfer fees withheld on all deposits so far
+    pub transfer_fee_amount: u64,
+    pub reserved: [u8; RESERVED_SIZE],
+}
+
+pub const MAGIC_OFFSET: usize = 0;
+pub const CREATED_AT_OFFSET: usize = 8;
+pub const WITHDRAWN_AMOUNT_OFFSET: usize = 16;
+pub const CANCELED_AT_OFFSET: usize = 24;
+pub const CLOSABLE_AT_OFFSET: usize = 32;
+pub const LAST_WITHDRAWN_AT_OFFSET: usize = 40;
+pub const SENDER_OFFSET: usize = 48;
+pub const SENDER_TOKENS_OFFSET: usize = 80;
+pub const RECIPIENT_OFFSET: usize = 112;
+pub const RECIPIENT_TOKENS_OFFSET: usize = 144;
+pub const MINT_OFFSET: usize = 176;
+pub const ESCROW_TOKENS_OFFSET: usize = 208;
+pub const START_TIME_OFFSET: usize = 240;
+pub const END_TIME_OFFSET: usize = 248;
+pub const DEPOSITED_AMOUNT_OFFSET: usize = 256;
+pub const TOTAL_AMOUNT_OFFSET: usize = 264;
+pub const PERIOD_OFFSET: usize = 272;
+pub const CLIFF_OFFSET: usize = 280;
+pub const CLIFF_AMOUNT_OFFSET: usize = 288;
+pub const RELEASE_RATE_OFFSET: usize = 296;
+pub const PAUSED_AT_OFFSET: usize = 304;
+pub const PAUSED_DURATION_OFFSET: usize = 312;
+pub const CURVE_PARAM_OFFSET: usize = 320;
+pub const CANCELABLE_BY_SENDER_OFFSET: usize = 328;
+pub const CANCELABLE_BY_RECIPIENT_OFFSET: usize = 329;
+pub const WITHDRAWAL_PUBLIC_OFFSET: usize = 330;
+pub const TRANSFERABLE_BY_SENDER_OFFSET: usize = 331;
+pub const TRANSFERABLE_BY_RECIPIENT_OFFSET: usize = 332;
+pub const PAUSABLE_BY_SENDER_OFFSET: usize = 333;
+pub const ESCROW_TOKENS_BUMP_OFFSET: usize = 334;
+pub const VESTING_CURVE_OFFSET: usize = 335;
+pub const STREAM_NAME_OFFSET: usize = 336;
+pub const PARTNER_TOKENS_OFFSET: usize = 536;
+pub const PROTOCOL_FEE_BASIS_POINTS_OFFSET: usize = 568;
+pub const PARTNER_FEE_BASIS_POINTS_OFFSET: usize = 576;
+pub const PROTOCOL_FEE_AMOUNT_OFFSET: usize = 584;
+pub const PARTNER_FEE_AMOUNT_OFFSET: usize = 592;
+pub const ACCEPTANCE_DEADLINE_OFFSET: usize = 600;
+pub const ACCEPTED_AT_OFFSET: usize = 608;
+pub const REQUIRES_ACCEPTANCE_OFFSET: usize = 616;
+pub const STATUS_OFFSET: usize = 617;
+pub const KEEPER_BOUNTY_IN_TOKENS_OFFSET: usize = 618;
+pub const DESTINATION_TOKENS_OFFSET: usize = 624;
+pub const KEEPER_BOUNTY_OFFSET: usize = 656;
+pub const KEEPER_BOUNTY_POOL_OFFSET: usize = 664;
+pub const CRANK_INTERVAL_OFFSET: usize = 672;
+pub const KEEPER_BOUNTY_LEFT_OFFSET: usize = 680;
+pub const TRANSFER_FEE_AMOUNT_OFFSET: usize = 688;
+pub const RESERVED_OFFSET: usize = 696;
+/// Size of the fixed part of the account, tranches start here
+pub const HEADER_SIZE: usize = 792;
+/// Size of a stored tranche
+pub const TRANCHE_SIZE: usize = 16;
+
+// `vesting_curve` of each `VestingCurve` variant
+pub const CURVE_LINEAR: u8 = 0;
+pub const CURVE_FRONT_LOADED: u8 = 1;
+pub const CURVE_BACK_LOADED: u8 = 2;
+pub const CURVE_HALVING: u8 = 3;
+pub const CURVE_BALLOON: u8 = 4;
+pub const CURVE_TRANCHES: u8 = 5;
+
+impl StreamMetadata {
+    /// Borrow the header of a metadata account in the current layout,
+    /// without copying. Account data is 8-byte aligned on chain.
+    pub fn from_account_data(data: &[u8]) -> Result<&Self, ProgramError> {
+        let header: &Self = data
+            .get(..HEADER_SIZE)
+            .and_then(|b| bytemuck::try_from_bytes(b).ok())
+            .ok_or(ProgramError::InvalidAccountData)?;
+        header.check_version()?;
+        Ok(header)
+    }
+
+    /// Mutably borrow the header of a metadata account in the current
+    /// layout, without copying.
+    pub fn from_account_data_mut(data: &mut [u8]) -> Result<&mut Self, ProgramError> {
+        let header: &mut Self = data
+            .get_mut(..HEADER_SIZE)
+            .and_then(|b| bytemuck::try_from_bytes_mut(b).ok())
+            .ok_or(ProgramError::InvalidAccountData)?;
+        header.check_version()?;
+        Ok(header)
+    }
+
+    /// Mutably borrow the header of a metadata account in the current
+    /// layout along with its tranches, without copying.
+    pub fn split_account_data_mut(
+        data: &mut [u8],
+    ) -> Result<(&mut Self, &[Tranche]), ProgramError> {
+        if data.len() < HEADER_SIZE {
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        let (header, tail) = data.split_at_mut(HEADER_SIZE);
+        let header = Self::from_account_data_mut(header)?;
+        let tail: &[u8] = tail;
+        let len = match header.vesting_curve {
+            CURVE_TRANCHES => header.curve_param as usize,
+            _ => 0,
+        };
+        let tranches = len
+            .checked_mul(TRANCHE_SIZE)
+            .and_then(|size| tail.get(..size))
+            .and_then(|b| bytemuck::try_cast_slice(b).ok())
+            .ok_or(ProgramError::InvalidAccountData)?;
+        Ok((header, tranches))
+    }
+
+    fn check_version(&self) -> Result<(), ProgramError> {
+        if self.magic != PROGRAM_VERSION {
+            msg!("Error: Metadata version {} is not current", self.magic);
+            return Err(ProgramError::InvalidAccountData);
+        }
+
+        StreamStatus::from_layout(self.status)?;
+        Ok(())
+    }
+
+    /// Account size needed to store the header and `num_tranches` tranches
+    pub fn space(num_tranches: usize) -> usize {
+        HEADER_SIZE + num_tranches * TRANCHE_SIZE
+    }
+
+    /// Read the tranches stored after the header
+    pub fn tranches(&self, data: &[u8]) -> Result<Vec<Tranche>, ProgramError> {
+        let len = self.curve_param as usize;
+        let tail = data
+            .get(HEADER_SIZE..)
+            .filter(|tail| tail.len() / TRANCHE_SIZE >= len)
+            .ok_or(ProgramError::InvalidAccountData)?;
+
+        Ok(tail
+            .chunks_exact(TRANCHE_SIZE)
+            .take(len)
+            .map(|chunk| {
+                let mut tranche = Tranche::zeroed();
+                bytemuck::bytes_of_mut(&mut tranche).copy_from_slice(chunk);
+                tranche
+            })
+            .collect())
+    }
+
+    /// Name of the stream, up to the first zero byte
+    pub fn stream_name(&self) -> String {
+        let len = self
+            .stream_name
+            .iter()
+            .position(|b| *b == 0)
+            .unwrap_or(STREAM_NAME_SIZE);
+        String::from_utf8_lossy(&self.stream_name[..len]).into_owned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_offsets() {
+        let m = StreamMetadata::zeroed();
+        macro_rules! assert_offset {
+            ($field:ident, $offset:expr) => {
+                assert_eq!(
+                    bytemuck::offset_of!(m, StreamMetadata, $field),
+                    $offset,
+                    stringify!($field)
+                );
+            };
+        }
+
+        assert_offset!(magic, MAGIC_OFFSET);
+        assert_offset!(created_at, CREATED_AT_OFFSET);
+        assert_offset!(withdrawn_amount, WITHDRAWN_AMOUNT_OFFSET);
+        assert_offset!(canceled_at, CANCELED_AT_OFFSET);
+        assert_offset!(closable_at, CLOSABLE_AT_OFFSET);
+        assert_offset!(last_withdrawn_at, LAST_WITHDRAWN_AT_OFFSET);
+        assert_offset!(sender, SENDER_OFFSET);
+        assert_offset!(sender_tokens, SENDER_TOKENS_OFFSET);
+        assert_offset!(recipient, RECIPIENT_OFFSET);
+        assert_offset!(recipient_tokens, RECIPIENT_TOKENS_OFFSET);
+        assert_offset!(mint, MINT_OFFSET);
+        assert_offset!(escrow_tokens, ESCROW_TOKENS_OFFSET);
+        assert_offset!(start_time, START_TIME_OFFSET);
+        assert_offset!(end_time, END_TIME_OFFSET);
+        assert_offset!(deposited_amount, DEPOSITED_AMOUNT_OFFSET);
+        assert_offset!(total_amount, TOTAL_AMOUNT_OFFSET);
+        assert_offset!(period, PERIOD_OFFSET);
+        assert_offset!(cliff, CLIFF_OFFSET);
+        assert_offset!(cliff_amount, CLIFF_AMOUNT_OFFSET);
+        assert_offset!(release_rate, RELEASE_RATE_OFFSET);
+        assert_offset!(paused_at, PAUSED_AT_OFFSET);
+        assert_offset!(paused_duration, PAUSED_DURATION_OFFSET);
+        assert_offset!(curve_param, CURVE_PARAM_OFFSET);
+        assert_offset!(cancelable_by_sender, CANCELABLE_BY_SENDER_OFFSET);
+        assert_offset!(cancelable_by_recipient, CANCELABLE_BY_RECIPIENT_OFFSET);
+        assert_offset!(withdrawal_public, WITHDRAWAL_PUBLIC_OFFSET);
+        assert_offset!(transferable_by_sender, TRANSFERABLE_BY_SENDER_OFFSET);
+        assert_offset!(transferable_by_recipient, TRANSFERABLE_BY_RECIPIENT_OFFSET);
+        assert_offset!(pausable_by_sender, PAUSABLE_BY_SENDER_OFFSET);
+        assert_offset!(escrow_tokens_bump, ESCROW_TOKENS_BUMP_OFFSET);
+        assert_offset!(vesting_curve, VESTING_CURVE_OFFSET);
+        assert_offset!(stream_name, STREAM_NAME_OFFSET);
+        assert_offset!(partner_tokens, PARTNER_TOKENS_OFFSET);
+        assert_offset!(protocol_fee_basis_points, PROTOCOL_FEE_BASIS_POINTS_OFFSET);
+        assert_offset!(partner_fee_basis_points, PARTNER_FEE_BASIS_POINTS_OFFSET);
+        assert_offset!(protocol_fee_amount, PROTOCOL_FEE_AMOUNT_OFFSET);
+        assert_offset!(partner_fee_amount, PARTNER_FEE_AMOUNT_OFFSET);
+        assert_offset!(acceptance_deadline, ACCEPTANCE_DEADLINE_OFFSET);
+        assert_offset!(accepted_at, ACCEPTED_AT_OFFSET);
+        assert_offset!(requires_acceptance, REQUIRES_ACCEPTANCE_OFFSET);
+        assert_offset!(status, STATUS_OFFSET);
+        assert_offset!(destination_tokens, DESTINATION_TOKENS_OFFSET);
+        assert_offset!(keeper_bounty_in_tokens, KEEPER_BOUNTY_IN_TOKENS_OFFSET);
+        assert_offset!(keeper_bounty, KEEPER_BOUNTY_OFFSET);
+        assert_offset!(keeper_bounty_pool, KEEPER_BOUNTY_POOL_OFFSET);
+        assert_offset!(crank_interval, CRANK_INTERVAL_OFFSET);
+        assert_offset!(keeper_bounty_left, KEEPER_BOUNTY_LEFT_OFFSET);
+        assert_offset!(transfer_fee_amount, TRANSFER_FEE_AMOUNT_OFFSET);
+        assert_offset!(reserved, RESERVED_OFFSET);
+        assert_eq!(std::mem::size_of::<StreamMetadata>(), HEADER_SIZE);
+        assert_eq!(std::mem::size_of::<Tranche>(), TRANCHE_SIZE);
+    }
+}
@@ -17,14 +17,25 @@
 //! The code providing timelock primitives
 //! used by [streamflow.finance](https://streamflow.finance).
 
-/// Entrypoint
-#[cfg(not(feature = "no-entrypoint"))]
+/// Program-wide configuration
+pub mod config;
+/// Entrypoint, the `entrypoint!` itself is left out with `no-entrypoint`
 pub mod entrypoint;
 /// Errors
 pub mod error;
+/// Program events
+pub mod event;
+/// Instruction types
+pub mod instruction;
+/// Fixed-size account layout
+pub mod layout;
+/// Mint allowlist or denylist
+pub mod mint_registry;
 /// Structs and data
 pub mod state;
 /// Functions related to SPL tokens
 pub mod token;
+/// Token-2022 program support
+pub mod token_2022;
 /// Utility functions
 pub mod utils;
@@ -0,0 +1,144 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use std::convert::TryInto;
+
+use borsh::{BorshDeserialize, BorshSerialize};
+use solana_program::{account_info::AccountInfo, msg, program_error::ProgramError, pubkey::Pubkey};
+
+use crate::config::ProgramConfig;
+use crate::error::StreamFlowError::{InvalidMintRegistry, MintNotAllowed};
+use crate::instruction::find_mint_registry_address;
+
+/// Seed of the mint registry account
+pub const MINT_REGISTRY_SEED: &[u8] = b"mint_registry";
+/// Layout version of the mint registry account
+pub const MINT_REGISTRY_VERSION: u64 = 1;
+/// The registry starts with its version and the number of mints listed,
+/// followed by the mints. The account only ever grows, slots freed by
+/// removed mints are reused.
+pub const MINT_REGISTRY_HEADER_SIZE: usize = 16;
+
+/// How `create` and `topup` treat the mints in the registry
+#[derive(BorshSerialize, BorshDeserialize, Clone, Copy, Debug, PartialEq)]
+pub enum MintRegistryMode {
+    /// The registry isn't consulted
+    Disabled,
+    /// Only listed mints can be streamed
+    Allowlist,
+    /// Listed mints can't be streamed
+    Denylist,
+}
+
+impl MintRegistryMode {
+    /// Mode stored in the program config
+    pub fn from_u8(mode: u8) -> Self {
+        match mode {
+            1 => Self::Allowlist,
+            2 => Self::Denylist,
+            _ => Self::Disabled,
+        }
+    }
+}
+
+/// Space taken by a registry holding `count` mints
+pub fn registry_len(count: usize) -> usize {
+    MINT_REGISTRY_HEADER_SIZE + count * 32
+}
+
+/// Mints listed in the data of the mint registry account, this is what
+/// clients read to list the registry
+pub fn registry_mints(data: &[u8]) -> Result<&[Pubkey], ProgramError> {
+    let read_u64 = |offset: usize| {
+        data.get(offset..offset + 8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    };
+
+    if read_u64(0) != Some(MINT_REGISTRY_VERSION) {
+        return Err(InvalidMintRegistry.into());
+    }
+
+    let count = read_u64(8).ok_or(InvalidMintRegistry)? as usize;
+    data.get(MINT_REGISTRY_HEADER_SIZE..registry_len(count))
+        .map(bytemuck::cast_slice)
+        .ok_or_else(|| InvalidMintRegistry.into())
+}
+
+/// Write the header of a registry holding `count` mints
+pub fn write_registry_header(data: &mut [u8], count: usize) {
+    data[..8].copy_from_slice(&MINT_REGISTRY_VERSION.to_le_bytes());
+    data[8..16].copy_from_slice(&(count as u64).to_le_bytes());
+}
+
+/// Fail if streams of `mint` can't be created or topped up under the
+/// config's registry mode. A registry that was never created lists no
+/// mints.
+pub fn check_mint(
+    program_id: &Pubkey,
+    config: &ProgramConfig,
+    registry: &AccountInfo,
+    mint: &Pubkey,
+) -> Result<(), ProgramError> {
+    let mode = MintRegistryMode::from_u8(config.mint_registry_mode);
+    if mode == MintRegistryMode::Disabled {
+        return Ok(());
+    }
+
+    if registry.key != &find_mint_registry_address(program_id).0 {
+        return Err(InvalidMintRegistry.into());
+    }
+
+    let listed = if registry.owner == program_id {
+        registry_mints(&registry.data.borrow())?.contains(mint)
+    } else {
+        false
+    };
+
+    if listed != (mode == MintRegistryMode::Allowlist) {
+        msg!("Error: Mint {} is not allowed", mint);
+        return Err(MintNotAllowed.into());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_mints() {
+        let mints = [Pubkey::new_unique(), Pubkey::new_unique()];
+        // Room for a third mint, left over from a removed one
+        let mut data = vec![0; registry_len(3)];
+        write_registry_header(&mut data, 2);
+        data[16..48].copy_from_slice(mints[0].as_ref());
+        data[48..80].copy_from_slice(mints[1].as_ref());
+        assert_eq!(registry_mints(&data), Ok(&mints[..]));
+
+        write_registry_header(&mut data, 0);
+        assert_eq!(registry_mints(&data), Ok(&[][..]));
+
+        write_registry_header(&mut data, 4);
+        assert_eq!(registry_mints(&data), Err(InvalidMintRegistry.into()));
+        data[0] = 0;
+        assert_eq!(registry_mints(&data), Err(InvalidMintRegistry.into()));
+        assert_eq!(registry_mints(&[]), Err(InvalidMintRegistry.into()));
+
+        assert_eq!(MintRegistryMode::from_u8(1), MintRegistryMode::Allowlist);
+        assert_eq!(MintRegistryMode::from_u8(2), MintRegistryMode::Denylist);
+        assert_eq!(MintRegistryMode::from_u8(0), MintRegistryMode::Disabled);
+    }
+}
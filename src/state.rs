@@ -15,14 +15,210 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
+use std::{cell::RefMut, io};
+
 use borsh::{BorshDeserialize, BorshSerialize};
-use solana_program::{account_info::AccountInfo, msg, pubkey::Pubkey};
+use bytemuck::{Pod, Zeroable};
+use solana_program::{
+    account_info::AccountInfo, entrypoint::ProgramResult, msg, program_error::ProgramError,
+    pubkey::Pubkey,
+};
+
+use crate::config::{fee_amount, MAX_BASIS_POINTS};
+use crate::error::StreamFlowError::{
+    self, ArithmeticError, InvalidMetadata, InvalidStatusTransition, InvalidVestingCurve,
+    MigrationRequired, StreamClosed, StreamNameTooLong,
+};
+use crate::layout::{
+    StreamMetadata, CURVE_BACK_LOADED, CURVE_BALLOON, CURVE_FRONT_LOADED, CURVE_HALVING,
+    CURVE_LINEAR, CURVE_TRANCHES, HEADER_SIZE, RESERVED_SIZE, STREAM_NAME_SIZE,
+};
 
 // Hardcoded program version
-pub const PROGRAM_VERSION: u64 = 2;
+// 1: initial layout, see `V1_METADATA_SIZE`
+// 2: Borsh-encoded `TokenStreamData`
+// 3: fixed-size `StreamMetadata`
+pub const PROGRAM_VERSION: u64 = 3;
+
+/// Metadata layout of `PROGRAM_VERSION` 1, without closable_at, the
+/// stream flags or the stream name. Accounts were created with this size.
+pub const V1_METADATA_SIZE: usize = 8 + 10 * 8 + 6 * 32;
+
+/// Deserialize a field appended to the layout after `PROGRAM_VERSION` 2.
+/// Older accounts and instructions carry nothing (or zero padding) in its
+/// place, so appended fields must encode their default value as zeroes.
+fn deserialize_appended<T: BorshDeserialize + Default>(buf: &mut &[u8]) -> io::Result<T> {
+    if buf.iter().all(|b| *b == 0) {
+        *buf = &buf[buf.len()..];
+        return Ok(T::default());
+    }
+
+    T::deserialize(buf)
+}
+
+/// Shape of the vesting curve between the cliff and the end of the stream.
+///
+/// The discriminant is stored on-chain: new curves are only ever appended
+/// and existing variants never change their position or fields.
+#[derive(BorshDeserialize, BorshSerialize, Clone, Debug, PartialEq)]
+pub enum VestingCurve {
+    /// Equal amount unlocked every period (or `release_rate` if recurring)
+    Linear,
+    /// Quadratic ease-out, most of the tokens unlock early in the stream
+    FrontLoaded,
+    /// Quadratic ease-in, most of the tokens unlock late in the stream
+    BackLoaded,
+    /// Amount unlocked per period halves every `halving_periods` periods
+    Halving { halving_periods: u64 },
+    /// Linear, with `balloon_amount` released on the last period
+    Balloon { balloon_amount: u64 },
+    /// Arbitrary schedule of tranches, sorted by timestamp and summing up
+    /// to `total_amount`. Cliff and period are not used.
+    Tranches(Vec<Tranche>),
+}
+
+/// Amount of tokens unlocked at a given timestamp
+#[derive(BorshDeserialize, BorshSerialize, Clone, Copy, Debug, PartialEq, Pod, Zeroable)]
+#[repr(C)]
+pub struct Tranche {
+    /// Timestamp when the tranche unlocks
+    pub timestamp: u64,
+    /// Amount of tokens unlocked
+    pub amount: u64,
+}
+
+impl Default for VestingCurve {
+    fn default() -> Self {
+        VestingCurve::Linear
+    }
+}
+
+impl VestingCurve {
+    /// Variant index and parameter, as stored in `StreamMetadata`
+    fn to_layout(&self) -> (u8, u64) {
+        match self {
+            VestingCurve::Linear => (CURVE_LINEAR, 0),
+            VestingCurve::FrontLoaded => (CURVE_FRONT_LOADED, 0),
+            VestingCurve::BackLoaded => (CURVE_BACK_LOADED, 0),
+            VestingCurve::Halving { halving_periods } => (CURVE_HALVING, *halving_periods),
+            VestingCurve::Balloon { balloon_amount } => (CURVE_BALLOON, *balloon_amount),
+            VestingCurve::Tranches(tranches) => (CURVE_TRANCHES, tranches.len() as u64),
+        }
+    }
+
+    fn from_layout(header: &StreamMetadata, tranches: &[Tranche]) -> Result<Self, ProgramError> {
+        Ok(match header.vesting_curve {
+            CURVE_LINEAR => VestingCurve::Linear,
+            CURVE_FRONT_LOADED => VestingCurve::FrontLoaded,
+            CURVE_BACK_LOADED => VestingCurve::BackLoaded,
+            CURVE_HALVING => VestingCurve::Halving {
+                halving_periods: header.curve_param,
+            },
+            CURVE_BALLOON => VestingCurve::Balloon {
+                balloon_amount: header.curve_param,
+            },
+            CURVE_TRANCHES => VestingCurve::Tranches(tranches.to_vec()),
+            _ => return Err(InvalidMetadata.into()),
+        })
+    }
+}
+
+/// Maximum number of halvings of a `VestingCurve::Halving` stream
+pub const MAX_HALVINGS: u64 = 32;
+/// Maximum number of tranches of a `VestingCurve::Tranches` stream
+pub const MAX_TRANCHES: usize = 64;
+
+/// Lifecycle status of a stream.
+///
+/// Instructions only store `Pending`, `Active`, `Paused`, `Canceled` and
+/// `Completed`. Whether an active stream is still scheduled, vesting or
+/// ran out of funds depends on the time, see `StreamMetadata::status_at`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StreamStatus {
+    /// Waiting for the recipient to accept the stream
+    Pending,
+    /// Vesting hasn't started yet
+    Scheduled,
+    /// Vesting
+    Active,
+    /// Paused by the sender
+    Paused,
+    /// Funds ran out before the end of the stream
+    Underfunded,
+    /// Canceled, the remaining funds went back to the sender
+    Canceled,
+    /// Everything deposited was withdrawn
+    Completed,
+}
+
+impl Default for StreamStatus {
+    fn default() -> Self {
+        StreamStatus::Active
+    }
+}
+
+impl StreamStatus {
+    /// Stored value in `StreamMetadata`, 0 is left for accounts written
+    /// before the status was stored
+    fn to_layout(self) -> u8 {
+        match self {
+            StreamStatus::Pending => 1,
+            StreamStatus::Scheduled => 2,
+            StreamStatus::Active => 3,
+            StreamStatus::Paused => 4,
+            StreamStatus::Underfunded => 5,
+            StreamStatus::Canceled => 6,
+            StreamStatus::Completed => 7,
+        }
+    }
+
+    /// Status stored in `StreamMetadata`. Scheduled and underfunded are
+    /// only ever computed by `status_at`, so storing them is invalid.
+    pub(crate) fn from_layout(status: u8) -> Result<Option<Self>, ProgramError> {
+        Ok(Some(match status {
+            0 => return Ok(None),
+            1 => StreamStatus::Pending,
+            3 => StreamStatus::Active,
+            4 => StreamStatus::Paused,
+            6 => StreamStatus::Canceled,
+            7 => StreamStatus::Completed,
+            _ => return Err(InvalidMetadata.into()),
+        }))
+    }
+
+    /// Whether no instruction can change the stream anymore
+    pub fn is_closed(self) -> bool {
+        matches!(self, StreamStatus::Canceled | StreamStatus::Completed)
+    }
+
+    /// Status after moving to `to`, failing on transitions the lifecycle
+    /// doesn't allow, staying in the same status included.
+    pub fn transition(self, to: StreamStatus) -> Result<Self, StreamFlowError> {
+        use StreamStatus::*;
+
+        let allowed = match (self, to) {
+            (from, _) if from.is_closed() => false,
+            (Pending, Active | Canceled) => true,
+            (Active, Paused) | (Paused, Active) => true,
+            (Active | Paused, Canceled | Completed) => true,
+            _ => false,
+        };
+
+        if !allowed {
+            msg!("Error: Stream can't go from {:?} to {:?}", self, to);
+            return Err(if self.is_closed() {
+                StreamClosed
+            } else {
+                InvalidStatusTransition
+            });
+        }
+
+        Ok(to)
+    }
+}
 
 /// The struct containing instructions for initializing a stream
-#[derive(BorshDeserialize, BorshSerialize, Clone, Debug)]
+#[derive(BorshSerialize, Clone, Debug, PartialEq)]
 #[repr(C)]
 pub struct StreamInstruction {
     /// Timestamp when the tokens start vesting
@@ -54,6 +250,57 @@ pub struct StreamInstruction {
     pub release_rate: u64,
     /// The name of this stream
     pub stream_name: String,
+    /// Shape of the vesting curve
+    pub vesting_curve: VestingCurve,
+    /// Whether or not the sender can pause the stream
+    pub pausable_by_sender: bool,
+    /// Whether or not the stream stays pending until the recipient
+    /// accepts it
+    pub requires_acceptance: bool,
+    /// Timestamp after which a pending stream can't be accepted anymore
+    /// and can be closed by anyone (0 for no deadline)
+    pub acceptance_deadline: u64,
+    /// Bounty paid to a keeper for each crank, a public withdrawal made
+    /// once `crank_interval` passed since the last one (0 for no bounty)
+    pub keeper_bounty: u64,
+    /// Whether the bounty is paid in the stream's token rather than in
+    /// lamports
+    pub keeper_bounty_in_tokens: bool,
+    /// Bounties funded by the sender on creation, the rest goes back to
+    /// the sender once the stream is closed
+    pub keeper_bounty_pool: u64,
+    /// Minimum number of seconds between two paid cranks, required with a
+    /// bounty
+    pub crank_interval: u64,
+}
+
+impl BorshDeserialize for StreamInstruction {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            start_time: u64::deserialize(buf)?,
+            end_time: u64::deserialize(buf)?,
+            deposited_amount: u64::deserialize(buf)?,
+            total_amount: u64::deserialize(buf)?,
+            period: u64::deserialize(buf)?,
+            cliff: u64::deserialize(buf)?,
+            cliff_amount: u64::deserialize(buf)?,
+            cancelable_by_sender: bool::deserialize(buf)?,
+            cancelable_by_recipient: bool::deserialize(buf)?,
+            withdrawal_public: bool::deserialize(buf)?,
+            transferable_by_sender: bool::deserialize(buf)?,
+            transferable_by_recipient: bool::deserialize(buf)?,
+            release_rate: u64::deserialize(buf)?,
+            stream_name: String::deserialize(buf)?,
+            vesting_curve: deserialize_appended(buf)?,
+            pausable_by_sender: deserialize_appended(buf)?,
+            requires_acceptance: deserialize_appended(buf)?,
+            acceptance_deadline: deserialize_appended(buf)?,
+            keeper_bounty: deserialize_appended(buf)?,
+            keeper_bounty_in_tokens: deserialize_appended(buf)?,
+            keeper_bounty_pool: deserialize_appended(buf)?,
+            crank_interval: deserialize_appended(buf)?,
+        })
+    }
 }
 
 impl Default for StreamInstruction {
@@ -74,12 +321,23 @@ impl Default for StreamInstruction {
             transferable_by_recipient: true,
             release_rate: 0,
             stream_name: "Stream".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
         }
     }
 }
 
 /// TokenStreamData is the struct containing metadata for an SPL token stream.
-#[derive(BorshSerialize, BorshDeserialize, Default, Debug)]
+///
+/// Stored on chain as `StreamMetadata`, see `load` and `save`. The Borsh
+/// encoding is the `PROGRAM_VERSION` 2 account layout.
+#[derive(BorshSerialize, Default, Debug)]
 #[repr(C)]
 pub struct TokenStreamData {
     /// Magic bytes, will be used for version of the contract
@@ -110,6 +368,253 @@ pub struct TokenStreamData {
     pub escrow_tokens: Pubkey,
     /// The stream instruction
     pub ix: StreamInstruction,
+    /// Timestamp when stream was paused (if paused)
+    pub paused_at: u64,
+    /// Total amount of seconds the stream spent paused before `paused_at`
+    pub paused_duration: u64,
+    /// Bump seed of the `escrow_tokens` PDA (0 for streams created
+    /// before it was stored)
+    pub escrow_tokens_bump: u8,
+    /// Token account receiving the partner fees, `Pubkey::default()`
+    /// for streams created without a partner
+    #[borsh_skip]
+    pub partner_tokens: Pubkey,
+    /// Protocol fee charged on every deposit, in basis points
+    #[borsh_skip]
+    pub protocol_fee_basis_points: u64,
+    /// Partner fee charged on every deposit, in basis points
+    #[borsh_skip]
+    pub partner_fee_basis_points: u64,
+    /// Protocol fees paid so far, not part of `deposited_amount`
+    #[borsh_skip]
+    pub protocol_fee_amount: u64,
+    /// Partner fees paid so far, not part of `deposited_amount`
+    #[borsh_skip]
+    pub partner_fee_amount: u64,
+    /// Token-2022 transfer fees withheld on the way into escrow so far,
+    /// not part of `deposited_amount`
+    #[borsh_skip]
+    pub transfer_fee_amount: u64,
+    /// Timestamp when the recipient accepted the stream (if accepted)
+    #[borsh_skip]
+    pub accepted_at: u64,
+    /// Stored lifecycle status, changed with `transition`
+    #[borsh_skip]
+    pub status: StreamStatus,
+    /// Token account the recipient registered for withdrawals,
+    /// `Pubkey::default()` for `recipient_tokens`
+    #[borsh_skip]
+    pub destination_tokens: Pubkey,
+    /// Part of `keeper_bounty_pool` not paid out yet, held by the
+    /// metadata account for lamports or by the escrow for tokens
+    #[borsh_skip]
+    pub keeper_bounty_left: u64,
+}
+
+impl TokenStreamData {
+    /// Decode the fixed-size layout of the current `PROGRAM_VERSION`
+    fn from_layout(data: &[u8]) -> Result<Self, ProgramError> {
+        let mut header = StreamMetadata::zeroed();
+        let len = std::mem::size_of::<StreamMetadata>();
+        // Copy, client buffers aren't necessarily aligned
+        bytemuck::bytes_of_mut(&mut header)
+            .copy_from_slice(data.get(..len).ok_or(InvalidMetadata)?);
+        let tranches = match header.vesting_curve {
+            CURVE_TRANCHES => header.tranches(data)?,
+            _ => Vec::new(),
+        };
+
+        Self::from_header(&header, &tranches)
+    }
+
+    /// Build the metadata from a header of the fixed-size layout and its
+    /// tranches
+    fn from_header(header: &StreamMetadata, tranches: &[Tranche]) -> Result<Self, ProgramError> {
+        let ix = StreamInstruction {
+            start_time: header.start_time,
+            end_time: header.end_time,
+            deposited_amount: header.deposited_amount,
+            total_amount: header.total_amount,
+            period: header.period,
+            cliff: header.cliff,
+            cliff_amount: header.cliff_amount,
+            cancelable_by_sender: header.cancelable_by_sender != 0,
+            cancelable_by_recipient: header.cancelable_by_recipient != 0,
+            withdrawal_public: header.withdrawal_public != 0,
+            transferable_by_sender: header.transferable_by_sender != 0,
+            transferable_by_recipient: header.transferable_by_recipient != 0,
+            release_rate: header.release_rate,
+            stream_name: header.stream_name(),
+            vesting_curve: VestingCurve::from_layout(header, tranches)?,
+            pausable_by_sender: header.pausable_by_sender != 0,
+            requires_acceptance: header.requires_acceptance != 0,
+            acceptance_deadline: header.acceptance_deadline,
+            keeper_bounty: header.keeper_bounty,
+            keeper_bounty_in_tokens: header.keeper_bounty_in_tokens != 0,
+            keeper_bounty_pool: header.keeper_bounty_pool,
+            crank_interval: header.crank_interval,
+        };
+
+        let mut metadata = Self {
+            magic: header.magic,
+            created_at: header.created_at,
+            withdrawn_amount: header.withdrawn_amount,
+            canceled_at: header.canceled_at,
+            closable_at: header.closable_at,
+            last_withdrawn_at: header.last_withdrawn_at,
+            sender: header.sender,
+            sender_tokens: header.sender_tokens,
+            recipient: header.recipient,
+            recipient_tokens: header.recipient_tokens,
+            mint: header.mint,
+            escrow_tokens: header.escrow_tokens,
+            ix,
+            paused_at: header.paused_at,
+            paused_duration: header.paused_duration,
+            escrow_tokens_bump: header.escrow_tokens_bump,
+            partner_tokens: header.partner_tokens,
+            protocol_fee_basis_points: header.protocol_fee_basis_points,
+            partner_fee_basis_points: header.partner_fee_basis_points,
+            protocol_fee_amount: header.protocol_fee_amount,
+            partner_fee_amount: header.partner_fee_amount,
+            transfer_fee_amount: header.transfer_fee_amount,
+            accepted_at: header.accepted_at,
+            destination_tokens: header.destination_tokens,
+            keeper_bounty_left: header.keeper_bounty_left,
+            ..Default::default()
+        };
+        metadata.status = match StreamStatus::from_layout(header.status)? {
+            Some(status) => status,
+            None => metadata.legacy_status(),
+        };
+
+        Ok(metadata)
+    }
+
+    /// Encode the header of the fixed-size layout. Stream names longer
+    /// than `STREAM_NAME_SIZE` bytes are cut at a character boundary,
+    /// `save` refuses them.
+    fn to_layout(&self) -> StreamMetadata {
+        let ix = &self.ix;
+        let (vesting_curve, curve_param) = ix.vesting_curve.to_layout();
+        let mut name_len = ix.stream_name.len().min(STREAM_NAME_SIZE);
+        while !ix.stream_name.is_char_boundary(name_len) {
+            name_len -= 1;
+        }
+        let mut stream_name = [0; STREAM_NAME_SIZE];
+        stream_name[..name_len].copy_from_slice(&ix.stream_name.as_bytes()[..name_len]);
+
+        StreamMetadata {
+            magic: self.magic,
+            created_at: self.created_at,
+            withdrawn_amount: self.withdrawn_amount,
+            canceled_at: self.canceled_at,
+            closable_at: self.closable_at,
+            last_withdrawn_at: self.last_withdrawn_at,
+            sender: self.sender,
+            sender_tokens: self.sender_tokens,
+            recipient: self.recipient,
+            recipient_tokens: self.recipient_tokens,
+            mint: self.mint,
+            escrow_tokens: self.escrow_tokens,
+            start_time: ix.start_time,
+            end_time: ix.end_time,
+            deposited_amount: ix.deposited_amount,
+            total_amount: ix.total_amount,
+            period: ix.period,
+            cliff: ix.cliff,
+            cliff_amount: ix.cliff_amount,
+            release_rate: ix.release_rate,
+            paused_at: self.paused_at,
+            paused_duration: self.paused_duration,
+            curve_param,
+            cancelable_by_sender: ix.cancelable_by_sender as u8,
+            cancelable_by_recipient: ix.cancelable_by_recipient as u8,
+            withdrawal_public: ix.withdrawal_public as u8,
+            transferable_by_sender: ix.transferable_by_sender as u8,
+            transferable_by_recipient: ix.transferable_by_recipient as u8,
+            pausable_by_sender: ix.pausable_by_sender as u8,
+            escrow_tokens_bump: self.escrow_tokens_bump,
+            vesting_curve,
+            stream_name,
+            partner_tokens: self.partner_tokens,
+            protocol_fee_basis_points: self.protocol_fee_basis_points,
+            partner_fee_basis_points: self.partner_fee_basis_points,
+            protocol_fee_amount: self.protocol_fee_amount,
+            partner_fee_amount: self.partner_fee_amount,
+            acceptance_deadline: ix.acceptance_deadline,
+            accepted_at: self.accepted_at,
+            requires_acceptance: ix.requires_acceptance as u8,
+            status: self.status.to_layout(),
+            keeper_bounty_in_tokens: ix.keeper_bounty_in_tokens as u8,
+            padding: [0; 5],
+            destination_tokens: self.destination_tokens,
+            keeper_bounty: ix.keeper_bounty,
+            keeper_bounty_pool: ix.keeper_bounty_pool,
+            crank_interval: ix.crank_interval,
+            keeper_bounty_left: self.keeper_bounty_left,
+            transfer_fee_amount: self.transfer_fee_amount,
+            reserved: [0; RESERVED_SIZE],
+        }
+    }
+
+    /// Decode the `PROGRAM_VERSION` 1 layout into the current struct.
+    /// Streams back then were cancelable by the sender and transferable
+    /// by the recipient only.
+    fn deserialize_v1(buf: &mut &[u8]) -> io::Result<Self> {
+        let magic = u64::deserialize(buf)?;
+        let ix = StreamInstruction {
+            start_time: u64::deserialize(buf)?,
+            end_time: u64::deserialize(buf)?,
+            deposited_amount: u64::deserialize(buf)?,
+            total_amount: u64::deserialize(buf)?,
+            period: u64::deserialize(buf)?,
+            cliff: u64::deserialize(buf)?,
+            cliff_amount: u64::deserialize(buf)?,
+            ..Default::default()
+        };
+
+        Ok(Self {
+            magic,
+            created_at: u64::deserialize(buf)?,
+            withdrawn_amount: u64::deserialize(buf)?,
+            canceled_at: u64::deserialize(buf)?,
+            closable_at: ix.end_time,
+            last_withdrawn_at: 0,
+            sender: Pubkey::deserialize(buf)?,
+            sender_tokens: Pubkey::deserialize(buf)?,
+            recipient: Pubkey::deserialize(buf)?,
+            recipient_tokens: Pubkey::deserialize(buf)?,
+            mint: Pubkey::deserialize(buf)?,
+            escrow_tokens: Pubkey::deserialize(buf)?,
+            ix,
+            ..Default::default()
+        })
+    }
+}
+
+impl BorshDeserialize for TokenStreamData {
+    fn deserialize(buf: &mut &[u8]) -> io::Result<Self> {
+        Ok(Self {
+            magic: u64::deserialize(buf)?,
+            created_at: u64::deserialize(buf)?,
+            withdrawn_amount: u64::deserialize(buf)?,
+            canceled_at: u64::deserialize(buf)?,
+            closable_at: u64::deserialize(buf)?,
+            last_withdrawn_at: u64::deserialize(buf)?,
+            sender: Pubkey::deserialize(buf)?,
+            sender_tokens: Pubkey::deserialize(buf)?,
+            recipient: Pubkey::deserialize(buf)?,
+            recipient_tokens: Pubkey::deserialize(buf)?,
+            mint: Pubkey::deserialize(buf)?,
+            escrow_tokens: Pubkey::deserialize(buf)?,
+            ix: StreamInstruction::deserialize(buf)?,
+            paused_at: deserialize_appended(buf)?,
+            paused_duration: deserialize_appended(buf)?,
+            escrow_tokens_bump: deserialize_appended(buf)?,
+            ..Default::default()
+        })
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -123,6 +628,7 @@ impl TokenStreamData {
         recipient_tokens: Pubkey,
         mint: Pubkey,
         escrow_tokens: Pubkey,
+        escrow_tokens_bump: u8,
         start_time: u64,
         end_time: u64,
         deposited_amount: u64,
@@ -137,6 +643,14 @@ impl TokenStreamData {
         transferable_by_recipient: bool,
         release_rate: u64,
         stream_name: String,
+        vesting_curve: VestingCurve,
+        pausable_by_sender: bool,
+        requires_acceptance: bool,
+        acceptance_deadline: u64,
+        keeper_bounty: u64,
+        keeper_bounty_in_tokens: bool,
+        keeper_bounty_pool: u64,
+        crank_interval: u64,
     ) -> Self {
         let ix = StreamInstruction {
             start_time,
@@ -153,6 +667,20 @@ impl TokenStreamData {
             transferable_by_recipient,
             release_rate,
             stream_name,
+            vesting_curve,
+            pausable_by_sender,
+            requires_acceptance,
+            acceptance_deadline,
+            keeper_bounty,
+            keeper_bounty_in_tokens,
+            keeper_bounty_pool,
+            crank_interval,
+        };
+
+        let status = if requires_acceptance {
+            StreamStatus::Pending
+        } else {
+            StreamStatus::Active
         };
 
         // TODO: calculate cancel_time based on other parameters (incl. deposited_amount)
@@ -170,84 +698,714 @@ impl TokenStreamData {
             mint,
             escrow_tokens,
             ix,
+            paused_at: 0,
+            paused_duration: 0,
+            escrow_tokens_bump,
+            status,
+            keeper_bounty_left: keeper_bounty_pool,
+            ..Default::default()
         }
     }
 
+    /// Read the metadata from the account data, in any layout written by
+    /// a previous program version. `magic` keeps the version of the stored
+    /// layout, accounts older than `PROGRAM_VERSION` can be read but need
+    /// to be migrated before they are written to.
+    pub fn load(data: &[u8]) -> Result<Self, ProgramError> {
+        let mut buf = data;
+        let magic = Self::version(data)?;
+        let mut metadata = match magic {
+            1 => Self::deserialize_v1(&mut buf).map_err(|_| InvalidMetadata)?,
+            2 => Self::deserialize(&mut buf).map_err(|_| InvalidMetadata)?,
+            PROGRAM_VERSION => Self::from_layout(data)?,
+            _ => {
+                msg!("Error: Unknown metadata version {}", magic);
+                return Err(InvalidMetadata.into());
+            }
+        };
+
+        if magic < PROGRAM_VERSION {
+            metadata.status = metadata.legacy_status();
+        }
+
+        // Partially funded v1 streams became closable before the end
+        if magic == 1 && metadata.ix.deposited_amount < metadata.ix.total_amount {
+            metadata.closable_at = metadata.closable()?;
+        }
+
+        Ok(metadata)
+    }
+
+    /// Version of the layout the account data is stored in
+    pub fn version(data: &[u8]) -> Result<u64, ProgramError> {
+        Ok(data
+            .get(..8)
+            .and_then(|b| u64::try_from_slice(b).ok())
+            .ok_or(InvalidMetadata)?)
+    }
+
+    /// Account size needed for the metadata in the current layout
+    pub fn space(&self) -> usize {
+        match self.ix.vesting_curve {
+            VestingCurve::Tranches(ref tranches) => StreamMetadata::space(tranches.len()),
+            _ => StreamMetadata::space(0),
+        }
+    }
+
+    /// Write the metadata into the account data in the current layout.
+    /// Accounts in older layouts must be migrated first.
+    pub fn save(&self, data: &mut [u8]) -> ProgramResult {
+        if self.magic != PROGRAM_VERSION {
+            msg!("Error: Metadata version {} is outdated", self.magic);
+            return Err(MigrationRequired.into());
+        }
+
+        if self.ix.stream_name.len() > STREAM_NAME_SIZE {
+            msg!(
+                "Error: Stream name is longer than {} bytes",
+                STREAM_NAME_SIZE
+            );
+            return Err(StreamNameTooLong.into());
+        }
+
+        if data.len() < self.space() {
+            msg!("Error: Metadata account is too small for the current layout");
+            return Err(ProgramError::AccountDataTooSmall);
+        }
+
+        data[..HEADER_SIZE].copy_from_slice(bytemuck::bytes_of(&self.to_layout()));
+        if let VestingCurve::Tranches(ref tranches) = self.ix.vesting_curve {
+            let tail = &mut data[HEADER_SIZE..self.space()];
+            tail.copy_from_slice(bytemuck::cast_slice(tranches));
+        }
+
+        Ok(())
+    }
+
+    /// Write the metadata back in the `PROGRAM_VERSION` 2 layout, keeping
+    /// the size of the account. Fails with `MigrationRequired` when the
+    /// stream uses anything the old layout can't hold. The status isn't
+    /// stored, it's inferred again on load.
+    fn save_v2(&self, data: &mut [u8]) -> ProgramResult {
+        let bytes = self.try_to_vec()?;
+        // Fields appended to the layout are zeroes at their default value,
+        // older accounts were created without room for them
+        let (fits, rest) = bytes.split_at(bytes.len().min(data.len()));
+        let representable = self.partner_tokens == Pubkey::default()
+            && self.protocol_fee_basis_points == 0
+            && self.partner_fee_basis_points == 0
+            && self.protocol_fee_amount == 0
+            && self.partner_fee_amount == 0
+            && self.transfer_fee_amount == 0
+            && self.accepted_at == 0
+            && self.destination_tokens == Pubkey::default()
+            && self.keeper_bounty_left == 0;
+        if !representable || rest.iter().any(|b| *b != 0) {
+            msg!(
+                "Error: Metadata version {} must be migrated first",
+                self.magic
+            );
+            return Err(MigrationRequired.into());
+        }
+
+        data[..fits.len()].copy_from_slice(fits);
+        for b in data[fits.len()..].iter_mut() {
+            *b = 0;
+        }
+
+        Ok(())
+    }
+
+    /// Tranches of a `VestingCurve::Tranches` stream, empty otherwise
+    fn tranches(&self) -> &[Tranche] {
+        match self.ix.vesting_curve {
+            VestingCurve::Tranches(ref tranches) => tranches,
+            _ => &[],
+        }
+    }
+
+    /// Address and bump seed of the `escrow_tokens` PDA, see
+    /// `StreamMetadata::escrow_address`
+    pub fn escrow_address(
+        &self,
+        program_id: &Pubkey,
+        metadata: &Pubkey,
+    ) -> Result<(Pubkey, u8), ProgramError> {
+        self.to_layout().escrow_address(program_id, metadata)
+    }
+
+    /// Check that the vesting curve parameters are valid for this stream.
+    pub fn validate_curve(&self) -> Result<(), StreamFlowError> {
+        self.to_layout().validate_curve(self.tranches())
+    }
+
+    /// Calculate amount of tokens vested (including already withdrawn)
+    /// with given timestamp. Never exceeds `deposited_amount`.
+    pub fn vested(&self, now: u64) -> Result<u64, StreamFlowError> {
+        self.to_layout().vested(self.tranches(), now)
+    }
+
+    /// Protocol and partner fees on a deposit of `amount`, at the
+    /// stream's rates
+    pub fn fees(&self, amount: u64) -> (u64, u64) {
+        self.to_layout().fees(amount)
+    }
+
+    /// Amount deposited before fees, transfer fees included
+    pub fn gross_deposited(&self) -> u64 {
+        self.to_layout().gross_deposited()
+    }
+
     /// Calculate amount available for withdrawal with given timestamp.
-    pub fn available(&self, now: u64) -> u64 {
-        if self.ix.start_time > now || self.ix.cliff > now {
-            return 0;
+    pub fn available(&self, now: u64) -> Result<u64, StreamFlowError> {
+        self.to_layout().available(self.tranches(), now)
+    }
+
+    /// Calculate timestamp when stream is cancellable, see
+    /// `StreamMetadata::closable`
+    pub fn closable(&self) -> Result<u64, StreamFlowError> {
+        self.to_layout().closable(self.tranches())
+    }
+
+    /// Status of the stream at the given time
+    pub fn status_at(&self, now: u64) -> StreamStatus {
+        self.to_layout().status_at(now)
+    }
+
+    /// Move the stream to the `to` status, see `StreamStatus::transition`
+    pub fn transition(&mut self, to: StreamStatus) -> Result<(), StreamFlowError> {
+        self.status = self.status.transition(to)?;
+        Ok(())
+    }
+
+    /// Status of accounts written before it was stored
+    fn legacy_status(&self) -> StreamStatus {
+        self.to_layout().legacy_status()
+    }
+
+    /// Token account withdrawals go to, unless the recipient signs for
+    /// another one
+    pub fn withdraw_destination(&self) -> Pubkey {
+        self.to_layout().withdraw_destination()
+    }
+
+    /// Bounty owed to a keeper cranking the stream at the given time, see
+    /// `StreamMetadata::crank_bounty`
+    pub fn crank_bounty(&self, now: u64) -> u64 {
+        self.to_layout().crank_bounty(now)
+    }
+
+    /// Whether the stream still waits for the recipient to accept it
+    pub fn pending(&self) -> bool {
+        self.to_layout().pending()
+    }
+
+    /// Activate a pending stream, see `StreamMetadata::accept`
+    pub fn accept(&mut self, now: u64) -> Result<(), StreamFlowError> {
+        let mut header = self.to_layout();
+        header.accept(self.tranches(), now)?;
+        self.status = header.status();
+        self.accepted_at = header.accepted_at;
+        self.paused_duration = header.paused_duration;
+        self.closable_at = header.closable_at;
+        Ok(())
+    }
+}
+
+/// Vesting schedule and lifecycle of a stream, computed from its header
+/// in place. `tranches` are the ones stored after the header, empty unless
+/// the stream vests in tranches.
+impl StreamMetadata {
+    /// Stored lifecycle status, inferred from the other fields for
+    /// accounts written before it was stored
+    pub fn status(&self) -> StreamStatus {
+        match StreamStatus::from_layout(self.status) {
+            Ok(Some(status)) => status,
+            _ => self.legacy_status(),
         }
+    }
 
-        // Ignore end date when recurring
-        if now >= self.ix.end_time && self.ix.release_rate == 0 {
-            return self.ix.deposited_amount - self.withdrawn_amount;
+    /// Move the stream to the `to` status, see `StreamStatus::transition`
+    pub fn transition(&mut self, to: StreamStatus) -> Result<(), StreamFlowError> {
+        self.status = self.status().transition(to)?.to_layout();
+        Ok(())
+    }
+
+    /// Fail with `StreamClosed` once the stream was canceled or completed
+    pub fn ensure_open(&self) -> Result<(), StreamFlowError> {
+        if self.status().is_closed() {
+            msg!("Error: Stream is closed");
+            return Err(StreamClosed);
         }
 
-        let cliff = if self.ix.cliff > 0 {
-            self.ix.cliff
+        Ok(())
+    }
+
+    /// Status of accounts written before it was stored, from the fields
+    /// it used to be inferred from
+    fn legacy_status(&self) -> StreamStatus {
+        if self.canceled_at > 0 {
+            StreamStatus::Canceled
+        } else if self.deposited_amount > 0 && self.withdrawn_amount >= self.deposited_amount {
+            StreamStatus::Completed
+        } else if self.paused_at > 0 {
+            StreamStatus::Paused
+        } else if self.pending() {
+            StreamStatus::Pending
         } else {
-            self.ix.start_time
-        };
+            StreamStatus::Active
+        }
+    }
+
+    /// Address and bump seed of the `escrow_tokens` PDA. Uses the stored
+    /// bump when available, saving the `find_program_address` search.
+    pub fn escrow_address(
+        &self,
+        program_id: &Pubkey,
+        metadata: &Pubkey,
+    ) -> Result<(Pubkey, u8), ProgramError> {
+        if self.escrow_tokens_bump == 0 {
+            return Ok(Pubkey::find_program_address(
+                &[metadata.as_ref()],
+                program_id,
+            ));
+        }
+
+        let escrow_tokens = Pubkey::create_program_address(
+            &[metadata.as_ref(), &[self.escrow_tokens_bump]],
+            program_id,
+        )
+        .map_err(|_| ProgramError::InvalidSeeds)?;
+        Ok((escrow_tokens, self.escrow_tokens_bump))
+    }
 
-        let cliff_amount = if self.ix.cliff_amount > 0 {
-            self.ix.cliff_amount
+    /// Returns the vesting starting point and the amount unlocked there.
+    fn cliff(&self) -> (u64, u64) {
+        let cliff_time = if self.cliff > 0 {
+            self.cliff
         } else {
-            0
+            self.start_time
         };
 
-        // TODO: Use uint arithmetics, floats are imprecise
-        let num_periods = (self.ix.end_time - cliff) as f64 / self.ix.period as f64;
-        let period_amount = if self.ix.release_rate > 0 {
-            self.ix.release_rate as f64
-        } else {
-            (self.ix.total_amount - cliff_amount) as f64 / num_periods
+        (cliff_time, self.cliff_amount)
+    }
+
+    /// Returns the number of periods between the cliff and the end of the
+    /// stream. Recurring streams are not bounded by the end time.
+    fn num_periods(&self) -> Result<u64, StreamFlowError> {
+        if self.release_rate > 0 {
+            return Ok(u64::MAX);
+        }
+
+        let (cliff_time, _) = self.cliff();
+        let num_periods = self
+            .end_time
+            .checked_sub(cliff_time)
+            .ok_or(ArithmeticError)?
+            .checked_div(self.period)
+            .ok_or(ArithmeticError)?;
+
+        // Streams shorter than a period unlock everything at once
+        Ok(num_periods.max(1))
+    }
+
+    /// Calculate amount unlocked after the cliff once `periods_passed`
+    /// periods have passed, following the stream's vesting curve.
+    /// Amounts are rounded down, the remainder is released with the
+    /// last period.
+    fn unlocked(&self, periods_passed: u64) -> Result<u128, StreamFlowError> {
+        let num_periods = self.num_periods()?;
+        let streamed = self
+            .total_amount
+            .checked_sub(self.cliff_amount)
+            .ok_or(ArithmeticError)? as u128;
+
+        if periods_passed >= num_periods {
+            return Ok(streamed);
+        }
+
+        let n = num_periods as u128;
+        let k = periods_passed as u128;
+        let unlocked = match self.vesting_curve {
+            CURVE_LINEAR if self.release_rate > 0 => Some(k * self.release_rate as u128),
+            CURVE_LINEAR => Some(k * (streamed / n)),
+            CURVE_FRONT_LOADED => k
+                .checked_mul(2 * n - k)
+                .and_then(|v| v.checked_mul(streamed))
+                .zip(n.checked_mul(n))
+                .map(|(v, d)| v / d),
+            CURVE_BACK_LOADED => k
+                .checked_mul(k)
+                .and_then(|v| v.checked_mul(streamed))
+                .zip(n.checked_mul(n))
+                .map(|(v, d)| v / d),
+            CURVE_HALVING => {
+                let h = self.curve_param as u128;
+                if h == 0 {
+                    return Err(ArithmeticError);
+                }
+                let epochs = (n + h - 1) / h;
+                if epochs > MAX_HALVINGS as u128 + 1 {
+                    return Err(ArithmeticError);
+                }
+                // Every epoch weighs half of the previous one, the last weighs 1
+                let weight = |k: u128| {
+                    let full = k / h;
+                    (h << epochs) - (h << (epochs - full)) + (((k % h) << (epochs - full)) >> 1)
+                };
+                streamed.checked_mul(weight(k)).map(|v| v / weight(n))
+            }
+            CURVE_BALLOON => {
+                let linear = streamed
+                    .checked_sub(self.curve_param as u128)
+                    .ok_or(ArithmeticError)?;
+                Some(k * (linear / n))
+            }
+            _ => return Err(InvalidVestingCurve),
         };
-        let periods_passed = (now - cliff) / self.ix.period;
-        (periods_passed as f64 * period_amount) as u64 + cliff_amount - self.withdrawn_amount
+
+        unlocked.ok_or(ArithmeticError)
     }
 
-    /// Calculate timestamp when stream is cancellable
-    /// end_time when deposit=total else time when funds run out
-    pub fn closable(&self) -> u64 {
-        let cliff_time = if self.ix.cliff > 0 {
-            self.ix.cliff
-        } else {
-            self.ix.start_time
+    /// Check that the vesting curve parameters are valid for this stream.
+    pub fn validate_curve(&self, tranches: &[Tranche]) -> Result<(), StreamFlowError> {
+        let valid = match self.vesting_curve {
+            CURVE_LINEAR => true,
+            _ if self.release_rate > 0 => false,
+            CURVE_FRONT_LOADED | CURVE_BACK_LOADED => true,
+            CURVE_HALVING => {
+                let halving_periods = self.curve_param;
+                halving_periods > 0 && (self.num_periods()? - 1) / halving_periods <= MAX_HALVINGS
+            }
+            CURVE_BALLOON => {
+                self.cliff_amount as u128 + self.curve_param as u128 <= self.total_amount as u128
+            }
+            CURVE_TRANCHES => return self.validate_tranches(tranches),
+            _ => false,
         };
 
-        let cliff_amount = if self.ix.cliff_amount > 0 {
-            self.ix.cliff_amount
+        if !valid {
+            return Err(InvalidVestingCurve);
+        }
+
+        // Make sure the whole curve can be computed
+        self.unlocked(self.num_periods()?.saturating_sub(1))?;
+        Ok(())
+    }
+
+    fn validate_tranches(&self, tranches: &[Tranche]) -> Result<(), StreamFlowError> {
+        if tranches.is_empty() || tranches.len() > MAX_TRANCHES {
+            msg!(
+                "Error: Number of tranches must be between 1 and {}",
+                MAX_TRANCHES
+            );
+            return Err(InvalidVestingCurve);
+        }
+
+        if self.cliff > 0 || self.cliff_amount > 0 {
+            msg!("Error: Cliff can't be used with tranches");
+            return Err(InvalidVestingCurve);
+        }
+
+        let mut last = None;
+        for t in tranches {
+            if t.timestamp < self.start_time || t.timestamp > self.end_time {
+                msg!("Error: Tranche at {} is outside of the stream", t.timestamp);
+                return Err(InvalidVestingCurve);
+            }
+            if last.map_or(false, |last| t.timestamp <= last) {
+                msg!("Error: Tranches are not sorted by timestamp");
+                return Err(InvalidVestingCurve);
+            }
+            last = Some(t.timestamp);
+        }
+
+        let sum: u128 = tranches.iter().map(|t| t.amount as u128).sum();
+        if sum != self.total_amount as u128 {
+            msg!("Error: Tranches sum up to {}, not total amount", sum);
+            return Err(InvalidVestingCurve);
+        }
+
+        Ok(())
+    }
+
+    /// Calculate amount of tokens vested (including already withdrawn)
+    /// with given timestamp. Never exceeds `deposited_amount`.
+    pub fn vested(&self, tranches: &[Tranche], now: u64) -> Result<u64, StreamFlowError> {
+        if self.pending() {
+            return Ok(0);
+        }
+
+        let now = self.unpaused_time(now);
+        if self.start_time > now || self.cliff > now {
+            return Ok(0);
+        }
+
+        // Ignore end date when recurring
+        if now >= self.end_time && self.release_rate == 0 {
+            return Ok(self.deposited_amount);
+        }
+
+        if self.vesting_curve == CURVE_TRANCHES {
+            let vested: u128 = tranches
+                .iter()
+                .take_while(|t| t.timestamp <= now)
+                .map(|t| t.amount as u128)
+                .sum();
+            return Ok(self.net_of_fees(vested).min(self.deposited_amount as u128) as u64);
+        }
+
+        let (cliff_time, cliff_amount) = self.cliff();
+        let periods_passed = (now - cliff_time)
+            .checked_div(self.period)
+            .ok_or(ArithmeticError)?;
+        let vested = self.unlocked(periods_passed)? + cliff_amount as u128;
+
+        Ok(self.net_of_fees(vested).min(self.deposited_amount as u128) as u64)
+    }
+
+    /// Protocol and partner fees on a deposit of `amount`, at the
+    /// stream's rates
+    pub fn fees(&self, amount: u64) -> (u64, u64) {
+        (
+            fee_amount(amount, self.protocol_fee_basis_points),
+            fee_amount(amount, self.partner_fee_basis_points),
+        )
+    }
+
+    /// Part of `amount` left to the recipient after the stream's fees.
+    /// The vesting schedule is given in amounts before fees, so that a
+    /// fully funded stream stays fully funded.
+    fn net_of_fees(&self, amount: u128) -> u128 {
+        let fee = |basis_points: u64| amount * basis_points as u128 / MAX_BASIS_POINTS as u128;
+        amount - fee(self.protocol_fee_basis_points) - fee(self.partner_fee_basis_points)
+    }
+
+    /// Amount deposited before fees, transfer fees included
+    pub fn gross_deposited(&self) -> u64 {
+        self.deposited_amount
+            .saturating_add(self.protocol_fee_amount)
+            .saturating_add(self.partner_fee_amount)
+            .saturating_add(self.transfer_fee_amount)
+    }
+
+    /// Calculate amount available for withdrawal with given timestamp.
+    pub fn available(&self, tranches: &[Tranche], now: u64) -> Result<u64, StreamFlowError> {
+        self.vested(tranches, now)?
+            .checked_sub(self.withdrawn_amount)
+            .ok_or(ArithmeticError)
+    }
+
+    /// Calculate timestamp when stream is cancellable
+    /// end_time when deposit=total else time when funds run out.
+    /// Paused streams aren't closable until resumed, unless funds ran
+    /// out before the pause. Pending streams are closable once their
+    /// acceptance deadline passed.
+    pub fn closable(&self, tranches: &[Tranche]) -> Result<u64, StreamFlowError> {
+        if self.pending() {
+            return Ok(match self.acceptance_deadline {
+                0 => u64::MAX,
+                deadline => deadline,
+            });
+        }
+
+        let closable_at = self
+            .closable_unpaused(tranches)?
+            .checked_add(self.paused_duration)
+            .ok_or(ArithmeticError)?;
+
+        if self.paused_at > 0 && closable_at > self.paused_at {
+            return Ok(u64::MAX);
+        }
+
+        Ok(closable_at)
+    }
+
+    /// Status of the stream at the given time
+    pub fn status_at(&self, now: u64) -> StreamStatus {
+        let status = self.status();
+        if status != StreamStatus::Active {
+            return status;
+        }
+
+        if self.unpaused_time(now) < self.start_time {
+            return StreamStatus::Scheduled;
+        }
+
+        let partially_funded = self.release_rate > 0 || self.gross_deposited() < self.total_amount;
+        if partially_funded && now >= self.closable_at {
+            return StreamStatus::Underfunded;
+        }
+
+        StreamStatus::Active
+    }
+
+    /// Token account withdrawals go to, unless the recipient signs for
+    /// another one
+    pub fn withdraw_destination(&self) -> Pubkey {
+        if self.destination_tokens == Pubkey::default() {
+            return self.recipient_tokens;
+        }
+
+        self.destination_tokens
+    }
+
+    /// Bounty owed to a keeper cranking the stream at the given time, 0
+    /// before `crank_interval` passed since the last withdrawal. Capped
+    /// by `keeper_bounty` and what's left in the pool.
+    pub fn crank_bounty(&self, now: u64) -> u64 {
+        let due = self.last_withdrawn_at.saturating_add(self.crank_interval);
+        if now < due {
+            return 0;
+        }
+
+        self.keeper_bounty.min(self.keeper_bounty_left)
+    }
+
+    /// Whether the stream still waits for the recipient to accept it
+    pub fn pending(&self) -> bool {
+        self.requires_acceptance != 0 && self.accepted_at == 0
+    }
+
+    /// Activate a pending stream. Vesting starts at the acceptance when
+    /// the stream was accepted after its start time, the whole schedule
+    /// is shifted by the delay.
+    pub fn accept(&mut self, tranches: &[Tranche], now: u64) -> Result<(), StreamFlowError> {
+        self.transition(StreamStatus::Active)?;
+        self.accepted_at = now;
+        self.paused_duration = self
+            .paused_duration
+            .checked_add(now.saturating_sub(self.start_time))
+            .ok_or(ArithmeticError)?;
+        self.closable_at = self.closable(tranches)?;
+        Ok(())
+    }
+
+    /// Shift given timestamp back by the time the stream spent paused,
+    /// so that nothing vests while the stream is paused.
+    fn unpaused_time(&self, now: u64) -> u64 {
+        let paused = if self.paused_at > 0 {
+            now.saturating_sub(self.paused_at)
         } else {
             0
         };
+        now.saturating_sub(self.paused_duration.saturating_add(paused))
+    }
+
+    /// Calculate timestamp when stream is cancellable, ignoring pauses
+    fn closable_unpaused(&self, tranches: &[Tranche]) -> Result<u64, StreamFlowError> {
+        let (cliff_time, cliff_amount) = self.cliff();
+        // The schedule is given in amounts before fees
+        let deposited = self.gross_deposited();
+
         // Deposit smaller then cliff amount, cancelable at cliff
-        if self.ix.deposited_amount < cliff_amount {
-            return cliff_time;
+        if deposited <= cliff_amount {
+            return Ok(cliff_time);
+        }
+
+        // First tranche covering the deposit
+        if self.vesting_curve == CURVE_TRANCHES {
+            let mut unlocked = 0;
+            for t in tranches {
+                unlocked += t.amount as u128;
+                if unlocked >= deposited as u128 {
+                    return Ok(t.timestamp);
+                }
+            }
+            return Ok(self.end_time);
         }
-        // Nr of seconds after the cliff
-        let seconds_nr = self.ix.end_time - cliff_time;
 
-        let amount_per_second = if self.ix.release_rate > 0 {
-            self.ix.release_rate / self.ix.period
+        // Periods till account runs out of available funds (rounded up)
+        let remaining = (deposited - cliff_amount) as u128;
+        let num_periods = self.num_periods()?;
+        let periods_left = if self.vesting_curve == CURVE_LINEAR {
+            let period_amount = self.unlocked(1)?;
+            if period_amount == 0 {
+                // Everything is released with the last period
+                num_periods as u128
+            } else {
+                (remaining + period_amount - 1) / period_amount
+            }
         } else {
-            // stream per second
-            ((self.ix.total_amount - cliff_amount) / seconds_nr) as u64
+            // Curves are monotonic, find the first period covering the deposit
+            let (mut lo, mut hi) = (0, num_periods);
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                if self.unlocked(mid)? >= remaining {
+                    hi = mid;
+                } else {
+                    lo = mid + 1;
+                }
+            }
+            lo as u128
         };
-        // Seconds till account runs out of available funds, +1 as ceil (integer)
-        let seconds_left = ((self.ix.deposited_amount - cliff_amount) / amount_per_second) + 1;
 
         msg!(
-            "Release {}, Period {}, seconds left {}",
-            self.ix.release_rate,
-            self.ix.period,
-            seconds_left
+            "Release {}, Period {}, periods left {}",
+            self.release_rate,
+            self.period,
+            periods_left
         );
         // closable_at time, ignore end time when recurring
-        if cliff_time + seconds_left > self.ix.end_time && self.ix.release_rate == 0 {
-            self.ix.end_time
-        } else {
-            cliff_time + seconds_left
+        if periods_left >= num_periods as u128 {
+            return Ok(self.end_time);
+        }
+
+        let closable_at = periods_left * self.period as u128 + cliff_time as u128;
+        if closable_at > u64::MAX as u128 {
+            return Err(ArithmeticError);
+        }
+
+        Ok(closable_at as u64)
+    }
+}
+
+/// Metadata account of a stream, borrowed for the length of an instruction.
+///
+/// Accounts in the current layout are read and changed in place. Accounts
+/// still in the `PROGRAM_VERSION` 2 layout are decoded into a copy of the
+/// header and written back in their own layout by `close`, so live streams
+/// keep working until they are migrated.
+pub struct StreamAccount<'a, 'b> {
+    data: RefMut<'a, &'b mut [u8]>,
+    legacy: Option<(StreamMetadata, Vec<Tranche>)>,
+}
+
+impl<'a, 'b> StreamAccount<'a, 'b> {
+    /// Borrow the data of the metadata `account`
+    pub fn open(account: &'a AccountInfo<'b>) -> Result<Self, ProgramError> {
+        let data = account.try_borrow_mut_data()?;
+        let legacy = match TokenStreamData::version(&data)? {
+            PROGRAM_VERSION => None,
+            2 => {
+                let metadata = TokenStreamData::load(&data)?;
+                Some((metadata.to_layout(), metadata.tranches().to_vec()))
+            }
+            version => {
+                msg!("Error: Metadata version {} must be migrated first", version);
+                return Err(MigrationRequired.into());
+            }
+        };
+
+        Ok(Self { data, legacy })
+    }
+
+    /// Header of the stream and its tranches
+    pub fn header(&mut self) -> Result<(&mut StreamMetadata, &[Tranche]), ProgramError> {
+        match &mut self.legacy {
+            Some((header, tranches)) => Ok((header, tranches.as_slice())),
+            None => StreamMetadata::split_account_data_mut(&mut self.data),
+        }
+    }
+
+    /// Write back the changes to an account in the `PROGRAM_VERSION` 2
+    /// layout, accounts in the current one are already up to date
+    pub fn close(mut self) -> ProgramResult {
+        match &self.legacy {
+            Some((header, tranches)) => {
+                TokenStreamData::from_header(header, tranches)?.save_v2(&mut self.data)
+            }
+            None => Ok(()),
         }
     }
 }
@@ -257,20 +1415,24 @@ impl TokenStreamData {
 pub struct InitializeAccounts<'a> {
     /// The main wallet address of the initializer.
     pub sender: AccountInfo<'a>,
-    /// The associated token account address of `sender`.
+    /// The associated token account address of `sender`,
+    /// or `sender` itself for native SOL streams.
     pub sender_tokens: AccountInfo<'a>,
     /// The main wallet address of the recipient.
     pub recipient: AccountInfo<'a>,
-    /// The associated token account address of `recipient`.
+    /// The associated token account address of `recipient`,
+    /// or `recipient` itself for native SOL streams.
     /// (Can be either empty or initialized).
     pub recipient_tokens: AccountInfo<'a>,
     /// The account holding the stream metadata.
-    /// Expects empty (non-initialized) account.
+    /// Expects empty (non-initialized) account, either a new keypair
+    /// or, when created with a seed, the PDA from `find_metadata_address`.
     pub metadata: AccountInfo<'a>,
     /// The escrow account holding the stream funds.
     /// Expects empty (non-initialized) account.
     pub escrow_tokens: AccountInfo<'a>,
-    /// The SPL token mint account
+    /// The SPL token mint account, `spl_token::native_mint` for
+    /// streams paying out native SOL (lamports)
     pub mint: AccountInfo<'a>,
     /// The Rent Sysvar account
     pub rent: AccountInfo<'a>,
@@ -282,20 +1444,33 @@ pub struct InitializeAccounts<'a> {
     pub associated_token_program: AccountInfo<'a>,
     /// The Solana system program
     pub system_program: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`
+    pub config: AccountInfo<'a>,
+    /// The mint registry account, see `find_mint_registry_address`
+    pub mint_registry: AccountInfo<'a>,
+    /// The fee treasury's associated token account, or the treasury
+    /// wallet itself for native SOL streams
+    pub fee_treasury_tokens: AccountInfo<'a>,
+    /// The partner's token account, or wallet for native SOL streams.
+    /// Optional, the partner fee is only charged when given.
+    pub partner_tokens: Option<AccountInfo<'a>>,
 }
 
 /// The account-holding struct for the stream withdraw instruction
 pub struct WithdrawAccounts<'a> {
-    /// Account invoking transaction. Must match `recipient`
-    // Same as `recipient` if `withdrawal_public == true`, otherwise
-    // any other account.
+    /// Account invoking transaction. Must match `recipient`, unless
+    /// `withdrawal_public == true`, in which case any account can
+    /// withdraw on behalf of the recipient.
     pub withdraw_authority: AccountInfo<'a>,
     /// Sender account is needed to collect the rent for escrow token
     /// account after the last withdrawal
     pub sender: AccountInfo<'a>,
     /// Recipient's wallet address
     pub recipient: AccountInfo<'a>,
-    /// The associated token account address of a stream `recipient`
+    /// The account receiving the funds: the stream's
+    /// `withdraw_destination`, or any token account of the mint when
+    /// `withdraw_authority` is the recipient (any wallet for native
+    /// SOL streams)
     pub recipient_tokens: AccountInfo<'a>,
     /// The account holding the stream metadata
     pub metadata: AccountInfo<'a>,
@@ -305,6 +1480,15 @@ pub struct WithdrawAccounts<'a> {
     pub mint: AccountInfo<'a>,
     /// The SPL token program
     pub token_program: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`. Clients
+    /// predating the config leave it out, the defaults apply then.
+    pub config: Option<AccountInfo<'a>>,
+    /// The sender's token account, getting back what's left of a token
+    /// bounty pool after the last withdrawal. Optional otherwise.
+    pub sender_tokens: Option<AccountInfo<'a>>,
+    /// The keeper's token account for bounties paid in tokens. Optional,
+    /// keepers cranking without one forgo the bounty.
+    pub keeper_tokens: Option<AccountInfo<'a>>,
 }
 
 /// The account-holding struct for the stream cancel instruction
@@ -316,11 +1500,15 @@ pub struct CancelAccounts<'a> {
     pub cancel_authority: AccountInfo<'a>,
     /// The main wallet address of the initializer
     pub sender: AccountInfo<'a>,
-    /// The associated token account address of `sender`
+    /// The associated token account address of `sender`,
+    /// or `sender` itself for native SOL streams
     pub sender_tokens: AccountInfo<'a>,
     /// The main wallet address of the recipient
     pub recipient: AccountInfo<'a>,
-    /// The associated token account address of `recipient`
+    /// The account receiving the recipient's share, like for a
+    /// withdrawal: the stream's `withdraw_destination`, or any token
+    /// account of the mint when the recipient signs the cancel. The
+    /// recipient's own `recipient_tokens` is always accepted.
     pub recipient_tokens: AccountInfo<'a>,
     /// The account holding the stream metadata
     pub metadata: AccountInfo<'a>,
@@ -330,6 +1518,9 @@ pub struct CancelAccounts<'a> {
     pub mint: AccountInfo<'a>,
     /// The SPL token program
     pub token_program: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`. Clients
+    /// predating the config leave it out, the defaults apply then.
+    pub config: Option<AccountInfo<'a>>,
 }
 
 /// Accounts needed for updating stream recipient
@@ -338,7 +1529,8 @@ pub struct TransferAccounts<'a> {
     pub authorized_wallet: AccountInfo<'a>,
     /// New stream beneficiary
     pub new_recipient: AccountInfo<'a>,
-    /// New stream beneficiary's token account.
+    /// New stream beneficiary's token account (`new_recipient` itself
+    /// for native SOL streams).
     /// If not initialized, it will be created and
     /// `existing_recipient` is the fee payer
     pub new_recipient_tokens: AccountInfo<'a>,
@@ -359,6 +1551,8 @@ pub struct TransferAccounts<'a> {
     /// The Solana system program needed in case associated
     /// account for the new recipients is being created.
     pub system_program: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`
+    pub config: AccountInfo<'a>,
 }
 
 /// The account-holding struct for the stream topup instruction
@@ -366,7 +1560,8 @@ pub struct TransferAccounts<'a> {
 pub struct TopUpAccounts<'a> {
     /// The main wallet address of the initializer.
     pub sender: AccountInfo<'a>,
-    /// The associated token account address of `sender`.
+    /// The associated token account address of `sender`,
+    /// or `sender` itself for native SOL streams.
     pub sender_tokens: AccountInfo<'a>,
     /// The account holding the stream metadata.
     /// Expects existing account.
@@ -378,4 +1573,1024 @@ pub struct TopUpAccounts<'a> {
     pub mint: AccountInfo<'a>,
     /// The SPL program needed for transfer
     pub token_program: AccountInfo<'a>,
+    /// The Solana system program, needed to top up native SOL streams
+    pub system_program: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`
+    pub config: AccountInfo<'a>,
+    /// The mint registry account, see `find_mint_registry_address`
+    pub mint_registry: AccountInfo<'a>,
+    /// The fee treasury's associated token account, or the treasury
+    /// wallet itself for native SOL streams
+    pub fee_treasury_tokens: AccountInfo<'a>,
+    /// The stream's `partner_tokens`, needed if it pays a partner fee
+    pub partner_tokens: Option<AccountInfo<'a>>,
+}
+
+/// Accounts needed for pausing and resuming a stream
+pub struct PauseAccounts<'a> {
+    /// Wallet address allowed to pause the stream
+    pub authority: AccountInfo<'a>,
+    /// The account holding the stream metadata
+    pub metadata: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`
+    pub config: AccountInfo<'a>,
+}
+
+/// Accounts needed for migrating stream metadata to the current layout
+pub struct MigrateAccounts<'a> {
+    /// Any wallet, pays the rent for the grown metadata account
+    pub payer: AccountInfo<'a>,
+    /// The account holding the stream metadata
+    pub metadata: AccountInfo<'a>,
+    /// The Solana system program
+    pub system_program: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`
+    pub config: AccountInfo<'a>,
+}
+
+/// Accounts needed for creating the program config
+pub struct InitConfigAccounts<'a> {
+    /// The program's upgrade authority, pays for the config account
+    pub upgrade_authority: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`.
+    /// Expects empty (non-initialized) account.
+    pub config: AccountInfo<'a>,
+    /// The program's `ProgramData` account, holding its upgrade authority
+    pub program_data: AccountInfo<'a>,
+    /// The Solana system program
+    pub system_program: AccountInfo<'a>,
+}
+
+/// Accounts needed for updating the program config
+pub struct UpdateConfigAccounts<'a> {
+    /// The config admin
+    pub admin: AccountInfo<'a>,
+    /// The program config account
+    pub config: AccountInfo<'a>,
+}
+
+/// Accounts needed for adding and removing registry mints
+pub struct MintRegistryAccounts<'a> {
+    /// The config admin, paying for the registry
+    pub admin: AccountInfo<'a>,
+    /// The program config account
+    pub config: AccountInfo<'a>,
+    /// The mint registry account, see `find_mint_registry_address`
+    pub mint_registry: AccountInfo<'a>,
+    /// The Solana system program
+    pub system_program: AccountInfo<'a>,
+}
+
+/// Accounts needed for pausing and unpausing instructions
+pub struct SetPauseAccounts<'a> {
+    /// The config guardian or admin
+    pub authority: AccountInfo<'a>,
+    /// The program config account
+    pub config: AccountInfo<'a>,
+}
+
+/// The account-holding struct for the stream accept instruction
+pub struct AcceptAccounts<'a> {
+    /// The stream recipient, paying for `recipient_tokens` if needed
+    pub recipient: AccountInfo<'a>,
+    /// The associated token account address of `recipient`,
+    /// or `recipient` itself for native SOL streams.
+    /// Created if not initialized.
+    pub recipient_tokens: AccountInfo<'a>,
+    /// The account holding the stream metadata
+    pub metadata: AccountInfo<'a>,
+    /// The SPL token mint account
+    pub mint: AccountInfo<'a>,
+    /// The Rent Sysvar account
+    pub rent: AccountInfo<'a>,
+    /// The SPL token program
+    pub token_program: AccountInfo<'a>,
+    /// The Associated Token program
+    pub associated_token_program: AccountInfo<'a>,
+    /// The Solana system program
+    pub system_program: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`
+    pub config: AccountInfo<'a>,
+}
+
+/// The account-holding struct for the set withdraw destination instruction
+pub struct SetDestinationAccounts<'a> {
+    /// The stream recipient
+    pub recipient: AccountInfo<'a>,
+    /// The account holding the stream metadata
+    pub metadata: AccountInfo<'a>,
+    /// The new default destination, a token account of the stream's
+    /// mint, or any wallet for native SOL streams
+    pub destination_tokens: AccountInfo<'a>,
+    /// The program config account, see `find_config_address`
+    pub config: AccountInfo<'a>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::layout::*;
+
+    const TEN_YEARS: u64 = 10 * 365 * 24 * 60 * 60;
+    const START: u64 = 1_600_000_000;
+
+    fn stream(start: u64, end: u64, period: u64, deposited: u64, total: u64) -> TokenStreamData {
+        TokenStreamData {
+            magic: PROGRAM_VERSION,
+            ix: StreamInstruction {
+                start_time: start,
+                end_time: end,
+                deposited_amount: deposited,
+                total_amount: total,
+                period,
+                ..Default::default()
+            },
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_available_floor_per_period() {
+        // 10 tokens over 3 periods, 3 per period, remainder on the last one
+        let s = stream(START, START + 3, 1, 10, 10);
+        assert_eq!(s.available(START - 1), Ok(0));
+        assert_eq!(s.available(START), Ok(0));
+        assert_eq!(s.available(START + 1), Ok(3));
+        assert_eq!(s.available(START + 2), Ok(6));
+        assert_eq!(s.available(START + 3), Ok(10));
+        assert_eq!(s.available(START + 100), Ok(10));
+
+        // Period not dividing the duration, last period closes the gap
+        let s = stream(START, START + 10, 3, 100, 100);
+        assert_eq!(s.available(START + 2), Ok(0));
+        assert_eq!(s.available(START + 3), Ok(33));
+        assert_eq!(s.available(START + 8), Ok(66));
+        assert_eq!(s.available(START + 9), Ok(100));
+    }
+
+    #[test]
+    fn test_available_cliff() {
+        let mut s = stream(START, START + 100, 10, 1_000, 1_000);
+        s.ix.cliff = START + 20;
+        s.ix.cliff_amount = 200;
+        assert_eq!(s.available(START + 19), Ok(0));
+        assert_eq!(s.available(START + 20), Ok(200));
+        // 8 periods of 100 after the cliff
+        assert_eq!(s.available(START + 30), Ok(300));
+        assert_eq!(s.available(START + 99), Ok(900));
+        assert_eq!(s.available(START + 100), Ok(1_000));
+    }
+
+    #[test]
+    fn test_available_huge_amounts() {
+        let s = stream(START, START + TEN_YEARS, 1, u64::MAX, u64::MAX);
+        let period_amount = u64::MAX / TEN_YEARS;
+        for passed in [1, 60, 86_400, TEN_YEARS / 2, TEN_YEARS - 1] {
+            assert_eq!(s.available(START + passed), Ok(passed * period_amount));
+        }
+        assert_eq!(s.available(START + TEN_YEARS), Ok(u64::MAX));
+
+        // Partial deposit never unlocks more than deposited
+        let s = stream(START, START + TEN_YEARS, 1, u64::MAX / 2, u64::MAX);
+        assert_eq!(s.available(START + TEN_YEARS - 1), Ok(u64::MAX / 2));
+    }
+
+    #[test]
+    fn test_available_one_second_periods_ten_years() {
+        let total = 1_000_000_000 * 100_000_000; // 1B tokens with 8 decimals
+        let mut s = stream(START, START + TEN_YEARS, 1, total, total);
+        let period_amount = total / TEN_YEARS;
+
+        let mut last = 0;
+        for passed in (0..TEN_YEARS).step_by(86_400 * 7) {
+            let available = s.available(START + passed).unwrap();
+            assert_eq!(available, passed * period_amount);
+            assert!(available >= last);
+            last = available;
+        }
+
+        s.withdrawn_amount = last;
+        assert_eq!(s.available(START + TEN_YEARS), Ok(total - last));
+    }
+
+    #[test]
+    fn test_available_recurring() {
+        let mut s = stream(START, START + 1_000, 200, 1_000, 0);
+        s.ix.release_rate = 300;
+        assert_eq!(s.available(START + 199), Ok(0));
+        assert_eq!(s.available(START + 200), Ok(300));
+        assert_eq!(s.available(START + 600), Ok(900));
+        // Not bounded by end time, but by deposited amount
+        assert_eq!(s.available(START + 800), Ok(1_000));
+        assert_eq!(s.available(START + TEN_YEARS), Ok(1_000));
+    }
+
+    #[test]
+    fn test_available_errors() {
+        let mut s = stream(START, START + 100, 1, 100, 100);
+        s.withdrawn_amount = 50;
+        assert_eq!(s.available(START + 10), Err(ArithmeticError));
+
+        let s = stream(START, START + 100, 0, 100, 100);
+        assert_eq!(s.available(START + 10), Err(ArithmeticError));
+
+        let mut s = stream(START, START + 100, 1, 100, 100);
+        s.ix.cliff_amount = 200;
+        assert_eq!(s.available(START + 10), Err(ArithmeticError));
+    }
+
+    #[test]
+    fn test_closable() {
+        // Fully funded, closable at the end
+        let s = stream(START, START + 1_000, 1, 2_000, 2_000);
+        assert_eq!(s.closable(), Ok(START + 1_000));
+
+        // Half funded, runs out halfway
+        let s = stream(START, START + 1_000, 1, 1_000, 2_000);
+        assert_eq!(s.closable(), Ok(START + 500));
+        assert_eq!(s.available(START + 500), Ok(1_000));
+
+        // Rounded up to the period in which funds run out
+        let s = stream(START, START + 1_000, 100, 1_050, 2_000);
+        assert_eq!(s.closable(), Ok(START + 600));
+
+        // Deposit covered by the cliff
+        let mut s = stream(START, START + 1_000, 1, 100, 2_000);
+        s.ix.cliff = START + 10;
+        s.ix.cliff_amount = 100;
+        assert_eq!(s.closable(), Ok(START + 10));
+
+        // Recurring ignores end time
+        let mut s = stream(START, START + 1_000, 200, 3_000, 0);
+        s.ix.release_rate = 100;
+        assert_eq!(s.closable(), Ok(START + 6_000));
+
+        let s = stream(START, START + TEN_YEARS, 1, u64::MAX / 3, u64::MAX);
+        let closable = s.closable().unwrap();
+        assert_eq!(s.available(closable), Ok(u64::MAX / 3));
+        assert!(s.available(closable - 1).unwrap() < u64::MAX / 3);
+    }
+
+    #[test]
+    fn test_available_fees() {
+        // 1% protocol and 0.5% partner fee taken out of a full deposit
+        let mut s = stream(START, START + 1_000, 1, 9_850, 10_000);
+        s.protocol_fee_basis_points = 100;
+        s.partner_fee_basis_points = 50;
+        s.protocol_fee_amount = 100;
+        s.partner_fee_amount = 50;
+        assert_eq!(s.fees(10_000), (100, 50));
+        assert_eq!(s.closable(), Ok(START + 1_000));
+        assert_eq!(s.available(START + 500), Ok(4_925));
+        assert_eq!(s.available(START + 1_000), Ok(9_850));
+
+        // Half of the gross deposit runs out halfway
+        s.ix.deposited_amount = 4_925;
+        s.protocol_fee_amount = 50;
+        s.partner_fee_amount = 25;
+        assert_eq!(s.closable(), Ok(START + 500));
+        assert_eq!(s.available(START + 500), Ok(4_925));
+    }
+
+    #[test]
+    fn test_closable_transfer_fee() {
+        // 1% withheld by the mint on a full deposit
+        let mut s = stream(START, START + 1_000, 1, 9_900, 10_000);
+        s.closable_at = START + 1_000;
+        assert!(s.closable().unwrap() < START + 1_000);
+
+        s.transfer_fee_amount = 100;
+        assert_eq!(s.gross_deposited(), 10_000);
+        assert_eq!(s.closable(), Ok(START + 1_000));
+        assert_eq!(s.status_at(START + 995), StreamStatus::Active);
+        assert_eq!(s.available(START + 1_000), Ok(9_900));
+    }
+
+    #[test]
+    fn test_vesting_curves() {
+        let mut s = stream(START, START + 100, 10, 1_000, 1_000);
+        let at = |s: &TokenStreamData, periods: u64| s.available(START + periods * 10).unwrap();
+
+        s.ix.vesting_curve = VestingCurve::FrontLoaded;
+        assert_eq!(at(&s, 1), 190);
+        assert_eq!(at(&s, 5), 750);
+        assert_eq!(at(&s, 9), 990);
+        assert_eq!(at(&s, 10), 1_000);
+
+        s.ix.vesting_curve = VestingCurve::BackLoaded;
+        assert_eq!(at(&s, 1), 10);
+        assert_eq!(at(&s, 5), 250);
+        assert_eq!(at(&s, 9), 810);
+        assert_eq!(at(&s, 10), 1_000);
+
+        // 2 epochs of 5 periods, weights 2 and 1
+        s.ix.vesting_curve = VestingCurve::Halving { halving_periods: 5 };
+        assert_eq!(at(&s, 1), 133);
+        assert_eq!(at(&s, 5), 666);
+        assert_eq!(at(&s, 6), 733);
+        assert_eq!(at(&s, 10), 1_000);
+
+        s.ix.vesting_curve = VestingCurve::Balloon {
+            balloon_amount: 200,
+        };
+        s.ix.cliff = START + 20;
+        s.ix.cliff_amount = 200;
+        // 8 periods of 75 after the cliff
+        assert_eq!(at(&s, 2), 200);
+        assert_eq!(at(&s, 3), 275);
+        assert_eq!(at(&s, 9), 725);
+        assert_eq!(at(&s, 10), 1_000);
+    }
+
+    #[test]
+    fn test_vesting_curves_closable() {
+        let mut s = stream(START, START + 100, 10, 750, 1_000);
+        s.ix.vesting_curve = VestingCurve::FrontLoaded;
+        assert_eq!(s.closable(), Ok(START + 50));
+
+        s.ix.vesting_curve = VestingCurve::BackLoaded;
+        assert_eq!(s.closable(), Ok(START + 90));
+
+        s.ix.vesting_curve = VestingCurve::Balloon {
+            balloon_amount: 500,
+        };
+        assert_eq!(s.closable(), Ok(START + 100));
+
+        s.ix.vesting_curve = VestingCurve::Halving { halving_periods: 1 };
+        let closable = s.closable().unwrap();
+        assert_eq!(s.available(closable), Ok(750));
+        assert!(s.available(closable - 10).unwrap() < 750);
+    }
+
+    #[test]
+    fn test_validate_curve() {
+        let mut s = stream(START, START + TEN_YEARS, 1, u64::MAX, u64::MAX);
+        for curve in [
+            VestingCurve::Linear,
+            VestingCurve::FrontLoaded,
+            VestingCurve::BackLoaded,
+            VestingCurve::Balloon {
+                balloon_amount: u64::MAX,
+            },
+            VestingCurve::Halving {
+                halving_periods: TEN_YEARS / MAX_HALVINGS,
+            },
+        ] {
+            s.ix.vesting_curve = curve;
+            assert_eq!(s.validate_curve(), Ok(()));
+        }
+
+        s.ix.vesting_curve = VestingCurve::Halving { halving_periods: 0 };
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+        s.ix.vesting_curve = VestingCurve::Halving {
+            halving_periods: TEN_YEARS / (MAX_HALVINGS + 2),
+        };
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+
+        s.ix.cliff_amount = 1;
+        s.ix.vesting_curve = VestingCurve::Balloon {
+            balloon_amount: u64::MAX,
+        };
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+
+        s.ix.release_rate = 100;
+        s.ix.vesting_curve = VestingCurve::FrontLoaded;
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+    }
+
+    /// Encode the instruction and metadata in the `PROGRAM_VERSION` 2 layout,
+    /// before any fields were appended.
+    fn v2_bytes(s: &TokenStreamData) -> (Vec<u8>, Vec<u8>) {
+        let ix = &s.ix;
+        let ix_bytes = (
+            (
+                ix.start_time,
+                ix.end_time,
+                ix.deposited_amount,
+                ix.total_amount,
+            ),
+            (ix.period, ix.cliff, ix.cliff_amount),
+            (ix.cancelable_by_sender, ix.cancelable_by_recipient),
+            (ix.withdrawal_public, ix.transferable_by_sender),
+            (ix.transferable_by_recipient, ix.release_rate),
+            &ix.stream_name,
+        )
+            .try_to_vec()
+            .unwrap();
+
+        let mut bytes = (
+            (2u64, s.created_at, s.withdrawn_amount),
+            (s.canceled_at, s.closable_at, s.last_withdrawn_at),
+            (s.sender, s.sender_tokens, s.recipient),
+            (s.recipient_tokens, s.mint, s.escrow_tokens),
+        )
+            .try_to_vec()
+            .unwrap();
+        bytes.extend_from_slice(&ix_bytes);
+
+        (ix_bytes, bytes)
+    }
+
+    #[test]
+    fn test_decode_without_vesting_curve() {
+        let mut s = stream(START, START + 100, 10, 1_000, 1_000);
+        s.ix.vesting_curve = VestingCurve::Halving { halving_periods: 2 };
+        let bytes = s.try_to_vec().unwrap();
+        let decoded: TokenStreamData =
+            solana_program::borsh::try_from_slice_unchecked(&bytes).unwrap();
+        assert_eq!(decoded.ix.vesting_curve, s.ix.vesting_curve);
+
+        // Accounts and instructions created before vesting curves existed
+        s.ix.vesting_curve = VestingCurve::Linear;
+        let (ix_bytes, mut bytes) = v2_bytes(&s);
+        let ix = StreamInstruction::try_from_slice(&ix_bytes).unwrap();
+        assert_eq!(ix.vesting_curve, VestingCurve::Linear);
+
+        bytes.extend_from_slice(&[0; 5]);
+        let decoded: TokenStreamData =
+            solana_program::borsh::try_from_slice_unchecked(&bytes).unwrap();
+        assert_eq!(decoded.ix.vesting_curve, VestingCurve::Linear);
+        assert_eq!(decoded.ix.stream_name, s.ix.stream_name);
+
+        // Older, smaller accounts must grow to the fixed-size layout first
+        let mut data = vec![0; bytes.len() - 5];
+        assert_eq!(s.save(&mut data), Err(ProgramError::AccountDataTooSmall));
+        s.ix.vesting_curve = VestingCurve::BackLoaded;
+        let mut data = vec![0; s.space()];
+        assert_eq!(s.save(&mut data), Ok(()));
+    }
+
+    #[test]
+    fn test_decode_legacy_layout() {
+        let mut s = stream(START, START + 100, 10, 1_000, 1_000);
+        s.ix.vesting_curve = VestingCurve::Halving { halving_periods: 2 };
+        s.ix.pausable_by_sender = true;
+        s.paused_at = START + 50;
+        s.paused_duration = 10;
+        let bytes = s.try_to_vec().unwrap();
+        let decoded: TokenStreamData =
+            solana_program::borsh::try_from_slice_unchecked(&bytes).unwrap();
+        assert_eq!(decoded.ix.vesting_curve, s.ix.vesting_curve);
+        assert!(decoded.ix.pausable_by_sender);
+        assert_eq!(decoded.paused_at, s.paused_at);
+        assert_eq!(decoded.paused_duration, s.paused_duration);
+
+        // Accounts and instructions created before fields were appended
+        let s = stream(START, START + 100, 10, 1_000, 1_000);
+        let (ix_bytes, mut bytes) = v2_bytes(&s);
+        let ix = StreamInstruction::try_from_slice(&ix_bytes).unwrap();
+        assert_eq!(ix.vesting_curve, VestingCurve::Linear);
+        assert!(!ix.pausable_by_sender);
+
+        // Padded to 8 bytes on creation
+        bytes.extend_from_slice(&[0; 5]);
+        let decoded = TokenStreamData::load(&bytes).unwrap();
+        assert_eq!(decoded.magic, 2);
+        assert_eq!(decoded.ix.vesting_curve, VestingCurve::Linear);
+        assert_eq!(decoded.ix.stream_name, s.ix.stream_name);
+        assert_eq!(decoded.paused_at, 0);
+        assert_eq!(decoded.escrow_tokens_bump, 0);
+
+        // Must be migrated to the fixed-size layout before writing
+        let mut data = bytes;
+        let mut s = decoded;
+        s.withdrawn_amount = 100;
+        assert_eq!(
+            s.save(&mut data),
+            Err(StreamFlowError::MigrationRequired.into())
+        );
+        s.magic = PROGRAM_VERSION;
+        assert_eq!(s.save(&mut data), Err(ProgramError::AccountDataTooSmall));
+        let mut data = vec![0; s.space()];
+        assert_eq!(s.save(&mut data), Ok(()));
+        assert_eq!(TokenStreamData::load(&data).unwrap().withdrawn_amount, 100);
+    }
+
+    #[test]
+    fn test_stream_account_legacy_layout() {
+        let s = stream(START, START + 100, 10, 1_000, 1_000);
+        let (_, mut bytes) = v2_bytes(&s);
+        bytes.extend_from_slice(&[0; 5]);
+        let (key, owner, mut lamports) = (Pubkey::new_unique(), Pubkey::new_unique(), 0);
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut bytes,
+            &owner,
+            false,
+            0,
+        );
+
+        // Live streams are written back in their own layout
+        let mut stream_account = StreamAccount::open(&account).unwrap();
+        let (metadata, _) = stream_account.header().unwrap();
+        assert_eq!(metadata.available(&[], START + 10), Ok(100));
+        metadata.withdrawn_amount = 100;
+        metadata.last_withdrawn_at = START + 10;
+        stream_account.close().unwrap();
+        let decoded = TokenStreamData::load(&account.data.borrow()).unwrap();
+        assert_eq!(decoded.magic, 2);
+        assert_eq!(decoded.withdrawn_amount, 100);
+        assert_eq!(decoded.last_withdrawn_at, START + 10);
+
+        // Unless the change needs the current layout
+        let mut stream_account = StreamAccount::open(&account).unwrap();
+        let (metadata, _) = stream_account.header().unwrap();
+        metadata.withdrawn_amount = 200;
+        metadata.destination_tokens = Pubkey::new_unique();
+        assert_eq!(
+            stream_account.close(),
+            Err(StreamFlowError::MigrationRequired.into())
+        );
+        let decoded = TokenStreamData::load(&account.data.borrow()).unwrap();
+        assert_eq!(decoded.withdrawn_amount, 100);
+    }
+
+    /// Hand-craft metadata account data in the `PROGRAM_VERSION` 1 layout
+    fn v1_bytes(s: &TokenStreamData) -> Vec<u8> {
+        let ix = &s.ix;
+        let mut bytes = vec![];
+        for v in [
+            1,
+            ix.start_time,
+            ix.end_time,
+            ix.deposited_amount,
+            ix.total_amount,
+            ix.period,
+            ix.cliff,
+            ix.cliff_amount,
+            s.created_at,
+            s.withdrawn_amount,
+            s.canceled_at,
+        ] {
+            bytes.extend_from_slice(&v.to_le_bytes());
+        }
+        for key in [
+            s.sender,
+            s.sender_tokens,
+            s.recipient,
+            s.recipient_tokens,
+            s.mint,
+            s.escrow_tokens,
+        ] {
+            bytes.extend_from_slice(key.as_ref());
+        }
+
+        assert_eq!(bytes.len(), V1_METADATA_SIZE);
+        bytes
+    }
+
+    #[test]
+    fn test_load_v1_layout() {
+        let mut s = stream(START, START + 1_000, 10, 500, 1_000);
+        s.created_at = START - 100;
+        s.withdrawn_amount = 50;
+        s.sender = Pubkey::new_unique();
+        s.sender_tokens = Pubkey::new_unique();
+        s.recipient = Pubkey::new_unique();
+        s.recipient_tokens = Pubkey::new_unique();
+        s.mint = Pubkey::new_unique();
+        s.escrow_tokens = Pubkey::new_unique();
+        let bytes = v1_bytes(&s);
+
+        let mut decoded = TokenStreamData::load(&bytes).unwrap();
+        assert_eq!(decoded.magic, 1);
+        assert_eq!(decoded.ix.start_time, s.ix.start_time);
+        assert_eq!(decoded.ix.end_time, s.ix.end_time);
+        assert_eq!(decoded.ix.period, 10);
+        assert_eq!(decoded.created_at, s.created_at);
+        assert_eq!(decoded.withdrawn_amount, 50);
+        assert_eq!(decoded.recipient_tokens, s.recipient_tokens);
+        assert_eq!(decoded.escrow_tokens, s.escrow_tokens);
+        assert!(decoded.ix.cancelable_by_sender);
+        assert!(!decoded.ix.withdrawal_public);
+        // Half funded, runs out halfway
+        assert_eq!(decoded.closable_at, START + 500);
+        assert_eq!(decoded.available(START + 100), Ok(50));
+
+        // Can't be written in the old layout
+        let mut data = bytes;
+        assert_eq!(
+            decoded.save(&mut data),
+            Err(StreamFlowError::MigrationRequired.into())
+        );
+
+        // Migrated into a grown account
+        decoded.magic = PROGRAM_VERSION;
+        let space = decoded.space();
+        assert!(space > V1_METADATA_SIZE);
+        assert_eq!(space % 8, 0);
+        let mut data = vec![0; space];
+        assert_eq!(decoded.save(&mut data), Ok(()));
+        let migrated = TokenStreamData::load(&data).unwrap();
+        assert_eq!(migrated.magic, PROGRAM_VERSION);
+        assert_eq!(migrated.closable_at, START + 500);
+        assert_eq!(migrated.ix, decoded.ix);
+        assert_eq!(migrated.mint, s.mint);
+
+        let mut bytes = v1_bytes(&s);
+        bytes[0] = 7;
+        assert_eq!(
+            TokenStreamData::load(&bytes).unwrap_err(),
+            StreamFlowError::InvalidMetadata.into()
+        );
+        assert_eq!(
+            TokenStreamData::load(&v1_bytes(&s)[..100]).unwrap_err(),
+            StreamFlowError::InvalidMetadata.into()
+        );
+        assert_eq!(
+            TokenStreamData::load(&[1, 0]).unwrap_err(),
+            StreamFlowError::InvalidMetadata.into()
+        );
+    }
+
+    #[test]
+    fn test_fixed_layout() {
+        let mut s = stream(START, START + 100, 10, 1_000, 1_000);
+        s.withdrawn_amount = 300;
+        s.sender = Pubkey::new_unique();
+        s.recipient = Pubkey::new_unique();
+        s.mint = Pubkey::new_unique();
+        s.escrow_tokens_bump = 254;
+        s.ix.withdrawal_public = true;
+        s.ix.pausable_by_sender = true;
+        s.ix.requires_acceptance = true;
+        s.ix.acceptance_deadline = START;
+        s.accepted_at = START - 10;
+        s.destination_tokens = Pubkey::new_unique();
+        s.ix.keeper_bounty = 5;
+        s.ix.keeper_bounty_in_tokens = true;
+        s.ix.keeper_bounty_pool = 50;
+        s.ix.crank_interval = 3_600;
+        s.keeper_bounty_left = 45;
+        s.transfer_fee_amount = 7;
+        s.ix.vesting_curve = VestingCurve::Balloon {
+            balloon_amount: 500,
+        };
+        s.ix.stream_name = "Vesting".to_string();
+
+        let mut data = vec![0; s.space()];
+        assert_eq!(data.len(), HEADER_SIZE);
+        s.save(&mut data).unwrap();
+        assert_eq!(&data[..8], &PROGRAM_VERSION.to_le_bytes());
+        assert_eq!(&data[SENDER_OFFSET..SENDER_OFFSET + 32], s.sender.as_ref());
+        assert_eq!(
+            &data[RECIPIENT_OFFSET..RECIPIENT_OFFSET + 32],
+            s.recipient.as_ref()
+        );
+        assert_eq!(&data[MINT_OFFSET..MINT_OFFSET + 32], s.mint.as_ref());
+        assert_eq!(
+            &data[WITHDRAWN_AMOUNT_OFFSET..WITHDRAWN_AMOUNT_OFFSET + 8],
+            &300u64.to_le_bytes()
+        );
+        assert_eq!(data[WITHDRAWAL_PUBLIC_OFFSET], 1);
+        assert_eq!(data[VESTING_CURVE_OFFSET], 4);
+        assert_eq!(
+            &data[STREAM_NAME_OFFSET..STREAM_NAME_OFFSET + 8],
+            b"Vesting\0"
+        );
+
+        let decoded = TokenStreamData::load(&data).unwrap();
+        assert_eq!(decoded.ix, s.ix);
+        assert_eq!(decoded.withdrawn_amount, 300);
+        assert_eq!(decoded.escrow_tokens_bump, 254);
+        assert_eq!(decoded.accepted_at, START - 10);
+        assert_eq!(decoded.status, StreamStatus::Active);
+        assert_eq!(decoded.withdraw_destination(), s.destination_tokens);
+        assert_eq!(data[REQUIRES_ACCEPTANCE_OFFSET], 1);
+        assert_eq!(data[KEEPER_BOUNTY_IN_TOKENS_OFFSET], 1);
+        assert_eq!(decoded.keeper_bounty_left, 45);
+        assert_eq!(decoded.transfer_fee_amount, 7);
+
+        // Read in place
+        let header = StreamMetadata::from_account_data(&data).unwrap();
+        assert_eq!(header.recipient, s.recipient);
+        assert_eq!(header.curve_param, 500);
+
+        // Tranches follow the header
+        s.ix.vesting_curve = VestingCurve::Tranches(vec![
+            Tranche {
+                timestamp: START,
+                amount: 400,
+            },
+            Tranche {
+                timestamp: START + 100,
+                amount: 600,
+            },
+        ]);
+        assert_eq!(s.save(&mut data), Err(ProgramError::AccountDataTooSmall));
+        let mut data = vec![0; s.space()];
+        assert_eq!(data.len(), HEADER_SIZE + 2 * TRANCHE_SIZE);
+        s.save(&mut data).unwrap();
+        assert_eq!(TokenStreamData::load(&data).unwrap().ix, s.ix);
+        assert_eq!(
+            TokenStreamData::load(&data[..HEADER_SIZE + TRANCHE_SIZE]).unwrap_err(),
+            ProgramError::InvalidAccountData
+        );
+
+        // Names fill the whole field, longer ones are refused
+        s.ix.stream_name = "ž".repeat(STREAM_NAME_SIZE / 2);
+        s.save(&mut data).unwrap();
+        assert_eq!(
+            TokenStreamData::load(&data).unwrap().ix.stream_name,
+            s.ix.stream_name
+        );
+        s.ix.stream_name = format!("a{}", "ž".repeat(STREAM_NAME_SIZE / 2));
+        assert_eq!(
+            s.save(&mut data),
+            Err(StreamFlowError::StreamNameTooLong.into())
+        );
+
+        data[VESTING_CURVE_OFFSET] = 6;
+        assert_eq!(
+            TokenStreamData::load(&data).unwrap_err(),
+            StreamFlowError::InvalidMetadata.into()
+        );
+    }
+
+    #[test]
+    fn test_escrow_address() {
+        let program_id = Pubkey::new_unique();
+        let metadata = Pubkey::new_unique();
+        let expected = Pubkey::find_program_address(&[metadata.as_ref()], &program_id);
+
+        // Legacy streams without a stored bump
+        let mut s = stream(START, START + 100, 10, 1_000, 1_000);
+        assert_eq!(s.escrow_address(&program_id, &metadata), Ok(expected));
+
+        s.escrow_tokens_bump = expected.1;
+        assert_eq!(s.escrow_address(&program_id, &metadata), Ok(expected));
+        assert_ne!(
+            s.escrow_address(&program_id, &Pubkey::new_unique())
+                .map(|(escrow_tokens, _)| escrow_tokens),
+            Ok(expected.0)
+        );
+    }
+
+    #[test]
+    fn test_pause() {
+        let mut s = stream(START, START + 100, 1, 100, 100);
+        s.ix.pausable_by_sender = true;
+        assert_eq!(s.available(START + 20), Ok(20));
+
+        s.paused_at = START + 20;
+        assert_eq!(s.available(START + 20), Ok(20));
+        assert_eq!(s.available(START + 80), Ok(20));
+        assert_eq!(s.available(START + 1_000), Ok(20));
+        assert_eq!(s.closable(), Ok(u64::MAX));
+
+        // Resumed after 60 seconds
+        s.paused_at = 0;
+        s.paused_duration = 60;
+        assert_eq!(s.available(START + 80), Ok(20));
+        assert_eq!(s.available(START + 100), Ok(40));
+        assert_eq!(s.available(START + 159), Ok(99));
+        assert_eq!(s.available(START + 160), Ok(100));
+        assert_eq!(s.closable(), Ok(START + 160));
+
+        // Paused again before start
+        s.paused_duration = 0;
+        s.paused_at = START - 10;
+        assert_eq!(s.available(START + 10), Ok(0));
+        s.paused_at = 0;
+        s.paused_duration = 30;
+        assert_eq!(s.available(START + 10), Ok(0));
+        assert_eq!(s.available(START + 40), Ok(10));
+
+        // Funds ran out before the pause
+        let mut s = stream(START, START + 100, 1, 50, 100);
+        s.paused_at = START + 60;
+        s.paused_duration = 5;
+        assert_eq!(s.closable(), Ok(START + 55));
+    }
+
+    #[test]
+    fn test_acceptance() {
+        let mut s = stream(START, START + 100, 1, 100, 100);
+        s.ix.requires_acceptance = true;
+        s.status = StreamStatus::Pending;
+        assert!(s.pending());
+        assert_eq!(s.available(START + 50), Ok(0));
+        assert_eq!(s.closable(), Ok(u64::MAX));
+        s.ix.acceptance_deadline = START + 30;
+        assert_eq!(s.closable(), Ok(START + 30));
+
+        // Accepted before the start, the schedule is kept
+        let mut early = stream(START, START + 100, 1, 100, 100);
+        early.ix.requires_acceptance = true;
+        early.status = StreamStatus::Pending;
+        early.accept(START - 10).unwrap();
+        assert!(!early.pending());
+        assert_eq!(early.available(START + 50), Ok(50));
+        assert_eq!(early.closable_at, START + 100);
+
+        // Accepted late, vesting starts at the acceptance
+        s.accept(START + 20).unwrap();
+        assert!(!s.pending());
+        assert_eq!(s.available(START + 20), Ok(0));
+        assert_eq!(s.available(START + 70), Ok(50));
+        assert_eq!(s.closable_at, START + 120);
+    }
+
+    #[test]
+    fn test_crank_bounty() {
+        let mut s = stream(START, START + 100, 1, 100, 100);
+        assert_eq!(s.crank_bounty(START + 50), 0);
+
+        s.ix.keeper_bounty = 5;
+        s.ix.crank_interval = 10;
+        s.keeper_bounty_left = 12;
+        s.last_withdrawn_at = START + 20;
+        assert_eq!(s.crank_bounty(START + 29), 0);
+        assert_eq!(s.crank_bounty(START + 30), 5);
+
+        // The last bounty gets whatever is left in the pool
+        s.keeper_bounty_left = 2;
+        assert_eq!(s.crank_bounty(START + 30), 2);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut s = stream(START, START + 100, 1, 100, 100);
+        s.closable_at = START + 100;
+        assert_eq!(s.status_at(START - 1), StreamStatus::Scheduled);
+        assert_eq!(s.status_at(START), StreamStatus::Active);
+        assert_eq!(s.status_at(START + 200), StreamStatus::Active);
+
+        assert_eq!(s.transition(StreamStatus::Paused), Ok(()));
+        assert_eq!(s.status_at(START), StreamStatus::Paused);
+        assert_eq!(
+            s.transition(StreamStatus::Paused),
+            Err(InvalidStatusTransition)
+        );
+        assert_eq!(s.transition(StreamStatus::Active), Ok(()));
+        assert_eq!(
+            s.transition(StreamStatus::Pending),
+            Err(InvalidStatusTransition)
+        );
+        assert_eq!(
+            s.transition(StreamStatus::Underfunded),
+            Err(InvalidStatusTransition)
+        );
+
+        // Closed streams stay closed
+        assert_eq!(s.to_layout().ensure_open(), Ok(()));
+        assert_eq!(s.transition(StreamStatus::Canceled), Ok(()));
+        assert_eq!(s.to_layout().ensure_open(), Err(StreamClosed));
+        for to in [StreamStatus::Canceled, StreamStatus::Active] {
+            assert_eq!(s.transition(to), Err(StreamClosed));
+        }
+        s.status = StreamStatus::Completed;
+        assert_eq!(s.transition(StreamStatus::Completed), Err(StreamClosed));
+
+        // Scheduled and underfunded are never stored
+        for status in [2, 5, 8] {
+            assert_eq!(
+                StreamStatus::from_layout(status),
+                Err(InvalidMetadata.into())
+            );
+        }
+
+        // Funds running out
+        let mut s = stream(START, START + 100, 1, 50, 100);
+        s.closable_at = s.closable().unwrap();
+        assert_eq!(s.status_at(START + 49), StreamStatus::Active);
+        assert_eq!(s.status_at(START + 50), StreamStatus::Underfunded);
+
+        // Pending streams can only be accepted or canceled
+        s.ix.requires_acceptance = true;
+        s.status = StreamStatus::Pending;
+        assert_eq!(s.status_at(START + 50), StreamStatus::Pending);
+        assert_eq!(
+            s.transition(StreamStatus::Paused),
+            Err(InvalidStatusTransition)
+        );
+        s.accept(START).unwrap();
+        assert_eq!(s.status, StreamStatus::Active);
+    }
+
+    #[test]
+    fn test_legacy_status() {
+        let mut s = stream(START, START + 100, 1, 100, 100);
+        s.status = StreamStatus::Paused;
+        let mut data = vec![0; s.space()];
+        s.save(&mut data).unwrap();
+        assert_eq!(data[STATUS_OFFSET], 4);
+        assert_eq!(
+            TokenStreamData::load(&data).unwrap().status,
+            StreamStatus::Paused
+        );
+
+        // Written before the status was stored
+        data[STATUS_OFFSET] = 0;
+        assert_eq!(
+            TokenStreamData::load(&data).unwrap().status,
+            StreamStatus::Active
+        );
+        data[WITHDRAWN_AMOUNT_OFFSET..WITHDRAWN_AMOUNT_OFFSET + 8]
+            .copy_from_slice(&100u64.to_le_bytes());
+        assert_eq!(
+            TokenStreamData::load(&data).unwrap().status,
+            StreamStatus::Completed
+        );
+        data[CANCELED_AT_OFFSET..CANCELED_AT_OFFSET + 8].copy_from_slice(&START.to_le_bytes());
+        assert_eq!(
+            TokenStreamData::load(&data).unwrap().status,
+            StreamStatus::Canceled
+        );
+
+        data[STATUS_OFFSET] = 8;
+        assert_eq!(
+            TokenStreamData::load(&data).unwrap_err(),
+            StreamFlowError::InvalidMetadata.into()
+        );
+    }
+
+    #[test]
+    fn test_tranches() {
+        // 10% at TGE, 15% at month 3, then 5% quarterly
+        let quarter = 91 * 24 * 60 * 60;
+        let mut tranches = vec![
+            Tranche {
+                timestamp: START,
+                amount: 100,
+            },
+            Tranche {
+                timestamp: START + quarter,
+                amount: 150,
+            },
+        ];
+        tranches.extend((2..17).map(|q| Tranche {
+            timestamp: START + q * quarter,
+            amount: 50,
+        }));
+
+        let mut s = stream(START, START + 16 * quarter, 0, 1_000, 1_000);
+        s.ix.vesting_curve = VestingCurve::Tranches(tranches.clone());
+        assert_eq!(s.validate_curve(), Ok(()));
+        assert_eq!(s.available(START - 1), Ok(0));
+        assert_eq!(s.available(START), Ok(100));
+        assert_eq!(s.available(START + quarter - 1), Ok(100));
+        assert_eq!(s.available(START + quarter), Ok(250));
+        assert_eq!(s.available(START + 4 * quarter + 1), Ok(400));
+        assert_eq!(s.available(START + 16 * quarter), Ok(1_000));
+        assert_eq!(s.closable(), Ok(START + 16 * quarter));
+
+        s.ix.deposited_amount = 300;
+        assert_eq!(s.available(START + 4 * quarter), Ok(300));
+        assert_eq!(s.closable(), Ok(START + 2 * quarter));
+
+        let bytes = s.try_to_vec().unwrap();
+        let decoded: TokenStreamData =
+            solana_program::borsh::try_from_slice_unchecked(&bytes).unwrap();
+        assert_eq!(decoded.ix.vesting_curve, s.ix.vesting_curve);
+    }
+
+    #[test]
+    fn test_validate_tranches() {
+        let tranche = |timestamp, amount| Tranche { timestamp, amount };
+        let mut s = stream(START, START + 100, 1, 100, 100);
+
+        s.ix.vesting_curve = VestingCurve::Tranches(vec![]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+
+        s.ix.vesting_curve = VestingCurve::Tranches(vec![tranche(START + 1, 1); MAX_TRANCHES + 1]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+
+        // Not sorted
+        s.ix.vesting_curve =
+            VestingCurve::Tranches(vec![tranche(START + 50, 50), tranche(START + 10, 50)]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+        s.ix.vesting_curve =
+            VestingCurve::Tranches(vec![tranche(START + 10, 50), tranche(START + 10, 50)]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+
+        // Outside of the stream
+        s.ix.vesting_curve =
+            VestingCurve::Tranches(vec![tranche(START - 1, 50), tranche(START + 10, 50)]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+        s.ix.vesting_curve =
+            VestingCurve::Tranches(vec![tranche(START, 50), tranche(START + 101, 50)]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+
+        // Doesn't sum up to total amount
+        s.ix.vesting_curve =
+            VestingCurve::Tranches(vec![tranche(START, 50), tranche(START + 100, 49)]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+        s.ix.vesting_curve =
+            VestingCurve::Tranches(vec![tranche(START, u64::MAX), tranche(START + 100, 101)]);
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+
+        s.ix.vesting_curve =
+            VestingCurve::Tranches(vec![tranche(START, 50), tranche(START + 100, 50)]);
+        assert_eq!(s.validate_curve(), Ok(()));
+
+        s.ix.cliff_amount = 10;
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+        s.ix.cliff_amount = 0;
+        s.ix.release_rate = 10;
+        assert_eq!(s.validate_curve(), Err(InvalidVestingCurve));
+    }
 }
@@ -15,29 +15,53 @@
 //
 // You should have received a copy of the GNU Affero General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
-use borsh::BorshSerialize;
 use solana_program::{
-    borsh as solana_borsh,
+    account_info::AccountInfo,
     entrypoint::ProgramResult,
     msg,
     program::{invoke, invoke_signed},
     program_error::ProgramError,
-    program_pack::Pack,
     pubkey::Pubkey,
     system_instruction, system_program, sysvar,
     sysvar::{clock::Clock, fees::Fees, rent::Rent, Sysvar},
 };
-use spl_associated_token_account::{create_associated_token_account, get_associated_token_address};
+use spl_token::native_mint;
 
+use crate::config::{
+    fee_amount, ConfigParams, ProgramConfig, CONFIG_SEED, CONFIG_SIZE, CONFIG_VERSION, PAUSE_ALL,
+};
 use crate::error::StreamFlowError::{
-    AccountsNotWritable, InvalidMetadata, MintMismatch, TransferNotAllowed, StreamClosed
+    AcceptanceExpired, AccountsNotWritable, AmountExceedsAvailable, CancelNotAllowed,
+    ConfigUpdateNotAllowed, InsufficientTokens, InvalidConfig, InvalidConfigAccount,
+    InvalidEscrowAccount, InvalidKeeperBounty, InvalidMintRegistry, InvalidProgramAccount,
+    InvalidTimestamps, InvalidTokenAccount, MetadataAccountMismatch, MintMismatch, PauseNotAllowed,
+    RecipientCancelNotAllowed, SenderCancelNotAllowed, StreamClosed, StreamNameTooLong,
+    StreamNotPaused, StreamNotPending, StreamPending, TransferNotAllowed, WithdrawNotAllowed,
+    ZeroAmount,
+};
+use crate::event::StreamEvent;
+use crate::instruction::{
+    find_config_address, find_metadata_address, find_mint_registry_address, find_tokens_address,
+    METADATA_SEED,
+};
+use crate::layout::StreamMetadata;
+use crate::mint_registry::{
+    check_mint, registry_len, registry_mints, write_registry_header, MINT_REGISTRY_SEED,
 };
 use crate::state::{
-    CancelAccounts, InitializeAccounts, StreamInstruction, TokenStreamData, TopUpAccounts,
-    TransferAccounts, WithdrawAccounts,
+    AcceptAccounts, CancelAccounts, InitConfigAccounts, InitializeAccounts, MigrateAccounts,
+    MintRegistryAccounts, PauseAccounts, SetDestinationAccounts, SetPauseAccounts, StreamAccount,
+    StreamInstruction, StreamStatus, TokenStreamData, TopUpAccounts, TransferAccounts,
+    UpdateConfigAccounts, WithdrawAccounts, PROGRAM_VERSION,
+};
+use crate::token_2022::{
+    self, close_account, create_associated_token_account, escrow_account_len,
+    harvest_withheld_tokens_to_mint, initialize_account, is_token_program, transfer_checked,
+    transfer_fee, withheld_amount,
 };
 use crate::utils::{
-    duration_sanity, encode_base10, pretty_time, unpack_mint_account, unpack_token_account,
+    create_pda_account, duration_sanity, encode_base10, mint_decimals, pretty_time, realloc,
+    transfer_lamports, unpack_token_account, upgrade_authority,
 };
 
 /// Initialize an SPL token stream
@@ -47,16 +71,45 @@ use crate::utils::{
 /// rent-exempt if necessary. When the stream is finished, these
 /// shall be returned to the stream initializer.
 
-const MAX_STRING_SIZE: usize = 200;
-
 pub fn create(
     program_id: &Pubkey,
+    config: &ProgramConfig,
+    acc: InitializeAccounts,
+    ix: StreamInstruction,
+) -> ProgramResult {
+    initialize(program_id, config, acc, ix, None)
+}
+
+/// Initialize an SPL token stream whose metadata account is a PDA
+///
+/// Same as `create`, but instead of a fresh signer keypair the metadata
+/// account is derived from the sender, recipient, mint and given `seed`,
+/// so it can be found again without storing any keypair.
+pub fn create_with_seed(
+    program_id: &Pubkey,
+    config: &ProgramConfig,
+    acc: InitializeAccounts,
+    ix: StreamInstruction,
+    seed: u64,
+) -> ProgramResult {
+    initialize(program_id, config, acc, ix, Some(seed))
+}
+
+fn initialize(
+    program_id: &Pubkey,
+    config: &ProgramConfig,
     acc: InitializeAccounts,
     ix: StreamInstruction,
+    seed: Option<u64>,
 ) -> ProgramResult {
     msg!("Initializing SPL token stream");
 
-    if !acc.escrow_tokens.data_is_empty() || !acc.metadata.data_is_empty() {
+    config.check_token_program(acc.token_program.key)?;
+    check_mint(program_id, config, &acc.mint_registry, acc.mint.key)?;
+
+    let native = native_mint::check_id(acc.mint.key);
+
+    if escrow_initialized(program_id, &acc.escrow_tokens, native) || !acc.metadata.data_is_empty() {
         return Err(ProgramError::AccountAlreadyInitialized);
     }
 
@@ -66,46 +119,143 @@ pub fn create(
         || !acc.recipient_tokens.is_writable
         || !acc.metadata.is_writable
         || !acc.escrow_tokens.is_writable
+        || !acc.fee_treasury_tokens.is_writable
+        || acc.partner_tokens.as_ref().map_or(false, |a| !a.is_writable)
     {
         return Err(AccountsNotWritable.into());
     }
 
     let (escrow_tokens_pubkey, nonce) =
         Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
-    let recipient_tokens_key = get_associated_token_address(acc.recipient.key, acc.mint.key);
+    let recipient_tokens_key =
+        find_tokens_address(acc.recipient.key, acc.mint.key, acc.token_program.key);
 
     if acc.system_program.key != &system_program::id()
-        || acc.token_program.key != &spl_token::id()
+        || !token_program_valid(&acc.token_program, &acc.mint)
         || acc.rent.key != &sysvar::rent::id()
-        || acc.escrow_tokens.key != &escrow_tokens_pubkey
-        || acc.recipient_tokens.key != &recipient_tokens_key
     {
-        return Err(ProgramError::InvalidAccountData);
+        return Err(InvalidProgramAccount.into());
+    }
+
+    if acc.escrow_tokens.key != &escrow_tokens_pubkey {
+        return Err(InvalidEscrowAccount.into());
+    }
+
+    if acc.recipient_tokens.key != &recipient_tokens_key
+        || (native && acc.sender_tokens.key != acc.sender.key)
+        || acc.fee_treasury_tokens.key
+            != &find_tokens_address(&config.fee_treasury, acc.mint.key, acc.token_program.key)
+    {
+        return Err(InvalidTokenAccount.into());
     }
 
-    if !acc.sender.is_signer || !acc.metadata.is_signer {
+    let seed_bytes = seed.map(u64::to_le_bytes);
+    let metadata_nonce = match seed {
+        Some(seed) => {
+            let (metadata_pubkey, metadata_nonce) = find_metadata_address(
+                program_id,
+                acc.sender.key,
+                acc.recipient.key,
+                acc.mint.key,
+                seed,
+            );
+            if acc.metadata.key != &metadata_pubkey {
+                return Err(ProgramError::InvalidSeeds);
+            }
+            Some(metadata_nonce)
+        }
+        None => None,
+    };
+
+    if !acc.sender.is_signer || (seed.is_none() && !acc.metadata.is_signer) {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let sender_token_info = unpack_token_account(&acc.sender_tokens)?;
-    let mint_info = unpack_mint_account(&acc.mint)?;
+    // Native SOL streams are funded straight from the sender's lamports
+    let sender_token_info = if native {
+        None
+    } else {
+        Some(unpack_token_account(&acc.sender_tokens)?)
+    };
+    let decimals = mint_decimals(&acc.mint)?;
+
+    if let Some(sender_token_info) = &sender_token_info {
+        if &sender_token_info.mint != acc.mint.key {
+            // Mint mismatch
+            return Err(MintMismatch.into());
+        }
+    }
 
-    if &sender_token_info.mint != acc.mint.key {
-        // Mint mismatch
-        return Err(MintMismatch.into());
+    // Any wallet can be the partner of a native SOL stream
+    if let (Some(partner_tokens), false) = (&acc.partner_tokens, native) {
+        if &unpack_token_account(partner_tokens)?.mint != acc.mint.key {
+            return Err(MintMismatch.into());
+        }
     }
 
-    let now = Clock::get()?.unix_timestamp as u64;
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp as u64;
     if !duration_sanity(now, ix.start_time, ix.end_time, ix.cliff) {
         msg!("Error: Given timestamps are invalid");
-        return Err(ProgramError::InvalidArgument);
+        return Err(InvalidTimestamps.into());
     }
 
-    if ix.stream_name.len() > MAX_STRING_SIZE {
+    if ix.stream_name.len() > config.max_name_size as usize {
         msg!("Error: Stream name too long!");
-        return Err(ProgramError::InvalidArgument);
+        return Err(StreamNameTooLong.into());
+    }
+
+    if ix.requires_acceptance && ix.acceptance_deadline > 0 && ix.acceptance_deadline < now {
+        msg!("Error: Acceptance deadline has already passed");
+        return Err(InvalidTimestamps.into());
     }
 
+    // Keepers can only crank public streams
+    let has_bounty = ix.keeper_bounty > 0 || ix.keeper_bounty_pool > 0;
+    if has_bounty
+        && (ix.keeper_bounty == 0
+            || ix.crank_interval == 0
+            || !ix.withdrawal_public
+            || (native && ix.keeper_bounty_in_tokens))
+    {
+        msg!("Error: Keeper bounties need public withdrawals, a crank interval and lamports for native SOL");
+        return Err(InvalidKeeperBounty.into());
+    }
+    // The lamport pool is held by the metadata account, the token pool by the escrow
+    let (lamport_pool, token_pool) = if ix.keeper_bounty_in_tokens {
+        (0, ix.keeper_bounty_pool)
+    } else {
+        (ix.keeper_bounty_pool, 0)
+    };
+
+    // Pending streams leave the recipient's token account to `accept`
+    let create_recipient_tokens =
+        !native && !ix.requires_acceptance && acc.recipient_tokens.data_is_empty();
+
+    // Protocol and partner fees come out of the deposit, the partner fee
+    // only when a partner is given
+    let protocol_fee_basis_points = config.fee_basis_points;
+    let partner_fee_basis_points = match acc.partner_tokens {
+        Some(_) => config.partner_fee_basis_points,
+        None => 0,
+    };
+    let protocol_fee = fee_amount(ix.deposited_amount, protocol_fee_basis_points);
+    let partner_fee = fee_amount(ix.deposited_amount, partner_fee_basis_points);
+    let escrowed = ix.deposited_amount - protocol_fee - partner_fee;
+
+    // Transfer fees are withheld on the way into escrow, the stream only
+    // holds what's left after them
+    let deposit_fee = if native {
+        0
+    } else {
+        transfer_fee(&acc.mint.data.borrow(), clock.epoch, escrowed)?
+    };
+    let pool_fee = if token_pool > 0 {
+        transfer_fee(&acc.mint.data.borrow(), clock.epoch, token_pool)?
+    } else {
+        0
+    };
+
     // TODO: Calculate cancel_data once continuous streams are ready
     let mut metadata = TokenStreamData::new(
         now,
@@ -115,9 +265,10 @@ pub fn create(
         *acc.recipient_tokens.key,
         *acc.mint.key,
         *acc.escrow_tokens.key,
+        nonce,
         ix.start_time,
         ix.end_time,
-        ix.deposited_amount,
+        escrowed - deposit_fee,
         ix.total_amount,
         ix.period,
         ix.cliff,
@@ -129,48 +280,84 @@ pub fn create(
         ix.transferable_by_recipient,
         ix.release_rate,
         ix.stream_name,
+        ix.vesting_curve,
+        ix.pausable_by_sender,
+        ix.requires_acceptance,
+        ix.acceptance_deadline,
+        ix.keeper_bounty,
+        ix.keeper_bounty_in_tokens,
+        ix.keeper_bounty_pool,
+        ix.crank_interval,
     );
+    metadata.keeper_bounty_left -= pool_fee;
+    metadata.partner_tokens = acc
+        .partner_tokens
+        .as_ref()
+        .map_or_else(Pubkey::default, |a| *a.key);
+    metadata.protocol_fee_basis_points = protocol_fee_basis_points;
+    metadata.partner_fee_basis_points = partner_fee_basis_points;
+    metadata.protocol_fee_amount = protocol_fee;
+    metadata.partner_fee_amount = partner_fee;
+    metadata.transfer_fee_amount = deposit_fee;
+
+    if let Err(e) = metadata.validate_curve() {
+        msg!(
+            "Error: Vesting curve {:?} is invalid",
+            metadata.ix.vesting_curve
+        );
+        return Err(e.into());
+    }
 
     // Move closable_at (from third party), when reccuring ignore end_date
-    if ix.deposited_amount < ix.total_amount || ix.release_rate > 0 {
-        metadata.closable_at = metadata.closable();
+    if metadata.gross_deposited() < ix.total_amount || ix.release_rate > 0 || metadata.pending() {
+        metadata.closable_at = metadata.closable()?;
         msg!("Closable at: {}", metadata.closable_at);
     }
 
     // We also transfer enough to be rent-exempt on the metadata account.
-    let metadata_bytes = metadata.try_to_vec()?;
-    // We pad % 8 for size , since that's what has to be allocated.
-    let mut metadata_struct_size = metadata_bytes.len();
-    while metadata_struct_size % 8 > 0 {
-        metadata_struct_size += 1;
-    }
-    let tokens_struct_size = spl_token::state::Account::LEN;
+    let metadata_struct_size = metadata.space();
+    // A native SOL escrow holds no data, only the rent and the deposit
+    let tokens_struct_size = if native {
+        0
+    } else {
+        escrow_account_len(&acc.mint.data.borrow())?
+    };
+    let escrow_struct_size = tokens_struct_size;
 
     let cluster_rent = Rent::get()?;
     let metadata_rent = cluster_rent.minimum_balance(metadata_struct_size);
-    let mut tokens_rent = cluster_rent.minimum_balance(tokens_struct_size);
-    if acc.recipient_tokens.data_is_empty() {
+    let metadata_lamports = metadata_rent + lamport_pool;
+    let escrow_rent = cluster_rent.minimum_balance(escrow_struct_size);
+    let mut tokens_rent = escrow_rent;
+    if create_recipient_tokens {
         tokens_rent += cluster_rent.minimum_balance(tokens_struct_size);
     }
+    let lamports_deposit = if native { ix.deposited_amount } else { 0 };
 
     let fees = Fees::get()?;
     let lps = fees.fee_calculator.lamports_per_signature;
 
-    // TODO: Check if wrapped SOL
-    if acc.sender.lamports() < metadata_rent + tokens_rent + (2 * lps) {
+    if acc.sender.lamports() < metadata_lamports + tokens_rent + lamports_deposit + (2 * lps) {
         msg!("Error: Insufficient funds in {}", acc.sender.key);
         return Err(ProgramError::InsufficientFunds);
     }
 
-    if sender_token_info.amount < ix.deposited_amount {
-        msg!("Error: Insufficient tokens in sender's wallet");
-        return Err(ProgramError::InsufficientFunds);
+    if let Some(sender_token_info) = &sender_token_info {
+        if sender_token_info.amount < ix.deposited_amount + token_pool {
+            msg!("Error: Insufficient tokens in sender's wallet");
+            return Err(InsufficientTokens.into());
+        }
     }
 
-    if acc.recipient_tokens.data_is_empty() {
+    if create_recipient_tokens {
         msg!("Initializing recipient's associated token account");
         invoke(
-            &create_associated_token_account(acc.sender.key, acc.recipient.key, acc.mint.key),
+            &create_associated_token_account(
+                acc.sender.key,
+                acc.recipient.key,
+                acc.mint.key,
+                acc.token_program.key,
+            ),
             &[
                 acc.sender.clone(),
                 acc.recipient_tokens.clone(),
@@ -184,81 +371,158 @@ pub fn create(
     }
 
     msg!("Creating account for holding metadata");
-    invoke(
-        &system_instruction::create_account(
-            acc.sender.key,
-            acc.metadata.key,
-            metadata_rent,
-            metadata_struct_size as u64,
-            program_id,
-        ),
-        &[
-            acc.sender.clone(),
-            acc.metadata.clone(),
-            acc.system_program.clone(),
-        ],
-    )?;
+    match (seed_bytes, metadata_nonce) {
+        (Some(seed_bytes), Some(metadata_nonce)) => {
+            let metadata_seeds = [
+                METADATA_SEED,
+                acc.sender.key.as_ref(),
+                acc.recipient.key.as_ref(),
+                acc.mint.key.as_ref(),
+                &seed_bytes,
+                &[metadata_nonce],
+            ];
+            create_pda_account(
+                &acc.sender,
+                &acc.metadata,
+                &acc.system_program,
+                metadata_lamports,
+                metadata_struct_size,
+                program_id,
+                &metadata_seeds,
+            )?;
+        }
+        _ => invoke(
+            &system_instruction::create_account(
+                acc.sender.key,
+                acc.metadata.key,
+                metadata_lamports,
+                metadata_struct_size as u64,
+                program_id,
+            ),
+            &[
+                acc.sender.clone(),
+                acc.metadata.clone(),
+                acc.system_program.clone(),
+            ],
+        )?,
+    }
 
     // Write the metadata to the account
     let mut data = acc.metadata.try_borrow_mut_data()?;
-    data[0..metadata_bytes.len()].clone_from_slice(&metadata_bytes);
+    metadata.save(&mut data)?;
 
     let seeds = [acc.metadata.key.as_ref(), &[nonce]];
-    msg!("Creating account for holding tokens");
-    invoke_signed(
-        &system_instruction::create_account(
-            acc.sender.key,
-            acc.escrow_tokens.key,
-            cluster_rent.minimum_balance(tokens_struct_size),
-            tokens_struct_size as u64,
-            &spl_token::id(),
-        ),
-        &[
-            acc.sender.clone(),
-            acc.escrow_tokens.clone(),
-            acc.system_program.clone(),
-        ],
-        &[&seeds],
-    )?;
-
-    msg!("Initializing escrow account for {} token", acc.mint.key);
-    invoke(
-        &spl_token::instruction::initialize_account(
+    if native {
+        msg!("Moving lamports into escrow account");
+        create_pda_account(
+            &acc.sender,
+            &acc.escrow_tokens,
+            &acc.system_program,
+            escrow_rent + metadata.ix.deposited_amount,
+            escrow_struct_size,
+            program_id,
+            &seeds,
+        )?;
+    } else {
+        msg!("Creating account for holding tokens");
+        create_pda_account(
+            &acc.sender,
+            &acc.escrow_tokens,
+            &acc.system_program,
+            escrow_rent,
+            escrow_struct_size,
             acc.token_program.key,
-            acc.escrow_tokens.key,
-            acc.mint.key,
-            acc.escrow_tokens.key,
-        )?,
-        &[
-            acc.token_program.clone(),
-            acc.escrow_tokens.clone(),
-            acc.mint.clone(),
-            acc.escrow_tokens.clone(),
-            acc.rent.clone(),
-        ],
-    )?;
+            &seeds,
+        )?;
 
-    msg!("Moving funds into escrow account");
-    invoke(
-        &spl_token::instruction::transfer(
-            acc.token_program.key,
-            acc.sender_tokens.key,
-            acc.escrow_tokens.key,
-            acc.sender.key,
-            &[],
-            metadata.ix.deposited_amount,
-        )?,
-        &[
-            acc.sender_tokens.clone(),
-            acc.escrow_tokens.clone(),
-            acc.sender.clone(),
-            acc.token_program.clone(),
-        ],
+        msg!("Initializing escrow account for {} token", acc.mint.key);
+        invoke(
+            &initialize_account(
+                acc.token_program.key,
+                acc.escrow_tokens.key,
+                acc.mint.key,
+                acc.escrow_tokens.key,
+            ),
+            &[
+                acc.token_program.clone(),
+                acc.escrow_tokens.clone(),
+                acc.mint.clone(),
+                acc.escrow_tokens.clone(),
+                acc.rent.clone(),
+            ],
+        )?;
+
+        msg!("Moving funds into escrow account");
+        invoke(
+            &transfer_checked(
+                acc.token_program.key,
+                acc.sender_tokens.key,
+                acc.mint.key,
+                acc.escrow_tokens.key,
+                acc.sender.key,
+                escrowed,
+                decimals,
+            ),
+            &[
+                acc.sender_tokens.clone(),
+                acc.mint.clone(),
+                acc.escrow_tokens.clone(),
+                acc.sender.clone(),
+                acc.token_program.clone(),
+            ],
+        )?;
+
+        if token_pool > 0 {
+            msg!("Moving keeper bounties into escrow account");
+            invoke(
+                &transfer_checked(
+                    acc.token_program.key,
+                    acc.sender_tokens.key,
+                    acc.mint.key,
+                    acc.escrow_tokens.key,
+                    acc.sender.key,
+                    token_pool,
+                    decimals,
+                ),
+                &[
+                    acc.sender_tokens.clone(),
+                    acc.mint.clone(),
+                    acc.escrow_tokens.clone(),
+                    acc.sender.clone(),
+                    acc.token_program.clone(),
+                ],
+            )?;
+        }
+    }
+
+    charge_fee(
+        &acc.token_program,
+        &acc.mint,
+        &acc.sender,
+        &acc.sender_tokens,
+        &acc.fee_treasury_tokens,
+        &acc.system_program,
+        protocol_fee,
+        decimals,
+        native,
     )?;
+    if let Some(partner_tokens) = &acc.partner_tokens {
+        charge_fee(
+            &acc.token_program,
+            &acc.mint,
+            &acc.sender,
+            &acc.sender_tokens,
+            partner_tokens,
+            &acc.system_program,
+            partner_fee,
+            decimals,
+            native,
+        )?;
+    }
 
     msg!(
         "Successfully initialized {} {} token stream for {}",
-        encode_base10(metadata.ix.deposited_amount, mint_info.decimals.into()),
+        encode_base10(metadata.ix.deposited_amount, decimals.into()),
         metadata.mint,
         acc.recipient.key
     );
@@ -274,6 +538,27 @@ pub fn create(
         msg!("Cliff happens at {}", pretty_time(metadata.ix.cliff));
     }
 
+    StreamEvent::StreamCreated {
+        metadata: *acc.metadata.key,
+        sender: metadata.sender,
+        recipient: metadata.recipient,
+        mint: metadata.mint,
+        escrow_tokens: metadata.escrow_tokens,
+        deposited_amount: metadata.ix.deposited_amount,
+        total_amount: metadata.ix.total_amount,
+        start_time: metadata.ix.start_time,
+        end_time: metadata.ix.end_time,
+    }
+    .emit();
+    fees_charged(
+        acc.metadata.key,
+        acc.fee_treasury_tokens.key,
+        protocol_fee,
+        &metadata.partner_tokens,
+        partner_fee,
+        decimals,
+    );
+
     Ok(())
 }
 
@@ -286,11 +571,9 @@ pub fn create(
 pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> ProgramResult {
     msg!("Withdrawing from SPL token stream");
 
-    if acc.escrow_tokens.data_is_empty()
-        || acc.escrow_tokens.owner != &spl_token::id()
-        || acc.metadata.data_is_empty()
-        || acc.metadata.owner != program_id
-    {
+    let native = native_mint::check_id(acc.mint.key);
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -299,90 +582,160 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
         || !acc.metadata.is_writable
         || !acc.escrow_tokens.is_writable
     {
-        return Err(ProgramError::InvalidAccountData);
+        return Err(AccountsNotWritable.into());
     }
 
-    let (escrow_tokens_pubkey, nonce) =
-        Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
-    let recipient_tokens_key = get_associated_token_address(acc.recipient.key, acc.mint.key);
-
-    if acc.token_program.key != &spl_token::id()
-        || acc.escrow_tokens.key != &escrow_tokens_pubkey
-        || acc.recipient_tokens.key != &recipient_tokens_key
-        //TODO: Update in future releases based on `is_withdrawal_public`
-        || acc.withdraw_authority.key != acc.recipient.key
-    {
-        return Err(ProgramError::InvalidAccountData);
+    if !token_program_valid(&acc.token_program, &acc.mint) {
+        return Err(InvalidProgramAccount.into());
     }
 
     if !acc.withdraw_authority.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let mut data = acc.metadata.try_borrow_mut_data()?;
-    // This thing is nasty lol
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, tranches) = account.header()?;
+
+    // The escrow of a closed stream is gone
+    metadata.ensure_open()?;
+    if !escrow_initialized(program_id, &acc.escrow_tokens, native) {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    let (escrow_tokens_pubkey, nonce) = metadata.escrow_address(program_id, acc.metadata.key)?;
+    if acc.escrow_tokens.key != &escrow_tokens_pubkey {
+        return Err(InvalidEscrowAccount.into());
+    }
 
-    let mint_info = unpack_mint_account(&acc.mint)?;
+    let decimals = mint_decimals(&acc.mint)?;
 
     if acc.recipient.key != &metadata.recipient
-        || acc.recipient_tokens.key != &metadata.recipient_tokens
         || acc.mint.key != &metadata.mint
         || acc.escrow_tokens.key != &metadata.escrow_tokens
     {
         msg!("Error: Metadata does not match given accounts");
-        return Err(ProgramError::InvalidAccountData);
+        return Err(MetadataAccountMismatch.into());
+    }
+
+    // Public streams can be cranked by anyone
+    let by_recipient = acc.withdraw_authority.key == &metadata.recipient;
+    if !by_recipient && metadata.withdrawal_public == 0 {
+        msg!("Error: Unauthorized wallet");
+        return Err(WithdrawNotAllowed.into());
+    }
+
+    // Funds go to the stream's destination, unless the recipient picks another
+    if acc.recipient_tokens.key != &metadata.withdraw_destination() {
+        if !by_recipient {
+            msg!("Error: Only the recipient can choose the destination");
+            return Err(InvalidTokenAccount.into());
+        }
+        check_destination(&acc.recipient_tokens, metadata, native)?;
+    }
+
+    if metadata.pending() {
+        msg!("Error: Stream wasn't accepted by the recipient");
+        return Err(StreamPending.into());
     }
 
     let now = Clock::get()?.unix_timestamp as u64;
-    let available = metadata.available(now);
-    let requested: u64;
+    let available = metadata.available(tranches, now)?;
 
     if amount > available {
         msg!("Amount requested for withdraw is more than what is available");
-        return Err(ProgramError::InvalidArgument);
+        return Err(AmountExceedsAvailable.into());
     }
 
     // 0 == MAX
-    if amount == 0 {
-        requested = available;
-    } else {
-        requested = amount;
-    }
+    let requested = if amount == 0 { available } else { amount };
 
     let seeds = [acc.metadata.key.as_ref(), &[nonce]];
-    invoke_signed(
-        &spl_token::instruction::transfer(
-            acc.token_program.key,
-            acc.escrow_tokens.key,
-            acc.recipient_tokens.key,
-            acc.escrow_tokens.key,
-            &[],
-            requested,
-        )?,
-        &[
-            acc.escrow_tokens.clone(),    // src
-            acc.recipient_tokens.clone(), // dest
-            acc.escrow_tokens.clone(),    // auth
-            acc.token_program.clone(),    // program
-        ],
-        &[&seeds],
+    escrow_transfer(
+        &acc.token_program,
+        &acc.mint,
+        &acc.escrow_tokens,
+        &acc.recipient_tokens,
+        requested,
+        &seeds,
+        decimals,
+        native,
     )?;
 
+    // Keepers cranking on time earn a bounty out of the stream's pool, as
+    // long as they settle everything available
+    let mut bounty = if by_recipient || requested == 0 || requested < available {
+        0
+    } else {
+        metadata.crank_bounty(now)
+    };
+    if bounty > 0 && metadata.keeper_bounty_in_tokens != 0 {
+        if let Some(keeper_tokens) = &acc.keeper_tokens {
+            check_destination(keeper_tokens, metadata, native)?;
+            escrow_transfer(
+                &acc.token_program,
+                &acc.mint,
+                &acc.escrow_tokens,
+                keeper_tokens,
+                bounty,
+                &seeds,
+                decimals,
+                native,
+            )?;
+        } else {
+            msg!("No keeper token account given, skipping the bounty");
+            bounty = 0;
+        }
+    } else if bounty > 0 {
+        if acc.withdraw_authority.is_writable {
+            transfer_lamports(&acc.metadata, &acc.withdraw_authority, bounty)?;
+        } else {
+            msg!("Keeper account is not writable, skipping the bounty");
+            bounty = 0;
+        }
+    }
+    metadata.keeper_bounty_left -= bounty;
+
     metadata.withdrawn_amount += requested;
     metadata.last_withdrawn_at = now;
-    let bytes = metadata.try_to_vec()?;
-    data[0..bytes.len()].clone_from_slice(&bytes);
+    let completed = metadata.withdrawn_amount == metadata.deposited_amount;
+    if completed {
+        metadata.transition(StreamStatus::Completed)?;
+    }
+    let bounty_left = metadata.keeper_bounty_left;
+    if completed {
+        metadata.keeper_bounty_left = 0;
+    }
 
     // Return rent when everything is withdrawn
-    if metadata.withdrawn_amount == metadata.ix.deposited_amount {
+    if completed {
         // Do we need this?
-        if !acc.sender.is_writable || acc.sender.key != &metadata.sender {
-            return Err(ProgramError::InvalidAccountData);
+        if !acc.sender.is_writable {
+            return Err(AccountsNotWritable.into());
+        }
+        if acc.sender.key != &metadata.sender {
+            return Err(MetadataAccountMismatch.into());
+        }
+
+        let sender_tokens = acc
+            .sender_tokens
+            .as_ref()
+            .filter(|t| t.key == &metadata.sender_tokens);
+        if metadata.keeper_bounty_in_tokens != 0 && bounty_left > 0 && sender_tokens.is_none() {
+            msg!("Error: The sender's token account is needed to return the bounty pool");
+            return Err(InvalidTokenAccount.into());
         }
+        refund_bounty_pool(
+            &acc.token_program,
+            &acc.mint,
+            &acc.escrow_tokens,
+            &acc.metadata,
+            &acc.sender,
+            sender_tokens.unwrap_or(&acc.sender),
+            metadata,
+            bounty_left,
+            &seeds,
+            decimals,
+        )?;
         //TODO: Close metadata account once there is alternative storage solution for historic data.
         // let rent = acc.metadata.lamports();
         // **acc.metadata.try_borrow_mut_lamports()? -= rent;
@@ -395,37 +748,57 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
             escrow_tokens_rent,
             acc.sender.key
         );
-        invoke_signed(
-            &spl_token::instruction::close_account(
-                acc.token_program.key,
-                acc.escrow_tokens.key,
-                acc.sender.key,
-                acc.escrow_tokens.key,
-                &[],
-            )?,
-            &[
-                acc.escrow_tokens.clone(),
-                acc.sender.clone(),
-                acc.escrow_tokens.clone(),
-            ],
-            &[&seeds],
+        close_escrow(
+            &acc.token_program,
+            &acc.mint,
+            &acc.escrow_tokens,
+            &acc.sender,
+            &seeds,
+            native,
         )?;
     }
 
     msg!(
         "Withdrawn: {} {} tokens",
-        encode_base10(requested, mint_info.decimals.into()),
+        encode_base10(requested, decimals.into()),
         metadata.mint
     );
     msg!(
         "Remaining: {} {} tokens",
         encode_base10(
-            metadata.ix.deposited_amount - metadata.withdrawn_amount,
-            mint_info.decimals.into()
+            metadata.deposited_amount - metadata.withdrawn_amount,
+            decimals.into()
         ),
         metadata.mint
     );
 
+    StreamEvent::Withdrawn {
+        metadata: *acc.metadata.key,
+        authority: *acc.withdraw_authority.key,
+        recipient_tokens: *acc.recipient_tokens.key,
+        amount: requested,
+        withdrawn_amount: metadata.withdrawn_amount,
+        timestamp: now,
+    }
+    .emit();
+
+    if bounty > 0 {
+        msg!(
+            "Paid a bounty of {} to keeper {}",
+            bounty,
+            acc.withdraw_authority.key
+        );
+        StreamEvent::Cranked {
+            metadata: *acc.metadata.key,
+            keeper: *acc.withdraw_authority.key,
+            bounty,
+            bounty_left,
+        }
+        .emit();
+    }
+
+    account.close()?;
+
     Ok(())
 }
 
@@ -437,11 +810,9 @@ pub fn withdraw(program_id: &Pubkey, acc: WithdrawAccounts, amount: u64) -> Prog
 pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
     msg!("Cancelling SPL token stream");
 
-    if acc.escrow_tokens.data_is_empty()
-        || acc.escrow_tokens.owner != &spl_token::id()
-        || acc.metadata.data_is_empty()
-        || acc.metadata.owner != program_id
-    {
+    let native = native_mint::check_id(acc.mint.key);
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -452,35 +823,47 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
         || !acc.metadata.is_writable
         || !acc.escrow_tokens.is_writable
     {
-        return Err(ProgramError::InvalidAccountData);
+        return Err(AccountsNotWritable.into());
     }
 
-    let (escrow_tokens_pubkey, nonce) =
-        Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
-    let recipient_tokens_key = get_associated_token_address(acc.recipient.key, acc.mint.key);
+    if !token_program_valid(&acc.token_program, &acc.mint) {
+        return Err(InvalidProgramAccount.into());
+    }
 
-    if acc.token_program.key != &spl_token::id()
-        || acc.escrow_tokens.key != &escrow_tokens_pubkey
-        || acc.recipient_tokens.key != &recipient_tokens_key
-    {
-        return Err(ProgramError::InvalidAccountData);
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, tranches) = account.header()?;
+
+    metadata.transition(StreamStatus::Canceled)?;
+    if !escrow_initialized(program_id, &acc.escrow_tokens, native) {
+        return Err(ProgramError::UninitializedAccount);
     }
 
-    let mut data = acc.metadata.try_borrow_mut_data()?;
-    // let mut metadata = match TokenStreamData::try_from_slice(&data) {
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
-    let mint_info = unpack_mint_account(&acc.mint)?;
+    let (escrow_tokens_pubkey, nonce) = metadata.escrow_address(program_id, acc.metadata.key)?;
+    if acc.escrow_tokens.key != &escrow_tokens_pubkey {
+        return Err(InvalidEscrowAccount.into());
+    }
+
+    let decimals = mint_decimals(&acc.mint)?;
 
     let now = Clock::get()?.unix_timestamp as u64;
     // if stream expired anyone can close it, if not check cancel authority
     msg!("Now: {}, closable at {}", now, metadata.closable_at);
     if now < metadata.closable_at {
-        //TODO: Update in future releases based on `cancelable_by_sender/recipient`
-        if acc.cancel_authority.key != acc.sender.key {
-            return Err(ProgramError::InvalidAccountData);
+        let is_sender = acc.cancel_authority.key == &metadata.sender;
+        let is_recipient = acc.cancel_authority.key == &metadata.recipient;
+        // The sender can always take back a stream the recipient didn't accept
+        let authorized = (is_sender && (metadata.cancelable_by_sender != 0 || metadata.pending()))
+            || (is_recipient && metadata.cancelable_by_recipient != 0);
+        if !authorized {
+            msg!("Error: Unauthorized wallet");
+            return Err(if is_sender {
+                SenderCancelNotAllowed
+            } else if is_recipient {
+                RecipientCancelNotAllowed
+            } else {
+                CancelNotAllowed
+            }
+            .into());
         }
         if !acc.cancel_authority.is_signer {
             return Err(ProgramError::MissingRequiredSignature);
@@ -490,84 +873,90 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
     if acc.sender.key != &metadata.sender
         || acc.sender_tokens.key != &metadata.sender_tokens
         || acc.recipient.key != &metadata.recipient
-        || acc.recipient_tokens.key != &metadata.recipient_tokens
         || acc.mint.key != &metadata.mint
         || acc.escrow_tokens.key != &metadata.escrow_tokens
     {
-        return Err(ProgramError::InvalidAccountData);
+        msg!("Error: Metadata does not match given accounts");
+        return Err(MetadataAccountMismatch.into());
+    }
+
+    // The recipient's share goes where a withdrawal would send it, or to
+    // their own token account, in case the destination was closed or frozen
+    if acc.recipient_tokens.key != &metadata.withdraw_destination()
+        && acc.recipient_tokens.key != &metadata.recipient_tokens
+    {
+        let by_recipient =
+            acc.cancel_authority.is_signer && acc.cancel_authority.key == &metadata.recipient;
+        if !by_recipient {
+            msg!("Error: Only the recipient can choose the destination");
+            return Err(InvalidTokenAccount.into());
+        }
+        check_destination(&acc.recipient_tokens, metadata, native)?;
     }
 
-    let available = metadata.available(now);
+    let available = metadata.available(tranches, now)?;
     msg!("Available {}", available);
-    let escrow_token_info = unpack_token_account(&acc.escrow_tokens)?;
-    msg!("Amount {}", escrow_token_info.amount);
+    msg!("Amount {}", escrow_balance(&acc.escrow_tokens, native)?);
     let seeds = [acc.metadata.key.as_ref(), &[nonce]];
-    invoke_signed(
-        &spl_token::instruction::transfer(
-            acc.token_program.key,
-            acc.escrow_tokens.key,
-            acc.recipient_tokens.key,
-            acc.escrow_tokens.key,
-            &[],
+    // Pending streams might not have a recipient token account yet
+    if available > 0 {
+        escrow_transfer(
+            &acc.token_program,
+            &acc.mint,
+            &acc.escrow_tokens,
+            &acc.recipient_tokens,
             available,
-        )?,
-        &[
-            acc.escrow_tokens.clone(),    // src
-            acc.recipient_tokens.clone(), // dest
-            acc.escrow_tokens.clone(),    // auth
-            acc.token_program.clone(),    // program
-        ],
-        &[&seeds],
-    )?;
-    let escrow_token_info = unpack_token_account(&acc.escrow_tokens)?;
-    msg!("Amount {}", escrow_token_info.amount);
+            &seeds,
+            decimals,
+            native,
+        )?;
+    }
+    msg!("Amount {}", escrow_balance(&acc.escrow_tokens, native)?);
     metadata.withdrawn_amount += available;
-    let remains = metadata.ix.deposited_amount - metadata.withdrawn_amount;
+    let remains = metadata.deposited_amount - metadata.withdrawn_amount;
     msg!(
         "Deposited {} , withdrawn: {}, tokens remain {}",
-        metadata.ix.deposited_amount,
+        metadata.deposited_amount,
         metadata.withdrawn_amount,
         remains
     );
     // Return any remaining funds to the stream initializer
     if remains > 0 {
-        invoke_signed(
-            &spl_token::instruction::transfer(
-                acc.token_program.key,
-                acc.escrow_tokens.key,
-                acc.sender_tokens.key,
-                acc.escrow_tokens.key,
-                &[],
-                remains,
-            )?,
-            &[
-                acc.escrow_tokens.clone(),
-                acc.sender_tokens.clone(),
-                acc.escrow_tokens.clone(),
-                acc.token_program.clone(),
-            ],
-            &[&seeds],
+        escrow_transfer(
+            &acc.token_program,
+            &acc.mint,
+            &acc.escrow_tokens,
+            &acc.sender_tokens,
+            remains,
+            &seeds,
+            decimals,
+            native,
         )?;
     }
+    refund_bounty_pool(
+        &acc.token_program,
+        &acc.mint,
+        &acc.escrow_tokens,
+        &acc.metadata,
+        &acc.sender,
+        &acc.sender_tokens,
+        metadata,
+        metadata.keeper_bounty_left,
+        &seeds,
+        decimals,
+    )?;
+    metadata.keeper_bounty_left = 0;
 
-    // TODO: Check this for wrapped SOL
     let rent_escrow_tokens = acc.escrow_tokens.lamports();
     // let remains_meta = acc.metadata.lamports();
     //Close escrow token account
-    invoke_signed(
-        &spl_token::instruction::close_account(
-            acc.token_program.key,
-            acc.escrow_tokens.key,
-            acc.sender.key,
-            acc.escrow_tokens.key,
-            &[],
-        )?,
-        &[
-            acc.escrow_tokens.clone(),
-            acc.sender.clone(),
-            acc.escrow_tokens.clone(),
-        ],
-        &[&seeds],
+    close_escrow(
+        &acc.token_program,
+        &acc.mint,
+        &acc.escrow_tokens,
+        &acc.sender,
+        &seeds,
+        native,
     )?;
 
     //TODO: Close metadata account once there is alternative storage solution for historic data.
@@ -575,18 +964,15 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
         metadata.last_withdrawn_at = now;
         metadata.canceled_at = now;
     }
-    // Write the metadata to the account
-    let bytes = metadata.try_to_vec().unwrap();
-    data[0..bytes.len()].clone_from_slice(&bytes);
 
     msg!(
         "Transferred: {} {} tokens",
-        encode_base10(available, mint_info.decimals.into()),
+        encode_base10(available, decimals.into()),
         metadata.mint
     );
     msg!(
         "Returned: {} {} tokens",
-        encode_base10(remains, mint_info.decimals.into()),
+        encode_base10(remains, decimals.into()),
         metadata.mint
     );
     msg!(
@@ -594,17 +980,26 @@ pub fn cancel(program_id: &Pubkey, acc: CancelAccounts) -> ProgramResult {
         rent_escrow_tokens /* + remains_meta */
     );
 
+    StreamEvent::Canceled {
+        metadata: *acc.metadata.key,
+        authority: *acc.cancel_authority.key,
+        recipient_amount: available,
+        sender_amount: remains,
+        timestamp: now,
+    }
+    .emit();
+
+    account.close()?;
+
     Ok(())
 }
 
 pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> ProgramResult {
     msg!("Transferring stream recipient");
 
-    if acc.metadata.data_is_empty()
-        || acc.metadata.owner != program_id
-        || acc.escrow_tokens.data_is_empty()
-        || acc.escrow_tokens.owner != &spl_token::id()
-    {
+    let native = native_mint::check_id(acc.mint.key);
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -616,25 +1011,26 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
         || !acc.authorized_wallet.is_writable
         || !acc.new_recipient_tokens.is_writable
     {
-        return Err(ProgramError::InvalidAccountData);
+        return Err(AccountsNotWritable.into());
     }
 
-    let mut data = acc.metadata.try_borrow_mut_data()?;
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, _) = account.header()?;
+    metadata.ensure_open()?;
+    if !escrow_initialized(program_id, &acc.escrow_tokens, native) {
+        return Err(ProgramError::UninitializedAccount);
+    }
 
-    if !metadata.ix.transferable_by_recipient && !metadata.ix.transferable_by_sender {
+    if metadata.transferable_by_recipient == 0 && metadata.transferable_by_sender == 0 {
         return Err(TransferNotAllowed.into());
     }
 
     // See if the caller is authorized
     let mut authorized = false;
-    if metadata.ix.transferable_by_recipient && &metadata.recipient == acc.authorized_wallet.key {
+    if metadata.transferable_by_recipient != 0 && &metadata.recipient == acc.authorized_wallet.key {
         authorized = true;
     }
-    if metadata.ix.transferable_by_sender && &metadata.sender == acc.authorized_wallet.key {
+    if metadata.transferable_by_sender != 0 && &metadata.sender == acc.authorized_wallet.key {
         authorized = true;
     }
     if !authorized {
@@ -642,32 +1038,43 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
         return Err(TransferNotAllowed.into());
     }
 
-    let (escrow_tokens_pubkey, _) =
-        Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
+    let (escrow_tokens_pubkey, _) = metadata.escrow_address(program_id, acc.metadata.key)?;
     let new_recipient_tokens_key =
-        get_associated_token_address(acc.new_recipient.key, acc.mint.key);
+        find_tokens_address(acc.new_recipient.key, acc.mint.key, acc.token_program.key);
 
-    if acc.new_recipient_tokens.key != &new_recipient_tokens_key
-        || acc.mint.key != &metadata.mint
-        || acc.authorized_wallet.key != &metadata.recipient
-        || acc.escrow_tokens.key != &metadata.escrow_tokens
-        || acc.escrow_tokens.key != &escrow_tokens_pubkey
-        || acc.token_program.key != &spl_token::id()
+    if !token_program_valid(&acc.token_program, &acc.mint)
         || acc.system_program.key != &system_program::id()
         || acc.rent.key != &sysvar::rent::id()
     {
-        return Err(ProgramError::InvalidAccountData);
+        return Err(InvalidProgramAccount.into());
     }
 
-    if acc.new_recipient_tokens.data_is_empty() {
+    if acc.authorized_wallet.key != &metadata.recipient {
+        msg!("Error: Only the recipient can transfer the stream");
+        return Err(TransferNotAllowed.into());
+    }
+
+    if acc.escrow_tokens.key != &escrow_tokens_pubkey {
+        return Err(InvalidEscrowAccount.into());
+    }
+
+    if acc.mint.key != &metadata.mint || acc.escrow_tokens.key != &metadata.escrow_tokens {
+        msg!("Error: Metadata does not match given accounts");
+        return Err(MetadataAccountMismatch.into());
+    }
+
+    if acc.new_recipient_tokens.key != &new_recipient_tokens_key {
+        return Err(InvalidTokenAccount.into());
+    }
+
+    if !native && acc.new_recipient_tokens.data_is_empty() {
         // Initialize a new_beneficiary_owner account
-        let tokens_struct_size = spl_token::state::Account::LEN;
+        let tokens_struct_size = escrow_account_len(&acc.mint.data.borrow())?;
         let cluster_rent = Rent::get()?;
         let tokens_rent = cluster_rent.minimum_balance(tokens_struct_size);
         let fees = Fees::get()?;
         let lps = fees.fee_calculator.lamports_per_signature;
 
-        // TODO: Check if wrapped SOL
         if acc.authorized_wallet.lamports() < tokens_rent + lps {
             msg!("Error: Insufficient funds in {}", acc.authorized_wallet.key);
             return Err(ProgramError::InsufficientFunds);
@@ -679,6 +1086,7 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
                 acc.authorized_wallet.key,
                 acc.new_recipient.key,
                 acc.mint.key,
+                acc.token_program.key,
             ),
             &[
                 acc.authorized_wallet.clone(),    // Funding
@@ -692,12 +1100,21 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
         )?;
     }
 
-    // Update recipient
+    // Update recipient, the destination registered by the old one is dropped
+    let old_recipient = metadata.recipient;
     metadata.recipient = *acc.new_recipient.key;
     metadata.recipient_tokens = *acc.new_recipient_tokens.key;
+    metadata.destination_tokens = Pubkey::default();
 
-    let bytes = metadata.try_to_vec()?;
-    data[0..bytes.len()].clone_from_slice(&bytes);
+    StreamEvent::RecipientTransferred {
+        metadata: *acc.metadata.key,
+        old_recipient,
+        new_recipient: metadata.recipient,
+        new_recipient_tokens: metadata.recipient_tokens,
+    }
+    .emit();
+
+    account.close()?;
 
     Ok(())
 }
@@ -705,10 +1122,19 @@ pub fn transfer_recipient(program_id: &Pubkey, acc: TransferAccounts) -> Program
 /// Top up the SPL Token stream
 ///
 /// The function will add the amount to the metadata SPL account
-pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> ProgramResult {
+pub fn topup_stream(
+    program_id: &Pubkey,
+    config: &ProgramConfig,
+    acc: TopUpAccounts,
+    amount: u64,
+) -> ProgramResult {
     msg!("Topping up the escrow account");
 
-    if acc.metadata.data_is_empty() || acc.escrow_tokens.owner != &spl_token::id() {
+    check_mint(program_id, config, &acc.mint_registry, acc.mint.key)?;
+
+    let native = native_mint::check_id(acc.mint.key);
+
+    if acc.metadata.data_is_empty() {
         return Err(ProgramError::UninitializedAccount);
     }
 
@@ -716,81 +1142,1030 @@ pub fn topup_stream(program_id: &Pubkey, acc: TopUpAccounts, amount: u64) -> Pro
         || !acc.sender_tokens.is_writable
         || !acc.metadata.is_writable
         || !acc.escrow_tokens.is_writable
+        || !acc.fee_treasury_tokens.is_writable
+        || acc
+            .partner_tokens
+            .as_ref()
+            .map_or(false, |a| !a.is_writable)
     {
         return Err(AccountsNotWritable.into());
     }
 
-    let (escrow_tokens_pubkey, _) =
-        Pubkey::find_program_address(&[acc.metadata.key.as_ref()], program_id);
+    if !token_program_valid(&acc.token_program, &acc.mint) {
+        return Err(InvalidProgramAccount.into());
+    }
 
-    if acc.token_program.key != &spl_token::id() || acc.escrow_tokens.key != &escrow_tokens_pubkey {
-        return Err(ProgramError::InvalidAccountData);
+    if acc.fee_treasury_tokens.key
+        != &find_tokens_address(&config.fee_treasury, acc.mint.key, acc.token_program.key)
+    {
+        return Err(InvalidTokenAccount.into());
     }
 
     if !acc.sender.is_signer {
         return Err(ProgramError::MissingRequiredSignature);
     }
 
-    let sender_token_info = unpack_token_account(&acc.sender_tokens)?;
+    if native {
+        if acc.sender_tokens.key != acc.sender.key {
+            return Err(InvalidTokenAccount.into());
+        }
+    } else {
+        let sender_token_info = unpack_token_account(&acc.sender_tokens)?;
 
-    if &sender_token_info.mint != acc.mint.key {
-        return Err(MintMismatch.into());
+        if &sender_token_info.mint != acc.mint.key {
+            return Err(MintMismatch.into());
+        }
     }
 
     if amount == 0 {
         msg!("Error: Amount can't be zero.");
-        return Err(ProgramError::InvalidArgument);
+        return Err(ZeroAmount.into());
     }
 
-    let mut data = acc.metadata.try_borrow_mut_data()?;
-    let mut metadata: TokenStreamData = match solana_borsh::try_from_slice_unchecked(&data) {
-        Ok(v) => v,
-        Err(_) => return Err(InvalidMetadata.into()),
-    };
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, tranches) = account.header()?;
+
+    let (escrow_tokens_pubkey, _) = metadata.escrow_address(program_id, acc.metadata.key)?;
+    if acc.escrow_tokens.key != &escrow_tokens_pubkey {
+        return Err(InvalidEscrowAccount.into());
+    }
 
     if acc.mint.key != &metadata.mint || acc.escrow_tokens.key != &metadata.escrow_tokens {
         msg!("Error: Metadata does not match given accounts");
-        return Err(ProgramError::InvalidAccountData);
+        return Err(MetadataAccountMismatch.into());
     }
 
-    let now = Clock::get()?.unix_timestamp as u64;
-    if metadata.closable() < now {
+    metadata.ensure_open()?;
+    if !escrow_initialized(program_id, &acc.escrow_tokens, native) {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    let clock = Clock::get()?;
+    let now = clock.unix_timestamp as u64;
+    if metadata.closable(tranches)? < now {
         msg!("Error: Topup after the stream is closed");
         return Err(StreamClosed.into());
     }
 
+    // Top-ups pay the fees at the rates the stream was created with
+    let (protocol_fee, partner_fee) = metadata.fees(amount);
+    let partner_tokens = match &acc.partner_tokens {
+        Some(partner_tokens) if partner_tokens.key == &metadata.partner_tokens => {
+            Some(partner_tokens)
+        }
+        _ if partner_fee == 0 => None,
+        _ => {
+            msg!("Error: Partner token account doesn't match the stream");
+            return Err(InvalidTokenAccount.into());
+        }
+    };
+    let escrowed = amount - protocol_fee - partner_fee;
+
+    let decimals = mint_decimals(&acc.mint)?;
+    let fee = if native {
+        0
+    } else {
+        transfer_fee(&acc.mint.data.borrow(), clock.epoch, escrowed)?
+    };
+
     msg!("Transferring to the escrow account");
-    invoke(
-        &spl_token::instruction::transfer(
-            acc.token_program.key,
-            acc.sender_tokens.key,
-            acc.escrow_tokens.key,
-            acc.sender.key,
-            &[],
-            amount,
-        )?,
-        &[
-            acc.sender_tokens.clone(),
-            acc.escrow_tokens.clone(),
-            acc.sender.clone(),
-            acc.token_program.clone(),
-        ],
-    )?;
+    if native {
+        if acc.system_program.key != &system_program::id() {
+            return Err(InvalidProgramAccount.into());
+        }
 
-    metadata.ix.deposited_amount += amount;
-    metadata.closable_at = metadata.closable();
+        invoke(
+            &system_instruction::transfer(acc.sender.key, acc.escrow_tokens.key, escrowed),
+            &[
+                acc.sender.clone(),
+                acc.escrow_tokens.clone(),
+                acc.system_program.clone(),
+            ],
+        )?;
+    } else {
+        invoke(
+            &transfer_checked(
+                acc.token_program.key,
+                acc.sender_tokens.key,
+                acc.mint.key,
+                acc.escrow_tokens.key,
+                acc.sender.key,
+                escrowed,
+                decimals,
+            ),
+            &[
+                acc.sender_tokens.clone(),
+                acc.mint.clone(),
+                acc.escrow_tokens.clone(),
+                acc.sender.clone(),
+                acc.token_program.clone(),
+            ],
+        )?;
+    }
 
-    let bytes = metadata.try_to_vec().unwrap();
-    data[0..bytes.len()].clone_from_slice(&bytes);
+    charge_fee(
+        &acc.token_program,
+        &acc.mint,
+        &acc.sender,
+        &acc.sender_tokens,
+        &acc.fee_treasury_tokens,
+        &acc.system_program,
+        protocol_fee,
+        decimals,
+        native,
+    )?;
+    if let Some(partner_tokens) = partner_tokens {
+        charge_fee(
+            &acc.token_program,
+            &acc.mint,
+            &acc.sender,
+            &acc.sender_tokens,
+            partner_tokens,
+            &acc.system_program,
+            partner_fee,
+            decimals,
+            native,
+        )?;
+    }
 
-    let mint_info = unpack_mint_account(&acc.mint)?;
+    metadata.deposited_amount += escrowed - fee;
+    metadata.protocol_fee_amount += protocol_fee;
+    metadata.partner_fee_amount += partner_fee;
+    metadata.transfer_fee_amount += fee;
+    metadata.closable_at = metadata.closable(tranches)?;
 
     msg!(
         "Successfully topped up {} to token stream {} on behalf of {}",
-        encode_base10(amount, mint_info.decimals.into()),
+        encode_base10(amount, decimals.into()),
         acc.escrow_tokens.key,
         acc.sender.key,
     );
 
+    StreamEvent::ToppedUp {
+        metadata: *acc.metadata.key,
+        amount,
+        deposited_amount: metadata.deposited_amount,
+        closable_at: metadata.closable_at,
+    }
+    .emit();
+    fees_charged(
+        acc.metadata.key,
+        acc.fee_treasury_tokens.key,
+        protocol_fee,
+        &metadata.partner_tokens,
+        partner_fee,
+        decimals,
+    );
+
+    account.close()?;
+
+    Ok(())
+}
+
+/// Pause an SPL Token stream
+///
+/// Nothing vests while the stream is paused. Vesting continues from the
+/// same point once the stream is resumed, shifting the rest of the schedule.
+pub fn pause(program_id: &Pubkey, acc: PauseAccounts) -> ProgramResult {
+    msg!("Pausing SPL token stream");
+
+    ProgramConfig::load(program_id, &acc.config)?.check_active()?;
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !acc.authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.metadata.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, tranches) = account.header()?;
+
+    if metadata.pausable_by_sender == 0 || acc.authority.key != &metadata.sender {
+        msg!("Error: Unauthorized wallet");
+        return Err(PauseNotAllowed.into());
+    }
+
+    if metadata.pending() {
+        return Err(StreamPending.into());
+    }
+
+    metadata.transition(StreamStatus::Paused)?;
+    let now = Clock::get()?.unix_timestamp as u64;
+    metadata.paused_at = now;
+    metadata.closable_at = metadata.closable(tranches)?;
+
+    msg!("Stream paused at {}", now);
+
+    StreamEvent::Paused {
+        metadata: *acc.metadata.key,
+        timestamp: now,
+    }
+    .emit();
+
+    account.close()?;
+
+    Ok(())
+}
+
+/// Resume a paused SPL Token stream
+pub fn resume(program_id: &Pubkey, acc: PauseAccounts) -> ProgramResult {
+    msg!("Resuming SPL token stream");
+
+    ProgramConfig::load(program_id, &acc.config)?.check_active()?;
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !acc.authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.metadata.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, tranches) = account.header()?;
+
+    if metadata.pausable_by_sender == 0 || acc.authority.key != &metadata.sender {
+        msg!("Error: Unauthorized wallet");
+        return Err(PauseNotAllowed.into());
+    }
+
+    if metadata.paused_at == 0 {
+        return Err(StreamNotPaused.into());
+    }
+
+    metadata.transition(StreamStatus::Active)?;
+
+    let now = Clock::get()?.unix_timestamp as u64;
+    metadata.paused_duration += now - metadata.paused_at;
+    metadata.paused_at = 0;
+    metadata.closable_at = metadata.closable(tranches)?;
+
+    msg!(
+        "Stream resumed after {}",
+        pretty_time(metadata.paused_duration)
+    );
+    msg!("Closable at: {}", metadata.closable_at);
+
+    StreamEvent::Resumed {
+        metadata: *acc.metadata.key,
+        timestamp: now,
+        closable_at: metadata.closable_at,
+    }
+    .emit();
+
+    account.close()?;
+
+    Ok(())
+}
+
+/// Accept a stream created with `requires_acceptance`
+///
+/// Only the recipient can accept, before the acceptance deadline. Their
+/// token account is created if needed, with the recipient paying for it,
+/// and the stream starts vesting.
+pub fn accept(program_id: &Pubkey, acc: AcceptAccounts) -> ProgramResult {
+    msg!("Accepting SPL token stream");
+
+    ProgramConfig::load(program_id, &acc.config)?.check_active()?;
+
+    let native = native_mint::check_id(acc.mint.key);
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !acc.recipient.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.recipient.is_writable || !acc.recipient_tokens.is_writable || !acc.metadata.is_writable
+    {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if !token_program_valid(&acc.token_program, &acc.mint)
+        || acc.system_program.key != &system_program::id()
+        || acc.rent.key != &sysvar::rent::id()
+    {
+        return Err(InvalidProgramAccount.into());
+    }
+
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, tranches) = account.header()?;
+
+    if acc.recipient.key != &metadata.recipient
+        || acc.recipient_tokens.key != &metadata.recipient_tokens
+        || acc.mint.key != &metadata.mint
+    {
+        msg!("Error: Metadata does not match given accounts");
+        return Err(MetadataAccountMismatch.into());
+    }
+
+    if !metadata.pending() {
+        return Err(StreamNotPending.into());
+    }
+
+    let now = Clock::get()?.unix_timestamp as u64;
+    if metadata.acceptance_deadline > 0 && now > metadata.acceptance_deadline {
+        msg!(
+            "Error: Acceptance deadline passed at {}",
+            metadata.acceptance_deadline
+        );
+        return Err(AcceptanceExpired.into());
+    }
+
+    if !native && acc.recipient_tokens.data_is_empty() {
+        msg!("Initializing recipient's associated token account");
+        invoke(
+            &create_associated_token_account(
+                acc.recipient.key,
+                acc.recipient.key,
+                acc.mint.key,
+                acc.token_program.key,
+            ),
+            &[
+                acc.recipient.clone(),
+                acc.recipient_tokens.clone(),
+                acc.recipient.clone(),
+                acc.mint.clone(),
+                acc.system_program.clone(),
+                acc.token_program.clone(),
+                acc.rent.clone(),
+            ],
+        )?;
+    }
+
+    metadata.accept(tranches, now)?;
+
+    msg!("Closable at: {}", metadata.closable_at);
+
+    StreamEvent::Accepted {
+        metadata: *acc.metadata.key,
+        recipient: metadata.recipient,
+        timestamp: now,
+        closable_at: metadata.closable_at,
+    }
+    .emit();
+
+    account.close()?;
+
+    Ok(())
+}
+
+/// Register the default withdraw destination of an SPL Token stream
+///
+/// Withdrawals, public ones included, go to `destination_tokens` from now
+/// on. Passing `recipient_tokens` goes back to the recipient's own account.
+pub fn set_withdraw_destination(program_id: &Pubkey, acc: SetDestinationAccounts) -> ProgramResult {
+    msg!("Setting the withdraw destination of SPL token stream");
+
+    ProgramConfig::load(program_id, &acc.config)?.check_active()?;
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !acc.recipient.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.metadata.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    let mut account = StreamAccount::open(&acc.metadata)?;
+    let (metadata, _) = account.header()?;
+    metadata.ensure_open()?;
+
+    if acc.recipient.key != &metadata.recipient {
+        msg!("Error: Only the recipient can set the withdraw destination");
+        return Err(WithdrawNotAllowed.into());
+    }
+
+    let native = native_mint::check_id(&metadata.mint);
+    check_destination(&acc.destination_tokens, metadata, native)?;
+
+    metadata.destination_tokens = if acc.destination_tokens.key == &metadata.recipient_tokens {
+        Pubkey::default()
+    } else {
+        *acc.destination_tokens.key
+    };
+
+    msg!("Withdrawals go to {}", metadata.withdraw_destination());
+
+    StreamEvent::DestinationSet {
+        metadata: *acc.metadata.key,
+        destination_tokens: metadata.withdraw_destination(),
+    }
+    .emit();
+
+    account.close()?;
+
+    Ok(())
+}
+
+/// Migrate stream metadata written by an older program version
+///
+/// The metadata account is grown to fit the current layout and rewritten
+/// in place, which needs the `realloc` feature. Anyone can migrate a
+/// stream, `payer` covers the extra rent.
+pub fn migrate(program_id: &Pubkey, acc: MigrateAccounts) -> ProgramResult {
+    msg!("Migrating stream metadata");
+
+    ProgramConfig::load(program_id, &acc.config)?.check_active()?;
+
+    if acc.metadata.data_is_empty() || acc.metadata.owner != program_id {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    if !acc.payer.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.payer.is_writable || !acc.metadata.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if acc.system_program.key != &system_program::id() {
+        return Err(InvalidProgramAccount.into());
+    }
+
+    let mut metadata = TokenStreamData::load(&acc.metadata.try_borrow_data()?)?;
+    let old_version = metadata.magic;
+    metadata.magic = PROGRAM_VERSION;
+    let space = metadata.space().max(acc.metadata.data_len());
+
+    if old_version == PROGRAM_VERSION && space == acc.metadata.data_len() {
+        msg!("Metadata is already up to date");
+        return Ok(());
+    }
+
+    // Older layouts stay writable in place on clusters without reallocation
+    if space > acc.metadata.data_len() && !cfg!(feature = "realloc") {
+        msg!("Error: Growing the metadata account needs the `realloc` feature");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    let rent = Rent::get()?.minimum_balance(space);
+    if acc.metadata.lamports() < rent {
+        invoke(
+            &system_instruction::transfer(
+                acc.payer.key,
+                acc.metadata.key,
+                rent - acc.metadata.lamports(),
+            ),
+            &[
+                acc.payer.clone(),
+                acc.metadata.clone(),
+                acc.system_program.clone(),
+            ],
+        )?;
+    }
+
+    realloc(&acc.metadata, space)?;
+    let mut data = acc.metadata.try_borrow_mut_data()?;
+    // Don't leave any bytes of the old layout behind
+    for b in data.iter_mut() {
+        *b = 0;
+    }
+    metadata.save(&mut data)?;
+
+    msg!(
+        "Migrated metadata from version {} to {}",
+        old_version,
+        PROGRAM_VERSION
+    );
+
+    StreamEvent::Migrated {
+        metadata: *acc.metadata.key,
+        old_version,
+        new_version: PROGRAM_VERSION,
+    }
+    .emit();
+
     Ok(())
 }
+
+/// Create the program config
+///
+/// Only the program's upgrade authority can do this, once. From then on
+/// the config is managed by `params.admin`.
+pub fn init_config(
+    program_id: &Pubkey,
+    acc: InitConfigAccounts,
+    params: ConfigParams,
+) -> ProgramResult {
+    msg!("Initializing program config");
+
+    if !acc.config.data_is_empty() {
+        return Err(ProgramError::AccountAlreadyInitialized);
+    }
+
+    if !acc.upgrade_authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.upgrade_authority.is_writable || !acc.config.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if acc.system_program.key != &system_program::id() {
+        return Err(InvalidProgramAccount.into());
+    }
+
+    let (config_pubkey, bump) = find_config_address(program_id);
+    if acc.config.key != &config_pubkey {
+        return Err(InvalidConfigAccount.into());
+    }
+
+    if upgrade_authority(program_id, &acc.program_data)? != Some(*acc.upgrade_authority.key) {
+        msg!("Error: Only the upgrade authority can initialize the config");
+        return Err(ConfigUpdateNotAllowed.into());
+    }
+
+    create_pda_account(
+        &acc.upgrade_authority,
+        &acc.config,
+        &acc.system_program,
+        Rent::get()?.minimum_balance(CONFIG_SIZE),
+        CONFIG_SIZE,
+        program_id,
+        &[CONFIG_SEED, &[bump]],
+    )?;
+
+    let mut data = acc.config.try_borrow_mut_data()?;
+    let config = ProgramConfig::from_account_data_mut(&mut data)?;
+    config.apply(&params)?;
+    config.magic = CONFIG_VERSION;
+    config.bump = bump;
+
+    msg!("Program config admin is {}", config.admin);
+
+    Ok(())
+}
+
+/// Update the program config
+///
+/// Only the current admin can do this, handing the config over to
+/// another admin if `params.admin` differs.
+pub fn update_config(
+    program_id: &Pubkey,
+    acc: UpdateConfigAccounts,
+    params: ConfigParams,
+) -> ProgramResult {
+    msg!("Updating program config");
+
+    let current = ProgramConfig::load(program_id, &acc.config)?;
+
+    if !acc.admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.config.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if acc.admin.key != &current.admin {
+        msg!("Error: Unauthorized wallet");
+        return Err(ConfigUpdateNotAllowed.into());
+    }
+
+    let mut data = acc.config.try_borrow_mut_data()?;
+    let config = ProgramConfig::from_account_data_mut(&mut data)?;
+    config.apply(&params)?;
+
+    if config.admin != current.admin {
+        msg!("Program config admin changed to {}", config.admin);
+    }
+
+    Ok(())
+}
+
+/// Pause the stream instructions in `paused_instructions`, unpausing
+/// the rest
+///
+/// Meant for emergencies, so it's open to the guardian as well as the
+/// admin. The handlers check the flags before anything else.
+pub fn set_pause(
+    program_id: &Pubkey,
+    acc: SetPauseAccounts,
+    paused_instructions: u64,
+) -> ProgramResult {
+    msg!("Setting paused instructions");
+
+    let current = ProgramConfig::load(program_id, &acc.config)?;
+
+    if !acc.authority.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.config.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if acc.authority.key != &current.guardian && acc.authority.key != &current.admin {
+        msg!("Error: Unauthorized wallet");
+        return Err(ConfigUpdateNotAllowed.into());
+    }
+
+    if paused_instructions & !PAUSE_ALL != 0 {
+        msg!("Error: Unknown instruction flags");
+        return Err(InvalidConfig.into());
+    }
+
+    let mut data = acc.config.try_borrow_mut_data()?;
+    let config = ProgramConfig::from_account_data_mut(&mut data)?;
+    config.paused_instructions = paused_instructions;
+
+    msg!("Paused instructions: {:#b}", paused_instructions);
+
+    Ok(())
+}
+
+/// Add `mint` to the mint registry, creating the registry on first use
+pub fn register_mint(
+    program_id: &Pubkey,
+    acc: MintRegistryAccounts,
+    mint: Pubkey,
+) -> ProgramResult {
+    msg!("Registering mint {}", mint);
+
+    let bump = check_mint_registry_accounts(program_id, &acc)?;
+
+    let count = if acc.mint_registry.data_is_empty() {
+        let space = registry_len(1);
+        create_pda_account(
+            &acc.admin,
+            &acc.mint_registry,
+            &acc.system_program,
+            Rent::get()?.minimum_balance(space),
+            space,
+            program_id,
+            &[MINT_REGISTRY_SEED, &[bump]],
+        )?;
+        0
+    } else {
+        let data = acc.mint_registry.data.borrow();
+        let mints = registry_mints(&data)?;
+        if mints.contains(&mint) {
+            msg!("Error: Mint is already registered");
+            return Err(ProgramError::InvalidArgument);
+        }
+        mints.len()
+    };
+
+    // Slots freed by removed mints are reused before growing the account
+    let space = registry_len(count + 1);
+    if acc.mint_registry.data_len() < space {
+        let rent = Rent::get()?.minimum_balance(space);
+        if acc.mint_registry.lamports() < rent {
+            invoke(
+                &system_instruction::transfer(
+                    acc.admin.key,
+                    acc.mint_registry.key,
+                    rent - acc.mint_registry.lamports(),
+                ),
+                &[
+                    acc.admin.clone(),
+                    acc.mint_registry.clone(),
+                    acc.system_program.clone(),
+                ],
+            )?;
+        }
+        realloc(&acc.mint_registry, space)?;
+    }
+
+    let mut data = acc.mint_registry.try_borrow_mut_data()?;
+    data[registry_len(count)..space].copy_from_slice(mint.as_ref());
+    write_registry_header(&mut data, count + 1);
+
+    msg!("Mint registry holds {} mints", count + 1);
+
+    Ok(())
+}
+
+/// Remove `mint` from the mint registry
+pub fn unregister_mint(
+    program_id: &Pubkey,
+    acc: MintRegistryAccounts,
+    mint: Pubkey,
+) -> ProgramResult {
+    msg!("Unregistering mint {}", mint);
+
+    check_mint_registry_accounts(program_id, &acc)?;
+
+    let mut data = acc.mint_registry.try_borrow_mut_data()?;
+    let mints = registry_mints(&data)?;
+    let count = mints.len();
+    let index = match mints.iter().position(|m| m == &mint) {
+        Some(index) => index,
+        None => {
+            msg!("Error: Mint is not registered");
+            return Err(ProgramError::InvalidArgument);
+        }
+    };
+
+    // Move the last mint into the freed slot
+    let last = registry_len(count - 1);
+    data.copy_within(last..registry_len(count), registry_len(index));
+    for b in data[last..registry_len(count)].iter_mut() {
+        *b = 0;
+    }
+    write_registry_header(&mut data, count - 1);
+
+    msg!("Mint registry holds {} mints", count - 1);
+
+    Ok(())
+}
+
+/// Checks shared by the mint registry instructions, returns the bump of
+/// the registry address
+fn check_mint_registry_accounts(
+    program_id: &Pubkey,
+    acc: &MintRegistryAccounts,
+) -> Result<u8, ProgramError> {
+    let config = ProgramConfig::load(program_id, &acc.config)?;
+
+    if !acc.admin.is_signer {
+        return Err(ProgramError::MissingRequiredSignature);
+    }
+
+    if !acc.admin.is_writable || !acc.mint_registry.is_writable {
+        return Err(AccountsNotWritable.into());
+    }
+
+    if acc.admin.key != &config.admin {
+        msg!("Error: Unauthorized wallet");
+        return Err(ConfigUpdateNotAllowed.into());
+    }
+
+    if acc.system_program.key != &system_program::id() {
+        return Err(InvalidProgramAccount.into());
+    }
+
+    let (mint_registry_pubkey, bump) = find_mint_registry_address(program_id);
+    if acc.mint_registry.key != &mint_registry_pubkey
+        || (!acc.mint_registry.data_is_empty() && acc.mint_registry.owner != program_id)
+    {
+        return Err(InvalidMintRegistry.into());
+    }
+
+    Ok(bump)
+}
+
+/// Check `destination` can receive the stream's funds: a token account of
+/// its mint, or any wallet for native SOL streams, but never its escrow
+fn check_destination(
+    destination: &AccountInfo,
+    metadata: &StreamMetadata,
+    native: bool,
+) -> ProgramResult {
+    if destination.key == &metadata.escrow_tokens {
+        return Err(InvalidTokenAccount.into());
+    }
+
+    if !native && unpack_token_account(destination)?.mint != metadata.mint {
+        return Err(MintMismatch.into());
+    }
+
+    Ok(())
+}
+
+/// Whether the escrow account of a stream has been created. Native SOL
+/// escrows hold no data, they're recognized by the program owning them.
+fn escrow_initialized(program_id: &Pubkey, escrow: &AccountInfo, native: bool) -> bool {
+    if native {
+        return escrow.owner == program_id;
+    }
+
+    !escrow.data_is_empty() && is_token_program(escrow.owner)
+}
+
+/// Whether `token_program` is one of the token programs and owns `mint`
+fn token_program_valid(token_program: &AccountInfo, mint: &AccountInfo) -> bool {
+    is_token_program(token_program.key) && mint.owner == token_program.key
+}
+
+/// Funds held in the escrow account, not counting the rent of a native
+/// SOL escrow
+fn escrow_balance(escrow: &AccountInfo, native: bool) -> Result<u64, ProgramError> {
+    if native {
+        let rent = Rent::get()?.minimum_balance(escrow.data_len());
+        return Ok(escrow.lamports().saturating_sub(rent));
+    }
+
+    Ok(unpack_token_account(escrow)?.amount)
+}
+
+/// Pay `amount` out of the escrow account into `destination`, signing
+/// with the escrow `seeds`. A native SOL escrow is never drained below
+/// its rent-exempt minimum, only `close_escrow` can do that.
+#[allow(clippy::too_many_arguments)]
+fn escrow_transfer<'a>(
+    token_program: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    destination: &AccountInfo<'a>,
+    amount: u64,
+    seeds: &[&[u8]],
+    decimals: u8,
+    native: bool,
+) -> ProgramResult {
+    if native {
+        if amount > escrow_balance(escrow, native)? {
+            msg!("Error: Escrow can't go below its rent-exempt balance");
+            return Err(ProgramError::InsufficientFunds);
+        }
+        return transfer_lamports(escrow, destination, amount);
+    }
+
+    invoke_signed(
+        &transfer_checked(
+            token_program.key,
+            escrow.key,
+            mint.key,
+            destination.key,
+            escrow.key,
+            amount,
+            decimals,
+        ),
+        &[
+            escrow.clone(),        // src
+            mint.clone(),          // mint
+            destination.clone(),   // dest
+            escrow.clone(),        // auth
+            token_program.clone(), // program
+        ],
+        &[seeds],
+    )
+}
+
+/// Pay a fee of `amount` from the sender into `fee_tokens`, a wallet for
+/// native SOL streams. Nothing happens for a zero fee, so the fee accounts
+/// don't need to exist while fees are off.
+#[allow(clippy::too_many_arguments)]
+fn charge_fee<'a>(
+    token_program: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    sender: &AccountInfo<'a>,
+    sender_tokens: &AccountInfo<'a>,
+    fee_tokens: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    amount: u64,
+    decimals: u8,
+    native: bool,
+) -> ProgramResult {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    if native {
+        return invoke(
+            &system_instruction::transfer(sender.key, fee_tokens.key, amount),
+            &[sender.clone(), fee_tokens.clone(), system_program.clone()],
+        );
+    }
+
+    invoke(
+        &transfer_checked(
+            token_program.key,
+            sender_tokens.key,
+            mint.key,
+            fee_tokens.key,
+            sender.key,
+            amount,
+            decimals,
+        ),
+        &[
+            sender_tokens.clone(),
+            mint.clone(),
+            fee_tokens.clone(),
+            sender.clone(),
+            token_program.clone(),
+        ],
+    )
+}
+
+/// Return `amount` left in the keeper bounty pool to the sender: out of the
+/// escrow into `sender_tokens` for token bounties, out of the metadata
+/// account into `sender` for lamports
+#[allow(clippy::too_many_arguments)]
+fn refund_bounty_pool<'a>(
+    token_program: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    metadata_account: &AccountInfo<'a>,
+    sender: &AccountInfo<'a>,
+    sender_tokens: &AccountInfo<'a>,
+    metadata: &StreamMetadata,
+    amount: u64,
+    seeds: &[&[u8]],
+    decimals: u8,
+) -> ProgramResult {
+    if amount == 0 {
+        return Ok(());
+    }
+
+    msg!(
+        "Returning {} of the keeper bounty pool to {}",
+        amount,
+        sender.key
+    );
+    if metadata.keeper_bounty_in_tokens == 0 {
+        return transfer_lamports(metadata_account, sender, amount);
+    }
+
+    escrow_transfer(
+        token_program,
+        mint,
+        escrow,
+        sender_tokens,
+        amount,
+        seeds,
+        decimals,
+        false,
+    )
+}
+
+/// Log the fees charged on a deposit, if any
+fn fees_charged(
+    metadata: &Pubkey,
+    fee_treasury_tokens: &Pubkey,
+    protocol_fee: u64,
+    partner_tokens: &Pubkey,
+    partner_fee: u64,
+    decimals: u8,
+) {
+    if protocol_fee == 0 && partner_fee == 0 {
+        return;
+    }
+
+    msg!(
+        "Charged {} protocol fee to {}",
+        encode_base10(protocol_fee, decimals.into()),
+        fee_treasury_tokens
+    );
+    if partner_fee > 0 {
+        msg!(
+            "Charged {} partner fee to {}",
+            encode_base10(partner_fee, decimals.into()),
+            partner_tokens
+        );
+    }
+
+    StreamEvent::FeesCharged {
+        metadata: *metadata,
+        fee_treasury_tokens: *fee_treasury_tokens,
+        protocol_fee,
+        partner_tokens: *partner_tokens,
+        partner_fee,
+    }
+    .emit();
+}
+
+/// Close the escrow account, returning whatever it holds to `sender`.
+///
+/// Transfer fees withheld in a Token-2022 escrow are harvested to the mint
+/// first, which needs the mint to be writable. Without that the escrow is
+/// left open, emptied of tokens, so the payout itself never fails.
+fn close_escrow<'a>(
+    token_program: &AccountInfo<'a>,
+    mint: &AccountInfo<'a>,
+    escrow: &AccountInfo<'a>,
+    sender: &AccountInfo<'a>,
+    seeds: &[&[u8]],
+    native: bool,
+) -> ProgramResult {
+    if native {
+        return transfer_lamports(escrow, sender, escrow.lamports());
+    }
+
+    if token_2022::check_id(token_program.key) && withheld_amount(&escrow.data.borrow()) > 0 {
+        if !mint.is_writable {
+            msg!("Escrow holds withheld transfer fees, leaving it open");
+            return Ok(());
+        }
+
+        msg!("Harvesting withheld transfer fees to {}", mint.key);
+        invoke(
+            &harvest_withheld_tokens_to_mint(mint.key, escrow.key),
+            &[mint.clone(), escrow.clone(), token_program.clone()],
+        )?;
+    }
+
+    invoke_signed(
+        &close_account(token_program.key, escrow.key, sender.key, escrow.key),
+        &[escrow.clone(), sender.clone(), escrow.clone()],
+        &[seeds],
+    )
+}
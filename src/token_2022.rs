@@ -0,0 +1,401 @@
+// Copyright (c) 2021 Ivan Jelincic <parazyd@dyne.org>
+//
+// This file is part of streamflow-finance/timelock-crate
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License version 3
+// as published by the Free Software Foundation.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+use std::convert::TryInto;
+
+use solana_program::{
+    instruction::{AccountMeta, Instruction},
+    program_error::ProgramError,
+    program_pack::Pack,
+    pubkey::Pubkey,
+    system_program, sysvar,
+};
+use spl_token::instruction::TokenInstruction;
+
+use crate::error::StreamFlowError;
+
+// Token-2022 shares the instruction set and base account layouts of the
+// SPL token program, followed by TLV encoded extensions.
+solana_program::declare_id!("TokenzQdBNbLqP5VEhdkAS6EPFLC1PHnBqCXEpPxuEb");
+
+/// Extension types, numbered as in the Token-2022 `ExtensionType` enum
+pub const TRANSFER_FEE_CONFIG: u16 = 1;
+pub const TRANSFER_FEE_AMOUNT: u16 = 2;
+pub const MINT_CLOSE_AUTHORITY: u16 = 3;
+pub const INTEREST_BEARING_CONFIG: u16 = 10;
+pub const METADATA_POINTER: u16 = 18;
+pub const TOKEN_METADATA: u16 = 19;
+
+/// Mint extensions that can't be used to take funds out of, or lock up,
+/// an escrow account. Interest-bearing mints only change how amounts are
+/// displayed, streams always work with raw amounts.
+const SUPPORTED_MINT_EXTENSIONS: [u16; 5] = [
+    TRANSFER_FEE_CONFIG,
+    MINT_CLOSE_AUTHORITY,
+    INTEREST_BEARING_CONFIG,
+    METADATA_POINTER,
+    TOKEN_METADATA,
+];
+
+/// Offset of the account type byte in accounts with extensions
+const ACCOUNT_TYPE_OFFSET: usize = spl_token::state::Account::LEN;
+const ACCOUNT_TYPE_MINT: u8 = 1;
+const ACCOUNT_TYPE_ACCOUNT: u8 = 2;
+/// Size of a TLV entry header, u16 type and u16 length
+const TLV_HEADER_SIZE: usize = 4;
+/// Size of the `TransferFeeAmount` account extension
+const TRANSFER_FEE_AMOUNT_SIZE: usize = 8;
+/// Offsets of the fees in the `TransferFeeConfig` mint extension, after
+/// two authorities and the withheld amount
+const OLDER_TRANSFER_FEE_OFFSET: usize = 72;
+const NEWER_TRANSFER_FEE_OFFSET: usize = 90;
+const TRANSFER_FEE_SIZE: usize = 18;
+
+/// Whether `key` is one of the supported token programs
+pub fn is_token_program(key: &Pubkey) -> bool {
+    key == &spl_token::id() || check_id(key)
+}
+
+/// Iterate over the extensions of a Token-2022 account of `account_type`
+fn extensions(data: &[u8], account_type: u8) -> impl Iterator<Item = (u16, &[u8])> {
+    let mut tlv: &[u8] = match data.get(ACCOUNT_TYPE_OFFSET) {
+        Some(t) if *t == account_type => &data[ACCOUNT_TYPE_OFFSET + 1..],
+        _ => &[],
+    };
+
+    std::iter::from_fn(move || {
+        let header = tlv.get(..TLV_HEADER_SIZE)?;
+        let extension_type = u16::from_le_bytes([header[0], header[1]]);
+        let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+        // Type 0 marks the unused space at the end of the account
+        if extension_type == 0 {
+            return None;
+        }
+        let value = tlv.get(TLV_HEADER_SIZE..TLV_HEADER_SIZE + len)?;
+        tlv = &tlv[TLV_HEADER_SIZE + len..];
+        Some((extension_type, value))
+    })
+}
+
+fn mint_extension(mint_data: &[u8], extension_type: u16) -> Option<&[u8]> {
+    extensions(mint_data, ACCOUNT_TYPE_MINT)
+        .find(|(t, _)| *t == extension_type)
+        .map(|(_, value)| value)
+}
+
+/// A transfer fee as configured on a mint
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TransferFee {
+    /// First epoch the fee applies to
+    pub epoch: u64,
+    pub maximum_fee: u64,
+    pub transfer_fee_basis_points: u16,
+}
+
+impl TransferFee {
+    fn unpack(data: &[u8]) -> Self {
+        Self {
+            epoch: u64::from_le_bytes(data[..8].try_into().unwrap()),
+            maximum_fee: u64::from_le_bytes(data[8..16].try_into().unwrap()),
+            transfer_fee_basis_points: u16::from_le_bytes(data[16..18].try_into().unwrap()),
+        }
+    }
+
+    /// Fee withheld when transferring `amount`, rounded up like the
+    /// Token-2022 program does
+    pub fn calculate_fee(&self, amount: u64) -> u64 {
+        if self.transfer_fee_basis_points == 0 || amount == 0 {
+            return 0;
+        }
+
+        let fee = (amount as u128 * self.transfer_fee_basis_points as u128 + 9_999) / 10_000;
+        (fee as u64).min(self.maximum_fee)
+    }
+}
+
+/// Fee withheld from a transfer of `amount` tokens of the mint in
+/// `mint_data` during `epoch`, 0 for mints without transfer fees
+pub fn transfer_fee(mint_data: &[u8], epoch: u64, amount: u64) -> Result<u64, ProgramError> {
+    let config = match mint_extension(mint_data, TRANSFER_FEE_CONFIG) {
+        Some(config) => config,
+        None => return Ok(0),
+    };
+
+    let fee = config
+        .get(NEWER_TRANSFER_FEE_OFFSET..NEWER_TRANSFER_FEE_OFFSET + TRANSFER_FEE_SIZE)
+        .map(TransferFee::unpack)
+        .ok_or(ProgramError::InvalidAccountData)?;
+    let fee = if epoch >= fee.epoch {
+        fee
+    } else {
+        TransferFee::unpack(
+            &config[OLDER_TRANSFER_FEE_OFFSET..OLDER_TRANSFER_FEE_OFFSET + TRANSFER_FEE_SIZE],
+        )
+    };
+
+    Ok(fee.calculate_fee(amount))
+}
+
+/// Size of an escrow token account for the mint in `mint_data`, with room
+/// for the account extensions the mint requires. Fails on mints with
+/// extensions the program doesn't support.
+pub fn escrow_account_len(mint_data: &[u8]) -> Result<usize, ProgramError> {
+    let mut len = spl_token::state::Account::LEN;
+    for (extension_type, _) in extensions(mint_data, ACCOUNT_TYPE_MINT) {
+        if !SUPPORTED_MINT_EXTENSIONS.contains(&extension_type) {
+            return Err(StreamFlowError::UnsupportedMintExtension.into());
+        }
+        if extension_type == TRANSFER_FEE_CONFIG {
+            len += TLV_HEADER_SIZE + TRANSFER_FEE_AMOUNT_SIZE;
+        }
+    }
+
+    if len > spl_token::state::Account::LEN {
+        // Account type byte
+        len += 1;
+    }
+
+    Ok(len)
+}
+
+/// Transfer fees withheld in the token account in `account_data`, they
+/// have to be harvested before the account can be closed
+pub fn withheld_amount(account_data: &[u8]) -> u64 {
+    extensions(account_data, ACCOUNT_TYPE_ACCOUNT)
+        .find(|(t, _)| *t == TRANSFER_FEE_AMOUNT)
+        .and_then(|(_, value)| value.get(..8))
+        .map(|amount| u64::from_le_bytes(amount.try_into().unwrap()))
+        .unwrap_or(0)
+}
+
+/// `InitializeAccount` for either token program
+pub fn initialize_account(
+    token_program: &Pubkey,
+    account: &Pubkey,
+    mint: &Pubkey,
+    owner: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*account, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(*owner, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: TokenInstruction::InitializeAccount.pack(),
+    }
+}
+
+/// `TransferChecked` for either token program, the plain `Transfer`
+/// is rejected for mints with transfer fees
+pub fn transfer_checked(
+    token_program: &Pubkey,
+    source: &Pubkey,
+    mint: &Pubkey,
+    destination: &Pubkey,
+    authority: &Pubkey,
+    amount: u64,
+    decimals: u8,
+) -> Instruction {
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*source, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new_readonly(*authority, true),
+        ],
+        data: TokenInstruction::TransferChecked { amount, decimals }.pack(),
+    }
+}
+
+/// `CloseAccount` for either token program
+pub fn close_account(
+    token_program: &Pubkey,
+    account: &Pubkey,
+    destination: &Pubkey,
+    owner: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: *token_program,
+        accounts: vec![
+            AccountMeta::new(*account, false),
+            AccountMeta::new(*destination, false),
+            AccountMeta::new_readonly(*owner, true),
+        ],
+        data: TokenInstruction::CloseAccount.pack(),
+    }
+}
+
+/// Token-2022 `HarvestWithheldTokensToMint`, moving the transfer fees
+/// withheld in `source` to the mint. Anyone can invoke it.
+pub fn harvest_withheld_tokens_to_mint(mint: &Pubkey, source: &Pubkey) -> Instruction {
+    Instruction {
+        program_id: id(),
+        accounts: vec![
+            AccountMeta::new(*mint, false),
+            AccountMeta::new(*source, false),
+        ],
+        // TransferFeeExtension, HarvestWithheldTokensToMint
+        data: vec![26, 4],
+    }
+}
+
+/// Associated token account address of `wallet` for `mint` owned by
+/// `token_program`
+pub fn get_associated_token_address(
+    wallet: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Pubkey {
+    Pubkey::find_program_address(
+        &[wallet.as_ref(), token_program.as_ref(), mint.as_ref()],
+        &spl_associated_token_account::id(),
+    )
+    .0
+}
+
+/// Create the associated token account of `wallet` for `mint` owned by
+/// `token_program`, funded by `funder`
+pub fn create_associated_token_account(
+    funder: &Pubkey,
+    wallet: &Pubkey,
+    mint: &Pubkey,
+    token_program: &Pubkey,
+) -> Instruction {
+    Instruction {
+        program_id: spl_associated_token_account::id(),
+        accounts: vec![
+            AccountMeta::new(*funder, true),
+            AccountMeta::new(
+                get_associated_token_address(wallet, mint, token_program),
+                false,
+            ),
+            AccountMeta::new_readonly(*wallet, false),
+            AccountMeta::new_readonly(*mint, false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(*token_program, false),
+            AccountMeta::new_readonly(sysvar::rent::id(), false),
+        ],
+        data: vec![],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mint_with_extensions(extensions: &[(u16, Vec<u8>)]) -> Vec<u8> {
+        let mut data = vec![0; ACCOUNT_TYPE_OFFSET];
+        data.push(ACCOUNT_TYPE_MINT);
+        for (extension_type, value) in extensions {
+            data.extend_from_slice(&extension_type.to_le_bytes());
+            data.extend_from_slice(&(value.len() as u16).to_le_bytes());
+            data.extend_from_slice(value);
+        }
+        data
+    }
+
+    fn transfer_fee_config(older: TransferFee, newer: TransferFee) -> Vec<u8> {
+        let mut config = vec![0; OLDER_TRANSFER_FEE_OFFSET];
+        for fee in [older, newer] {
+            config.extend_from_slice(&fee.epoch.to_le_bytes());
+            config.extend_from_slice(&fee.maximum_fee.to_le_bytes());
+            config.extend_from_slice(&fee.transfer_fee_basis_points.to_le_bytes());
+        }
+        config
+    }
+
+    #[test]
+    fn test_calculate_fee() {
+        let fee = TransferFee {
+            epoch: 0,
+            maximum_fee: 5_000,
+            transfer_fee_basis_points: 100,
+        };
+        assert_eq!(fee.calculate_fee(0), 0);
+        assert_eq!(fee.calculate_fee(1), 1);
+        assert_eq!(fee.calculate_fee(10_000), 100);
+        assert_eq!(fee.calculate_fee(10_001), 101);
+        assert_eq!(fee.calculate_fee(u64::MAX), 5_000);
+        assert_eq!(TransferFee::default().calculate_fee(10_000), 0);
+    }
+
+    #[test]
+    fn test_fee_bearing_mint() {
+        let older = TransferFee {
+            epoch: 0,
+            maximum_fee: u64::MAX,
+            transfer_fee_basis_points: 50,
+        };
+        let newer = TransferFee {
+            epoch: 10,
+            maximum_fee: 1_000,
+            transfer_fee_basis_points: 200,
+        };
+        let mint = mint_with_extensions(&[
+            (INTEREST_BEARING_CONFIG, vec![0; 52]),
+            (TRANSFER_FEE_CONFIG, transfer_fee_config(older, newer)),
+        ]);
+
+        assert_eq!(transfer_fee(&mint, 9, 10_000), Ok(50));
+        assert_eq!(transfer_fee(&mint, 10, 10_000), Ok(200));
+        assert_eq!(transfer_fee(&mint, 10, 1_000_000), Ok(1_000));
+        assert_eq!(escrow_account_len(&mint), Ok(178));
+
+        // Plain mints, with or without the Token-2022 layout
+        let plain = vec![0; spl_token::state::Mint::LEN];
+        assert_eq!(transfer_fee(&plain, 10, 10_000), Ok(0));
+        assert_eq!(escrow_account_len(&plain), Ok(165));
+        let interest_bearing = mint_with_extensions(&[(INTEREST_BEARING_CONFIG, vec![0; 52])]);
+        assert_eq!(transfer_fee(&interest_bearing, 10, 10_000), Ok(0));
+        assert_eq!(escrow_account_len(&interest_bearing), Ok(165));
+
+        // Permanent delegate
+        let unsupported = mint_with_extensions(&[(12, vec![0; 32])]);
+        assert_eq!(
+            escrow_account_len(&unsupported),
+            Err(StreamFlowError::UnsupportedMintExtension.into())
+        );
+    }
+
+    #[test]
+    fn test_withheld_amount() {
+        let mut account = vec![0; ACCOUNT_TYPE_OFFSET];
+        assert_eq!(withheld_amount(&account), 0);
+
+        account.push(ACCOUNT_TYPE_ACCOUNT);
+        account.extend_from_slice(&TRANSFER_FEE_AMOUNT.to_le_bytes());
+        account.extend_from_slice(&8u16.to_le_bytes());
+        account.extend_from_slice(&42u64.to_le_bytes());
+        assert_eq!(account.len(), 178);
+        assert_eq!(withheld_amount(&account), 42);
+    }
+
+    #[test]
+    fn test_associated_token_address() {
+        let wallet = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        assert_eq!(
+            get_associated_token_address(&wallet, &mint, &spl_token::id()),
+            spl_associated_token_account::get_associated_token_address(&wallet, &mint)
+        );
+        assert_ne!(
+            get_associated_token_address(&wallet, &mint, &id()),
+            get_associated_token_address(&wallet, &mint, &spl_token::id())
+        );
+    }
+}
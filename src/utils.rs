@@ -15,7 +15,22 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 use std::iter::FromIterator;
 
-use solana_program::{account_info::AccountInfo, program_error::ProgramError, program_pack::Pack};
+use solana_program::{
+    account_info::AccountInfo,
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+    entrypoint::{ProgramResult, MAX_PERMITTED_DATA_INCREASE},
+    msg,
+    program::invoke,
+    program::invoke_signed,
+    program_error::ProgramError,
+    program_pack::{IsInitialized, Pack},
+    pubkey::Pubkey,
+    system_instruction,
+};
+use spl_token::native_mint;
+
+use crate::error::StreamFlowError::InvalidProgramAccount;
+use crate::token_2022::is_token_program;
 
 /// Do a sanity check with given Unix timestamps.
 pub fn duration_sanity(now: u64, start: u64, end: u64, cliff: u64) -> bool {
@@ -28,22 +43,178 @@ pub fn duration_sanity(now: u64, start: u64, end: u64, cliff: u64) -> bool {
     now < start && start < end && cliff_cond
 }
 
-/// Unpack token account from `account_info`
+/// Unpack token account from `account_info`, owned by either token
+/// program. Token-2022 extensions after the base account are ignored.
 pub fn unpack_token_account(
     account_info: &AccountInfo,
 ) -> Result<spl_token::state::Account, ProgramError> {
-    if account_info.owner != &spl_token::id() {
+    if !is_token_program(account_info.owner) {
         return Err(ProgramError::InvalidAccountData);
     }
 
-    spl_token::state::Account::unpack(&account_info.data.borrow())
+    unpack_base::<spl_token::state::Account>(&account_info.data.borrow())
 }
 
-/// Unpack mint account from `account_info`
+/// Unpack mint account from `account_info`, ignoring Token-2022 extensions
 pub fn unpack_mint_account(
     account_info: &AccountInfo,
 ) -> Result<spl_token::state::Mint, ProgramError> {
-    spl_token::state::Mint::unpack(&account_info.data.borrow())
+    unpack_base::<spl_token::state::Mint>(&account_info.data.borrow())
+}
+
+fn unpack_base<T: Pack + IsInitialized>(data: &[u8]) -> Result<T, ProgramError> {
+    let base = T::unpack_from_slice(data.get(..T::LEN).ok_or(ProgramError::InvalidAccountData)?)?;
+    if !base.is_initialized() {
+        return Err(ProgramError::UninitializedAccount);
+    }
+
+    Ok(base)
+}
+
+/// Decimals of the mint in `account_info`, without reading the account
+/// for native SOL.
+pub fn mint_decimals(account_info: &AccountInfo) -> Result<u8, ProgramError> {
+    if native_mint::check_id(account_info.key) {
+        return Ok(native_mint::DECIMALS);
+    }
+
+    Ok(unpack_mint_account(account_info)?.decimals)
+}
+
+/// Move `amount` lamports from `from`, which must be owned by the program,
+/// to `to`.
+pub fn transfer_lamports(from: &AccountInfo, to: &AccountInfo, amount: u64) -> ProgramResult {
+    let from_lamports = from
+        .lamports()
+        .checked_sub(amount)
+        .ok_or(ProgramError::InsufficientFunds)?;
+    let to_lamports = to
+        .lamports()
+        .checked_add(amount)
+        .ok_or(ProgramError::InvalidArgument)?;
+
+    **from.try_borrow_mut_lamports()? = from_lamports;
+    **to.try_borrow_mut_lamports()? = to_lamports;
+    Ok(())
+}
+
+/// Upgrade authority of `program_id`, read from its `program_data`
+/// account. `None` once the program has been made immutable.
+pub fn upgrade_authority(
+    program_id: &Pubkey,
+    program_data: &AccountInfo,
+) -> Result<Option<Pubkey>, ProgramError> {
+    let (program_data_key, _) =
+        Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+    if program_data.key != &program_data_key || program_data.owner != &bpf_loader_upgradeable::id()
+    {
+        return Err(InvalidProgramAccount.into());
+    }
+
+    match bincode::deserialize(&program_data.data.borrow()) {
+        Ok(UpgradeableLoaderState::ProgramData {
+            upgrade_authority_address,
+            ..
+        }) => Ok(upgrade_authority_address),
+        _ => Err(ProgramError::InvalidAccountData),
+    }
+}
+
+/// Create a program derived account owned by `owner`, funded by `payer`.
+/// Unlike a plain `create_account`, this doesn't fail when somebody has
+/// already sent lamports to the (predictable) address.
+pub fn create_pda_account<'a>(
+    payer: &AccountInfo<'a>,
+    account: &AccountInfo<'a>,
+    system_program: &AccountInfo<'a>,
+    lamports: u64,
+    space: usize,
+    owner: &Pubkey,
+    seeds: &[&[u8]],
+) -> ProgramResult {
+    let accounts = [payer.clone(), account.clone(), system_program.clone()];
+
+    if account.lamports() == 0 {
+        return invoke_signed(
+            &system_instruction::create_account(
+                payer.key,
+                account.key,
+                lamports,
+                space as u64,
+                owner,
+            ),
+            &accounts,
+            &[seeds],
+        );
+    }
+
+    let required = lamports.saturating_sub(account.lamports());
+    if required > 0 {
+        invoke(
+            &system_instruction::transfer(payer.key, account.key, required),
+            &accounts,
+        )?;
+    }
+    invoke_signed(
+        &system_instruction::allocate(account.key, space as u64),
+        &accounts,
+        &[seeds],
+    )?;
+    invoke_signed(
+        &system_instruction::assign(account.key, owner),
+        &accounts,
+        &[seeds],
+    )
+}
+
+/// Grow the data of an account owned by the program to `new_len` bytes,
+/// zeroing the new bytes. The runtime allows growing by at most
+/// `MAX_PERMITTED_DATA_INCREASE` bytes per instruction, and only on
+/// clusters with account data reallocation enabled, so this is built with
+/// the `realloc` feature only. Without it, growing fails with
+/// `AccountDataTooSmall`.
+///
+/// TODO: Use `AccountInfo::realloc` once solana-program provides it.
+pub fn realloc(account: &AccountInfo, new_len: usize) -> ProgramResult {
+    let old_len = account.data_len();
+    if new_len < old_len || new_len - old_len > MAX_PERMITTED_DATA_INCREASE {
+        return Err(ProgramError::InvalidAccountData);
+    }
+
+    if new_len == old_len {
+        return Ok(());
+    }
+
+    if !cfg!(feature = "realloc") {
+        msg!("Error: Growing accounts needs the `realloc` feature");
+        return Err(ProgramError::AccountDataTooSmall);
+    }
+
+    let mut data = account.try_borrow_mut_data()?;
+
+    #[cfg(all(feature = "realloc", target_arch = "bpf"))]
+    unsafe {
+        // The loader serializes the data length right before the data and
+        // leaves MAX_PERMITTED_DATA_INCREASE bytes of room after it.
+        let data_ptr = data.as_mut_ptr();
+        *(data_ptr.offset(-8) as *mut u64) = new_len as u64;
+        *data = std::slice::from_raw_parts_mut(data_ptr, new_len);
+    }
+
+    // Native programs (tests) get a copy of the data written back after
+    // the instruction, so a new buffer will do.
+    #[cfg(all(feature = "realloc", not(target_arch = "bpf")))]
+    {
+        let mut new_data = data.to_vec();
+        new_data.resize(new_len, 0);
+        *data = Box::leak(new_data.into_boxed_slice());
+    }
+
+    for b in data[old_len..].iter_mut() {
+        *b = 0;
+    }
+
+    Ok(())
 }
 
 /// Returns a days/hours/minutes/seconds string from given `t` seconds.
@@ -74,7 +245,15 @@ pub fn encode_base10(amount: u64, decimal_places: usize) -> String {
 
 #[allow(unused_imports)]
 mod tests {
-    use crate::utils::duration_sanity;
+    use crate::error::StreamFlowError::InvalidProgramAccount;
+    use crate::utils::{duration_sanity, realloc, transfer_lamports, upgrade_authority};
+    use solana_program::{
+        account_info::AccountInfo,
+        bpf_loader_upgradeable::{self, UpgradeableLoaderState},
+        entrypoint::MAX_PERMITTED_DATA_INCREASE,
+        program_error::ProgramError,
+        pubkey::Pubkey,
+    };
 
     #[test]
     fn test_duration_sanity() {
@@ -86,4 +265,118 @@ mod tests {
         assert!(!duration_sanity(130, 130, 130, 130));
         assert!(!duration_sanity(100, 110, 130, 140));
     }
+
+    #[test]
+    fn test_realloc() {
+        let key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut lamports = 0;
+        let mut data = vec![1; 8];
+        let account = AccountInfo::new(
+            &key,
+            false,
+            true,
+            &mut lamports,
+            &mut data,
+            &owner,
+            false,
+            0,
+        );
+
+        assert_eq!(realloc(&account, 8), Ok(()));
+        assert_eq!(realloc(&account, 4), Err(ProgramError::InvalidAccountData));
+        assert_eq!(
+            realloc(&account, 9 + MAX_PERMITTED_DATA_INCREASE),
+            Err(ProgramError::InvalidAccountData)
+        );
+
+        #[cfg(not(feature = "realloc"))]
+        assert_eq!(
+            realloc(&account, 16),
+            Err(ProgramError::AccountDataTooSmall)
+        );
+
+        #[cfg(feature = "realloc")]
+        {
+            assert_eq!(realloc(&account, 16), Ok(()));
+            assert_eq!(
+                &account.data.borrow()[..],
+                &[1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0]
+            );
+        }
+    }
+
+    #[test]
+    fn test_transfer_lamports() {
+        let from_key = Pubkey::new_unique();
+        let to_key = Pubkey::new_unique();
+        let owner = Pubkey::new_unique();
+        let mut from_lamports = 100;
+        let mut to_lamports = 5;
+        let mut from_data = vec![];
+        let mut to_data = vec![];
+        let from = AccountInfo::new(
+            &from_key,
+            false,
+            true,
+            &mut from_lamports,
+            &mut from_data,
+            &owner,
+            false,
+            0,
+        );
+        let to = AccountInfo::new(
+            &to_key,
+            false,
+            true,
+            &mut to_lamports,
+            &mut to_data,
+            &owner,
+            false,
+            0,
+        );
+
+        assert_eq!(transfer_lamports(&from, &to, 40), Ok(()));
+        assert_eq!((from.lamports(), to.lamports()), (60, 45));
+        assert_eq!(
+            transfer_lamports(&from, &to, 61),
+            Err(ProgramError::InsufficientFunds)
+        );
+        assert_eq!((from.lamports(), to.lamports()), (60, 45));
+    }
+
+    #[test]
+    fn test_upgrade_authority() {
+        let program_id = Pubkey::new_unique();
+        let authority = Pubkey::new_unique();
+        let (key, _) =
+            Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+        let mut lamports = 0;
+        let mut data = bincode::serialize(&UpgradeableLoaderState::ProgramData {
+            slot: 42,
+            upgrade_authority_address: Some(authority),
+        })
+        .unwrap();
+        // Followed by the program itself
+        data.extend_from_slice(&[1; 16]);
+        let program_data = AccountInfo::new(
+            &key,
+            false,
+            false,
+            &mut lamports,
+            &mut data,
+            &bpf_loader_upgradeable::ID,
+            false,
+            0,
+        );
+
+        assert_eq!(
+            upgrade_authority(&program_id, &program_data),
+            Ok(Some(authority))
+        );
+        assert_eq!(
+            upgrade_authority(&Pubkey::new_unique(), &program_data),
+            Err(InvalidProgramAccount.into())
+        );
+    }
 }
@@ -43,8 +43,21 @@ pub struct ProgramTestBench {
 
 impl ProgramTestBench {
     pub async fn start_new(programs: &[TestBenchProgram<'_>]) -> Self {
+        Self::start_with_accounts(programs, &[]).await
+    }
+
+    /// Start the bench with additional accounts preloaded, e.g. accounts
+    /// hand-crafted in a legacy layout
+    pub async fn start_with_accounts(
+        programs: &[TestBenchProgram<'_>],
+        accounts: &[(Pubkey, Account)],
+    ) -> Self {
         let mut program_test = ProgramTest::default();
 
+        for (pubkey, account) in accounts {
+            program_test.add_account(*pubkey, account.clone());
+        }
+
         let alice = Keypair::new();
         let bob = Keypair::new();
 
@@ -3,20 +3,35 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use solana_program::program_error::ProgramError;
 use solana_program_test::{processor, tokio};
 use solana_sdk::{
+    account::Account,
+    bpf_loader_upgradeable::{self, UpgradeableLoaderState},
     clock::UnixTimestamp,
+    compute_budget,
     instruction::{AccountMeta, Instruction},
+    native_token::sol_to_lamports,
+    process_instruction::BpfComputeBudget,
     program_pack::Pack,
     pubkey::Pubkey,
     signature::Signer,
     signer::keypair::Keypair,
-    system_program,
+    system_instruction, system_program,
     sysvar::rent,
+    transaction::Transaction,
 };
 use spl_associated_token_account::get_associated_token_address;
 use test_sdk::{tools::clone_keypair, ProgramTestBench, TestBenchProgram};
 
+use streamflow_timelock::config::{ConfigParams, ProgramConfig, CONFIG_SIZE, PAUSE_ALL};
 use streamflow_timelock::entrypoint::process_instruction;
-use streamflow_timelock::state::{StreamInstruction, TokenStreamData, PROGRAM_VERSION};
+use streamflow_timelock::error::StreamFlowError;
+use streamflow_timelock::instruction::{self, TimelockInstruction};
+use streamflow_timelock::layout::{ESCROW_TOKENS_BUMP_OFFSET, STREAM_NAME_SIZE};
+use streamflow_timelock::mint_registry::{registry_mints, MintRegistryMode};
+use streamflow_timelock::state::{
+    StreamInstruction, StreamStatus, TokenStreamData, VestingCurve, PROGRAM_VERSION,
+    V1_METADATA_SIZE,
+};
+use streamflow_timelock::token_2022;
 
 #[derive(BorshSerialize, BorshDeserialize, Clone)]
 struct CreateStreamIx {
@@ -49,21 +64,107 @@ struct TransferIx {
 pub struct TimelockProgramTest {
     pub bench: ProgramTestBench,
     pub program_id: Pubkey,
+    /// Upgrade authority of the program and admin of its config
+    pub admin: Keypair,
+    pub config: Pubkey,
+    pub mint_registry: Pubkey,
+}
+
+fn config_params(admin: &Pubkey) -> ConfigParams {
+    ConfigParams {
+        admin: *admin,
+        fee_treasury: *admin,
+        max_name_size: 64,
+        fee_basis_points: 0,
+        paused: false,
+        spl_token_allowed: true,
+        token_2022_allowed: true,
+        partner_fee_basis_points: 0,
+        guardian: *admin,
+        sender_cancel_while_paused: false,
+        mint_registry_mode: MintRegistryMode::Disabled,
+    }
 }
 
 impl TimelockProgramTest {
     pub async fn start_new() -> Self {
-        let program_id = Keypair::new().pubkey();
+        Self::start_with_accounts(Keypair::new().pubkey(), &[]).await
+    }
+
+    pub async fn start_with_accounts(program_id: Pubkey, accounts: &[(Pubkey, Account)]) -> Self {
+        Self::start_with_programs(program_id, accounts, &[]).await
+    }
 
+    /// Start with `programs` loaded next to the timelock program, e.g. BPF
+    /// builds found in `tests/fixtures`
+    pub async fn start_with_programs(
+        program_id: Pubkey,
+        accounts: &[(Pubkey, Account)],
+        programs: &[TestBenchProgram<'_>],
+    ) -> Self {
         let program = TestBenchProgram {
             program_name: "streamflow_timelock",
             program_id,
             process_instruction: processor!(process_instruction),
         };
 
-        let bench = ProgramTestBench::start_new(&[program]).await;
+        // The program isn't really deployed, fake its upgradeable loader
+        // account so the config can be initialized
+        let admin = Keypair::new();
+        let (program_data, _) =
+            Pubkey::find_program_address(&[program_id.as_ref()], &bpf_loader_upgradeable::id());
+        let program_data_account = Account {
+            lamports: sol_to_lamports(1.0),
+            data: bincode::serialize(&UpgradeableLoaderState::ProgramData {
+                slot: 0,
+                upgrade_authority_address: Some(admin.pubkey()),
+            })
+            .unwrap(),
+            owner: bpf_loader_upgradeable::id(),
+            ..Account::default()
+        };
+        let admin_account = Account {
+            lamports: sol_to_lamports(1.0),
+            ..Account::default()
+        };
+
+        let mut accounts = accounts.to_vec();
+        accounts.push((program_data, program_data_account));
+        accounts.push((admin.pubkey(), admin_account));
+        let mut programs = programs.to_vec();
+        programs.insert(0, program);
+        let mut bench = ProgramTestBench::start_with_accounts(&programs, &accounts).await;
+
+        let init_config_ix =
+            instruction::init_config(&program_id, &admin.pubkey(), config_params(&admin.pubkey()));
+        bench
+            .process_transaction(&[init_config_ix], Some(&[&admin]))
+            .await
+            .unwrap();
+        let (config, _) = instruction::find_config_address(&program_id);
+        let (mint_registry, _) = instruction::find_mint_registry_address(&program_id);
+
+        Self {
+            bench,
+            program_id,
+            admin,
+            config,
+            mint_registry,
+        }
+    }
+
+    pub async fn update_config(&mut self, params: ConfigParams) -> Result<(), ProgramError> {
+        let admin = clone_keypair(&self.admin);
+        let update_config_ix =
+            instruction::update_config(&self.program_id, &admin.pubkey(), params);
+        self.bench
+            .process_transaction(&[update_config_ix], Some(&[&admin]))
+            .await
+    }
 
-        Self { bench, program_id }
+    pub async fn get_metadata(&mut self, address: &Pubkey) -> TokenStreamData {
+        let account = self.bench.get_account(address).await.unwrap();
+        TokenStreamData::load(&account.data).unwrap()
     }
 
     pub async fn advance_clock_past_timestamp(&mut self, unix_timestamp: UnixTimestamp) {
@@ -82,6 +183,51 @@ impl TimelockProgramTest {
             clock = self.bench.get_clock().await;
         }
     }
+
+    /// Compute units `ix` consumes: the smallest budget requested with
+    /// `compute_budget::request_units` it succeeds under. Every successful
+    /// probe is processed, so `ix` must be repeatable. Only BPF programs
+    /// are metered, see `cargo test-bpf`.
+    pub async fn compute_units(&mut self, ix: &Instruction, signer: &Keypair) -> u64 {
+        let (mut low, mut high) = (0, 1_000_000);
+        assert!(self.probe_compute_units(ix, signer, high).await);
+        while low < high {
+            let units = (low + high) / 2;
+            if self.probe_compute_units(ix, signer, units).await {
+                high = units;
+            } else {
+                low = units + 1;
+            }
+        }
+
+        u64::from(high)
+    }
+
+    async fn probe_compute_units(
+        &mut self,
+        ix: &Instruction,
+        signer: &Keypair,
+        units: u32,
+    ) -> bool {
+        let instructions = [compute_budget::request_units(units), ix.clone()];
+        let mut transaction =
+            Transaction::new_with_payer(&instructions, Some(&self.bench.payer.pubkey()));
+        let recent_blockhash = self
+            .bench
+            .context
+            .banks_client
+            .get_recent_blockhash()
+            .await
+            .unwrap();
+        transaction.sign(&[&self.bench.payer, signer], recent_blockhash);
+
+        self.bench
+            .context
+            .banks_client
+            .process_transaction(transaction)
+            .await
+            .is_ok()
+    }
 }
 
 #[tokio::test]
@@ -146,6 +292,14 @@ async fn timelock_program_test() -> Result<()> {
             transferable_by_recipient: false,
             release_rate: 0,
             stream_name: "TheTestoooooooooor".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
         },
     };
 
@@ -164,6 +318,12 @@ async fn timelock_program_test() -> Result<()> {
             AccountMeta::new_readonly(spl_token::id(), false),
             AccountMeta::new_readonly(spl_associated_token_account::id(), false),
             AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
+            AccountMeta::new_readonly(tt.mint_registry, false),
+            AccountMeta::new(
+                get_associated_token_address(&tt.admin.pubkey(), &strm_token_mint.pubkey()),
+                false,
+            ),
         ],
     );
 
@@ -172,7 +332,7 @@ async fn timelock_program_test() -> Result<()> {
         .await?;
 
     let metadata_acc = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
 
     assert_eq!(metadata_acc.owner, tt.program_id);
     assert_eq!(metadata_data.magic, PROGRAM_VERSION);
@@ -218,6 +378,7 @@ async fn timelock_program_test() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
@@ -225,8 +386,9 @@ async fn timelock_program_test() -> Result<()> {
         .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&bob]))
         .await?;
 
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
-    assert_eq!(metadata_data.withdrawn_amount, 1180000000);
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    // 354 periods of 3333333 (20 tokens / 600 periods, rounded down)
+    assert_eq!(metadata_data.withdrawn_amount, 1179999882);
 
     println!("{:#?}", metadata_data);
     Ok(())
@@ -294,6 +456,14 @@ async fn timelock_program_test2() -> Result<()> {
             transferable_by_recipient: false,
             release_rate: 0, // Old contracts don't have it
             stream_name: "Test2".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
         },
     };
 
@@ -312,6 +482,12 @@ async fn timelock_program_test2() -> Result<()> {
             AccountMeta::new_readonly(spl_token::id(), false),
             AccountMeta::new_readonly(spl_associated_token_account::id(), false),
             AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
+            AccountMeta::new_readonly(tt.mint_registry, false),
+            AccountMeta::new(
+                get_associated_token_address(&tt.admin.pubkey(), &strm_token_mint.pubkey()),
+                false,
+            ),
         ],
     );
 
@@ -320,10 +496,10 @@ async fn timelock_program_test2() -> Result<()> {
         .await?;
 
     let metadata_acc = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
 
     assert_eq!(metadata_acc.owner, tt.program_id);
-    assert_eq!(metadata_data.closable_at, now + 510 + 1); // 1 after, like in function
+    assert_eq!(metadata_data.closable_at, now + 510); // Funds run out after 500 periods
 
     assert_eq!(metadata_data.ix.start_time, now + 10);
     assert_eq!(metadata_data.ix.end_time, now + 1010);
@@ -353,6 +529,7 @@ async fn timelock_program_test2() -> Result<()> {
             AccountMeta::new_readonly(spl_token::id(), false),
             AccountMeta::new_readonly(spl_associated_token_account::id(), false),
             AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
@@ -378,13 +555,20 @@ async fn timelock_program_test2() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
+            AccountMeta::new_readonly(tt.mint_registry, false),
+            AccountMeta::new(
+                get_associated_token_address(&tt.admin.pubkey(), &strm_token_mint.pubkey()),
+                false,
+            ),
         ],
     );
     tt.bench
         .process_transaction(&[topupix_bytes], Some(&[&alice]))
         .await?;
     // let metadata_acc = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
     assert_eq!(
         metadata_data.ix.deposited_amount,
         spl_token::ui_amount_to_amount(20.0, 8)
@@ -412,6 +596,7 @@ async fn timelock_program_test2() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
@@ -422,7 +607,10 @@ async fn timelock_program_test2() -> Result<()> {
         .err()
         .unwrap();
 
-    assert_eq!(transaction_error, ProgramError::InvalidArgument);
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::AmountExceedsAvailable as u32)
+    );
 
     let some_other_kp = Keypair::new();
     let cancel_ix = CancelIx { ix: 2 };
@@ -440,10 +628,11 @@ async fn timelock_program_test2() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
-    // It should be IA data error, stream hasn't expired
+    // Stream hasn't expired, only sender or recipient may cancel
     let transaction_error = tt
         .bench
         .process_transaction(&[cancel_ix_bytes], Some(&[&some_other_kp]))
@@ -451,7 +640,10 @@ async fn timelock_program_test2() -> Result<()> {
         .err()
         .unwrap();
 
-    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::CancelNotAllowed as u32)
+    );
 
     // Ahead with time, stream expired
     tt.advance_clock_past_timestamp(now as i64 + 2000).await;
@@ -469,6 +661,7 @@ async fn timelock_program_test2() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
@@ -542,6 +735,14 @@ async fn timelock_program_test_transfer() -> Result<()> {
             transferable_by_recipient: true, // Should be possible to transfer stream
             release_rate: 0,                 // Old contracts don't have it
             stream_name: "TransferStream".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
         },
     };
 
@@ -560,6 +761,12 @@ async fn timelock_program_test_transfer() -> Result<()> {
             AccountMeta::new_readonly(spl_token::id(), false),
             AccountMeta::new_readonly(spl_associated_token_account::id(), false),
             AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
+            AccountMeta::new_readonly(tt.mint_registry, false),
+            AccountMeta::new(
+                get_associated_token_address(&tt.admin.pubkey(), &strm_token_mint.pubkey()),
+                false,
+            ),
         ],
     );
 
@@ -567,7 +774,7 @@ async fn timelock_program_test_transfer() -> Result<()> {
         .process_transaction(&[create_stream_ix_bytes], Some(&[&alice, &metadata_kp]))
         .await?;
 
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
 
     assert_eq!(metadata_data.ix.stream_name, "TransferStream".to_string());
     assert!(metadata_data.ix.transferable_by_recipient);
@@ -588,12 +795,13 @@ async fn timelock_program_test_transfer() -> Result<()> {
             AccountMeta::new_readonly(spl_token::id(), false),
             AccountMeta::new_readonly(spl_associated_token_account::id(), false),
             AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
     tt.bench
         .process_transaction(&[transfer_ix_bytes], Some(&[&bob]))
         .await?;
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
     // Check new recipient
     assert_eq!(metadata_data.recipient, alice.pubkey());
     // Check new recipient token account
@@ -664,6 +872,14 @@ async fn timelock_program_test_recurring() -> Result<()> {
             transferable_by_recipient: false,
             release_rate: spl_token::ui_amount_to_amount(1.0, 8),
             stream_name: "Recurring".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
         },
     };
 
@@ -682,6 +898,12 @@ async fn timelock_program_test_recurring() -> Result<()> {
             AccountMeta::new_readonly(spl_token::id(), false),
             AccountMeta::new_readonly(spl_associated_token_account::id(), false),
             AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
+            AccountMeta::new_readonly(tt.mint_registry, false),
+            AccountMeta::new(
+                get_associated_token_address(&tt.admin.pubkey(), &strm_token_mint.pubkey()),
+                false,
+            ),
         ],
     );
 
@@ -690,10 +912,10 @@ async fn timelock_program_test_recurring() -> Result<()> {
         .await?;
 
     let metadata_acc = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
 
     assert_eq!(metadata_acc.owner, tt.program_id);
-    assert_eq!(metadata_data.closable_at, now + 10 + 2000 + 1); // 1 after, like in function
+    assert_eq!(metadata_data.closable_at, now + 10 + 2000); // 10 periods of 200
     assert_eq!(metadata_data.ix.start_time, now + 10);
     assert_eq!(metadata_data.ix.end_time, now + 1010);
     assert_eq!(
@@ -718,19 +940,26 @@ async fn timelock_program_test_recurring() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
+            AccountMeta::new_readonly(tt.mint_registry, false),
+            AccountMeta::new(
+                get_associated_token_address(&tt.admin.pubkey(), &strm_token_mint.pubkey()),
+                false,
+            ),
         ],
     );
     tt.bench
         .process_transaction(&[topupix_bytes], Some(&[&alice]))
         .await?;
     // let metadata_acc = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
     assert_eq!(
         metadata_data.ix.deposited_amount,
         spl_token::ui_amount_to_amount(30.0, 8)
     );
-    // Closable to end_date, closable fn would return 1010 + 1
-    assert_eq!(metadata_data.closable_at, now + 10 + 6000 + 1);
+    // Recurring ignores end_date, 30 periods of 200
+    assert_eq!(metadata_data.closable_at, now + 10 + 6000);
 
     let some_other_kp = Keypair::new();
     let cancel_ix = CancelIx { ix: 2 };
@@ -748,9 +977,10 @@ async fn timelock_program_test_recurring() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
-    // It should be IA data error, stream hasn't expired
+    // Stream hasn't expired, only sender or recipient may cancel
     let transaction_error = tt
         .bench
         .process_transaction(&[cancel_ix_bytes], Some(&[&some_other_kp]))
@@ -758,7 +988,10 @@ async fn timelock_program_test_recurring() -> Result<()> {
         .err()
         .unwrap();
 
-    assert_eq!(transaction_error, ProgramError::InvalidAccountData);
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::CancelNotAllowed as u32)
+    );
 
     // Try to withdraw more then due
     let withdraw_stream_ix = WithdrawStreamIx {
@@ -778,10 +1011,11 @@ async fn timelock_program_test_recurring() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
-    // It should be Invalid argument error, available < requested amount for withdrawal
+    // available < requested amount for withdrawal
     let transaction_error = tt
         .bench
         .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&bob]))
@@ -789,7 +1023,10 @@ async fn timelock_program_test_recurring() -> Result<()> {
         .err()
         .unwrap();
 
-    assert_eq!(transaction_error, ProgramError::InvalidArgument);
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::AmountExceedsAvailable as u32)
+    );
 
     // Ahead with time, stream expired
     // Beware test clock is not deterministic (check fn)
@@ -815,6 +1052,7 @@ async fn timelock_program_test_recurring() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
@@ -822,7 +1060,7 @@ async fn timelock_program_test_recurring() -> Result<()> {
         .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&bob]))
         .await?;
 
-    let metadata_data: TokenStreamData = tt.bench.get_borsh_account(&metadata_kp.pubkey()).await;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
     assert_eq!(
         metadata_data.withdrawn_amount,
         spl_token::ui_amount_to_amount(25.0, 8)
@@ -844,10 +1082,18 @@ async fn timelock_program_test_recurring() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(system_program::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
+            AccountMeta::new_readonly(tt.mint_registry, false),
+            AccountMeta::new(
+                get_associated_token_address(&tt.admin.pubkey(), &strm_token_mint.pubkey()),
+                false,
+            ),
         ],
     );
 
-    let transaction_error = tt.bench
+    let transaction_error = tt
+        .bench
         .process_transaction(&[topupix_bytes], Some(&[&alice]))
         .await;
     // Stream closed, no topup
@@ -866,6 +1112,7 @@ async fn timelock_program_test_recurring() -> Result<()> {
             AccountMeta::new(escrow_tokens_pubkey, false),
             AccountMeta::new_readonly(strm_token_mint.pubkey(), false),
             AccountMeta::new_readonly(spl_token::id(), false),
+            AccountMeta::new_readonly(tt.config, false),
         ],
     );
 
@@ -875,3 +1122,3040 @@ async fn timelock_program_test_recurring() -> Result<()> {
         .await?;
     Ok(())
 }
+
+#[tokio::test]
+async fn timelock_program_test_pause() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            period: 1,
+            cliff: 0,
+            cliff_amount: 0,
+            cancelable_by_sender: false,
+            cancelable_by_recipient: false,
+            withdrawal_public: false,
+            transferable_by_sender: false,
+            transferable_by_recipient: false,
+            release_rate: 0,
+            stream_name: "Payroll".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: true,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
+        },
+    );
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert!(metadata_data.ix.pausable_by_sender);
+    assert_eq!(metadata_data.paused_at, 0);
+
+    // Recipient is not allowed to pause
+    let pause_ix_bytes = instruction::pause(&tt.program_id, &bob.pubkey(), &metadata_kp.pubkey());
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[pause_ix_bytes], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::PauseNotAllowed as u32)
+    );
+
+    tt.advance_clock_past_timestamp(now as i64 + 110).await;
+
+    let pause_ix_bytes = instruction::pause(&tt.program_id, &alice.pubkey(), &metadata_kp.pubkey());
+
+    tt.bench
+        .process_transaction(&[pause_ix_bytes], Some(&[&alice]))
+        .await?;
+
+    let paused_at = tt.bench.get_clock().await.unix_timestamp as u64;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.paused_at, paused_at);
+    assert_eq!(metadata_data.closable_at, u64::MAX);
+    let vested = metadata_data.available(paused_at)?;
+
+    tt.advance_clock_past_timestamp(paused_at as i64 + 300)
+        .await;
+
+    // Nothing vests while paused
+    let withdraw_stream_ix_bytes = instruction::withdraw(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        0,
+    );
+
+    tt.bench
+        .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&bob]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.withdrawn_amount, vested);
+
+    let resume_ix_bytes =
+        instruction::resume(&tt.program_id, &alice.pubkey(), &metadata_kp.pubkey());
+
+    tt.bench
+        .process_transaction(&[resume_ix_bytes], Some(&[&alice]))
+        .await?;
+
+    let resumed_at = tt.bench.get_clock().await.unix_timestamp as u64;
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.paused_at, 0);
+    assert_eq!(metadata_data.paused_duration, resumed_at - paused_at);
+    assert_eq!(
+        metadata_data.closable_at,
+        now + 1010 + metadata_data.paused_duration
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_cancel_authority() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    // Irrevocable grant, only the recipient may cancel
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            period: 1,
+            cliff: 0,
+            cliff_amount: 0,
+            cancelable_by_sender: false,
+            cancelable_by_recipient: true,
+            withdrawal_public: false,
+            transferable_by_sender: false,
+            transferable_by_recipient: false,
+            release_rate: 0,
+            stream_name: "Grant".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
+        },
+    );
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    tt.advance_clock_past_timestamp(now as i64 + 210).await;
+
+    let cancel_ix_bytes = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+    );
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[cancel_ix_bytes], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::SenderCancelNotAllowed as u32)
+    );
+
+    let cancel_ix_bytes = instruction::cancel(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+    );
+
+    tt.bench
+        .process_transaction(&[cancel_ix_bytes], Some(&[&bob]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert!(metadata_data.canceled_at > 0);
+    assert!(metadata_data.withdrawn_amount < metadata_data.ix.deposited_amount);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_withdraw_public() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let keeper = Keypair::new();
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            period: 1,
+            cliff: 0,
+            cliff_amount: 0,
+            cancelable_by_sender: false,
+            cancelable_by_recipient: false,
+            withdrawal_public: true,
+            transferable_by_sender: false,
+            transferable_by_recipient: false,
+            release_rate: 0,
+            stream_name: "Payroll".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
+        },
+    );
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    tt.advance_clock_past_timestamp(now as i64 + 210).await;
+
+    // Keeper pushes vested tokens to the recipient
+    let withdraw_stream_ix_bytes = instruction::withdraw(
+        &tt.program_id,
+        &keeper.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        0,
+    );
+
+    tt.bench
+        .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&keeper]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert!(metadata_data.withdrawn_amount > 0);
+
+    let bob_ass_account = tt.bench.get_account(&bob_ass_token).await.unwrap();
+    let bob_token_data = spl_token::state::Account::unpack_from_slice(&bob_ass_account.data)?;
+    assert_eq!(bob_token_data.amount, metadata_data.withdrawn_amount);
+
+    // Keeper can't redirect the funds to its own token account
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &keeper.pubkey())
+        .await;
+
+    let withdraw_stream_ix_bytes = instruction::withdraw(
+        &tt.program_id,
+        &keeper.pubkey(),
+        &alice.pubkey(),
+        &keeper.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        0,
+    );
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&keeper]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::MetadataAccountMismatch as u32)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_withdraw_not_public() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let keeper = Keypair::new();
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let metadata_kp = Keypair::new();
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            period: 1,
+            cliff: 0,
+            cliff_amount: 0,
+            cancelable_by_sender: false,
+            cancelable_by_recipient: false,
+            withdrawal_public: false,
+            transferable_by_sender: false,
+            transferable_by_recipient: false,
+            release_rate: 0,
+            stream_name: "Payroll".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
+        },
+    );
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    tt.advance_clock_past_timestamp(now as i64 + 210).await;
+
+    // Keeper pushes vested tokens to the recipient
+    let withdraw_stream_ix_bytes = instruction::withdraw(
+        &tt.program_id,
+        &keeper.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        0,
+    );
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&keeper]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::WithdrawNotAllowed as u32)
+    );
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.withdrawn_amount, 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_create_with_seed() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: 0,
+        stream_name: "Seeded".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: false,
+        acceptance_deadline: 0,
+        keeper_bounty: 0,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 0,
+        crank_interval: 0,
+    };
+
+    let (metadata_pubkey, _) = instruction::find_metadata_address(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &strm_token_mint.pubkey(),
+        1,
+    );
+
+    // Only the sender signs, metadata is a PDA
+    let create_stream_ix = instruction::create_stream_with_seed(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        1,
+        stream_ix.clone(),
+    );
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_pubkey).await;
+    assert_eq!(metadata_data.sender, alice.pubkey());
+    assert_eq!(metadata_data.recipient, bob.pubkey());
+    assert_eq!(
+        metadata_data.escrow_tokens,
+        instruction::find_escrow_address(&tt.program_id, &metadata_pubkey).0
+    );
+
+    // Same seed resolves to the same stream, creating it twice fails
+    let create_stream_ix = instruction::create_stream_with_seed(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        1,
+        stream_ix.clone(),
+    );
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(transaction_error, ProgramError::AccountAlreadyInitialized);
+
+    // A metadata account not matching the seed is rejected
+    let mut create_stream_ix = instruction::create_stream_with_seed(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        2,
+        stream_ix,
+    );
+    create_stream_ix.data = TimelockInstruction::CreateWithSeed {
+        seed: 3,
+        ix: match TimelockInstruction::unpack(&create_stream_ix.data)? {
+            TimelockInstruction::CreateWithSeed { ix, .. } => ix,
+            _ => unreachable!(),
+        },
+    }
+    .try_to_vec()?;
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(transaction_error, ProgramError::InvalidSeeds);
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "only BPF programs are metered, run under cargo test-bpf"]
+async fn timelock_program_test_escrow_bump_compute_units() -> Result<()> {
+    assert!(
+        std::env::var("BPF_OUT_DIR").is_ok(),
+        "Compute units are only metered under cargo test-bpf"
+    );
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    // Pick a metadata account whose escrow bump is below 255, so the
+    // search takes a few attempts and has a measurable cost.
+    let metadata_kp = loop {
+        let kp = Keypair::new();
+        let (_, bump) = instruction::find_escrow_address(&tt.program_id, &kp.pubkey());
+        if bump < u8::MAX - 2 {
+            break kp;
+        }
+    };
+    let (escrow_tokens_pubkey, escrow_tokens_bump) =
+        instruction::find_escrow_address(&tt.program_id, &metadata_kp.pubkey());
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            period: 1,
+            cliff: 0,
+            cliff_amount: 0,
+            cancelable_by_sender: false,
+            cancelable_by_recipient: false,
+            withdrawal_public: false,
+            transferable_by_sender: false,
+            transferable_by_recipient: false,
+            release_rate: 0,
+            stream_name: "Payroll".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
+        },
+    );
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.escrow_tokens, escrow_tokens_pubkey);
+    assert_eq!(metadata_data.escrow_tokens_bump, escrow_tokens_bump);
+
+    // Baseline: the same stream without a stored bump, as left by `migrate`,
+    // derives its escrow with `find_program_address`
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &strm_token_mint.pubkey());
+    let mut accounts = vec![];
+    for address in [
+        metadata_kp.pubkey(),
+        escrow_tokens_pubkey,
+        strm_token_mint.pubkey(),
+        bob_ass_token,
+    ] {
+        accounts.push((address, tt.bench.get_account(&address).await.unwrap()));
+    }
+    accounts[0].1.data[ESCROW_TOKENS_BUMP_OFFSET] = 0;
+    let mut baseline = TimelockProgramTest::start_with_accounts(tt.program_id, &accounts).await;
+
+    tt.advance_clock_past_timestamp(now as i64 + 210).await;
+    baseline
+        .advance_clock_past_timestamp(now as i64 + 210)
+        .await;
+
+    // Withdraw derives the escrow from the stored bump
+    let withdraw_stream_ix_bytes = instruction::withdraw(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        0,
+    );
+
+    tt.bench
+        .process_transaction(&[withdraw_stream_ix_bytes], Some(&[&bob]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert!(metadata_data.withdrawn_amount > 0);
+
+    // Probes withdraw the smallest amount, leave some to withdraw
+    tt.advance_clock_past_timestamp(now as i64 + 310).await;
+    let withdraw_ix = instruction::withdraw(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        1,
+    );
+    let stored_units = tt.compute_units(&withdraw_ix, &bob).await;
+    let find_units = baseline.compute_units(&withdraw_ix, &bob).await;
+    assert_eq!(
+        baseline
+            .get_metadata(&metadata_kp.pubkey())
+            .await
+            .escrow_tokens_bump,
+        0
+    );
+
+    // `find_program_address` pays for one `create_program_address` per bump
+    // tried from 255 down, the stored bump needs exactly one
+    let units = BpfComputeBudget::default().create_program_address_units;
+    let saved = match find_units.checked_sub(stored_units) {
+        Some(saved) if saved > 0 => saved,
+        _ => panic!(
+            "find_program_address took {} units, the stored bump {}",
+            find_units, stored_units
+        ),
+    };
+    assert!(saved >= (u8::MAX - escrow_tokens_bump) as u64 * units);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_error_codes() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: 0,
+        stream_name: "Errors".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: false,
+        acceptance_deadline: 0,
+        keeper_bounty: 0,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 0,
+        crank_interval: 0,
+    };
+
+    let cases = vec![
+        (
+            StreamInstruction {
+                end_time: now + 5,
+                ..stream_ix.clone()
+            },
+            StreamFlowError::InvalidTimestamps,
+        ),
+        (
+            StreamInstruction {
+                stream_name: "x".repeat(65),
+                ..stream_ix.clone()
+            },
+            StreamFlowError::StreamNameTooLong,
+        ),
+        (
+            StreamInstruction {
+                deposited_amount: spl_token::ui_amount_to_amount(1000.0, 8),
+                total_amount: spl_token::ui_amount_to_amount(1000.0, 8),
+                ..stream_ix.clone()
+            },
+            StreamFlowError::InsufficientTokens,
+        ),
+    ];
+
+    for (ix, error) in cases {
+        let metadata_kp = Keypair::new();
+        let create_stream_ix = instruction::create_stream(
+            &tt.program_id,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &metadata_kp.pubkey(),
+            &strm_token_mint.pubkey(),
+            &spl_token::id(),
+            &tt.admin.pubkey(),
+            None,
+            ix,
+        );
+
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(transaction_error, ProgramError::Custom(error as u32));
+    }
+
+    // Escrow account not derived from the metadata account
+    let metadata_kp = Keypair::new();
+    let mut create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        stream_ix.clone(),
+    );
+    create_stream_ix.accounts[5].pubkey = Keypair::new().pubkey();
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidEscrowAccount as u32)
+    );
+
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        stream_ix,
+    );
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let topup_ix = instruction::topup(
+        &tt.program_id,
+        &alice.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        0,
+    );
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[topup_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ZeroAmount as u32)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_native_sol() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let mint = spl_token::native_mint::id();
+    let metadata_kp = Keypair::new();
+    let (escrow, _) = instruction::find_escrow_address(&tt.program_id, &metadata_kp.pubkey());
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+    let deposit = sol_to_lamports(0.5);
+
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: deposit,
+            total_amount: deposit,
+            period: 1,
+            cliff: 0,
+            cliff_amount: 0,
+            cancelable_by_sender: true,
+            cancelable_by_recipient: false,
+            withdrawal_public: false,
+            transferable_by_sender: false,
+            transferable_by_recipient: false,
+            release_rate: 0,
+            stream_name: "Native".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
+        },
+    );
+    // Wallets stand in for the token accounts
+    assert_eq!(create_stream_ix.accounts[1].pubkey, alice.pubkey());
+    assert_eq!(create_stream_ix.accounts[3].pubkey, bob.pubkey());
+
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let escrow_rent = tt.bench.rent.minimum_balance(0);
+    let escrow_account = tt.bench.get_account(&escrow).await.unwrap();
+    assert_eq!(escrow_account.owner, tt.program_id);
+    assert!(escrow_account.data.is_empty());
+    assert_eq!(escrow_account.lamports, escrow_rent + deposit);
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert_eq!(metadata_data.mint, mint);
+    assert_eq!(metadata_data.sender_tokens, alice.pubkey());
+    assert_eq!(metadata_data.recipient_tokens, bob.pubkey());
+
+    tt.advance_clock_past_timestamp(now as i64 + 510).await;
+
+    let bob_lamports = tt.bench.get_account(&bob.pubkey()).await.unwrap().lamports;
+    let withdraw_ix = instruction::withdraw(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+        0,
+    );
+
+    tt.bench
+        .process_transaction(&[withdraw_ix], Some(&[&bob]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert!(metadata_data.withdrawn_amount > 0);
+    assert_eq!(
+        tt.bench.get_account(&bob.pubkey()).await.unwrap().lamports,
+        bob_lamports + metadata_data.withdrawn_amount
+    );
+    assert_eq!(
+        tt.bench.get_account(&escrow).await.unwrap().lamports,
+        escrow_rent + deposit - metadata_data.withdrawn_amount
+    );
+
+    let topup_ix = instruction::topup(
+        &tt.program_id,
+        &alice.pubkey(),
+        &metadata_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+        &tt.admin.pubkey(),
+        None,
+        sol_to_lamports(0.1),
+    );
+
+    tt.bench
+        .process_transaction(&[topup_ix], Some(&[&alice]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    assert_eq!(
+        metadata_data.ix.deposited_amount,
+        deposit + sol_to_lamports(0.1)
+    );
+
+    // Fees are paid by the bench payer, so only the stream moves lamports
+    let alice_lamports = tt
+        .bench
+        .get_account(&alice.pubkey())
+        .await
+        .unwrap()
+        .lamports;
+    let bob_lamports = tt.bench.get_account(&bob.pubkey()).await.unwrap().lamports;
+    let escrow_lamports = tt.bench.get_account(&escrow).await.unwrap().lamports;
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+    );
+
+    tt.bench
+        .process_transaction(&[cancel_ix], Some(&[&alice]))
+        .await?;
+
+    // The escrow is closed, the unvested lamports and its rent go back to the sender
+    assert!(tt.bench.get_account(&escrow).await.is_none());
+    let metadata_data = tt.get_metadata(&metadata_kp.pubkey()).await;
+    let returned = metadata_data.ix.deposited_amount - metadata_data.withdrawn_amount;
+    assert_eq!(
+        tt.bench
+            .get_account(&alice.pubkey())
+            .await
+            .unwrap()
+            .lamports,
+        alice_lamports + escrow_rent + returned
+    );
+    assert_eq!(
+        tt.bench.get_account(&bob.pubkey()).await.unwrap().lamports,
+        bob_lamports + escrow_lamports - escrow_rent - returned
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+#[ignore = "needs the realloc feature, solana-program-test 1.8 rejects account data reallocation"]
+async fn timelock_program_test_migrate() -> Result<()> {
+    let program_id = Keypair::new().pubkey();
+    let metadata_pubkey = Pubkey::new_unique();
+    let sender = Pubkey::new_unique();
+    let recipient = Pubkey::new_unique();
+    let mint = Pubkey::new_unique();
+    let (escrow_tokens, _) = instruction::find_escrow_address(&program_id, &metadata_pubkey);
+
+    // Metadata account as written by the first program version
+    let mut data = vec![];
+    for v in [
+        1u64,          // magic
+        1_600_000_000, // start_time
+        1_600_001_000, // end_time
+        500,           // deposited_amount
+        1_000,         // total_amount
+        10,            // period
+        0,             // cliff
+        0,             // cliff_amount
+        1_599_999_000, // created_at
+        100,           // withdrawn
+        0,             // cancel_time
+    ] {
+        data.extend_from_slice(&v.to_le_bytes());
+    }
+    for key in [
+        sender,
+        get_associated_token_address(&sender, &mint),
+        recipient,
+        get_associated_token_address(&recipient, &mint),
+        mint,
+        escrow_tokens,
+    ] {
+        data.extend_from_slice(key.as_ref());
+    }
+    assert_eq!(data.len(), V1_METADATA_SIZE);
+
+    let v1_account = Account {
+        lamports: solana_sdk::rent::Rent::default().minimum_balance(data.len()),
+        data,
+        owner: program_id,
+        ..Account::default()
+    };
+    let mut tt =
+        TimelockProgramTest::start_with_accounts(program_id, &[(metadata_pubkey, v1_account)])
+            .await;
+    let alice = clone_keypair(&tt.bench.alice);
+
+    let migrate_ix = instruction::migrate(&tt.program_id, &alice.pubkey(), &metadata_pubkey);
+    tt.bench
+        .process_transaction(&[migrate_ix], Some(&[&alice]))
+        .await?;
+
+    let account = tt.bench.get_account(&metadata_pubkey).await.unwrap();
+    let metadata_data = TokenStreamData::load(&account.data)?;
+    assert_eq!(metadata_data.magic, PROGRAM_VERSION);
+    assert_eq!(account.data.len(), metadata_data.space());
+    assert!(tt
+        .bench
+        .rent
+        .is_exempt(account.lamports, account.data.len()));
+    assert_eq!(metadata_data.ix.start_time, 1_600_000_000);
+    assert_eq!(metadata_data.ix.deposited_amount, 500);
+    assert_eq!(metadata_data.withdrawn_amount, 100);
+    assert_eq!(metadata_data.closable_at, 1_600_000_500);
+    assert_eq!(metadata_data.sender, sender);
+    assert_eq!(metadata_data.escrow_tokens, escrow_tokens);
+
+    // Migrating again is a no-op
+    let migrate_ix = instruction::migrate(&tt.program_id, &alice.pubkey(), &metadata_pubkey);
+    tt.bench
+        .process_transaction(&[migrate_ix], Some(&[&alice]))
+        .await?;
+    let migrated = tt.bench.get_account(&metadata_pubkey).await.unwrap();
+    assert_eq!(migrated.data, account.data);
+    assert_eq!(migrated.lamports, account.lamports);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_config() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = clone_keypair(&tt.admin);
+
+    let account = tt.bench.get_account(&tt.config).await.unwrap();
+    assert_eq!(account.owner, tt.program_id);
+    assert_eq!(account.data.len(), CONFIG_SIZE);
+    let config: ProgramConfig = *bytemuck::from_bytes(&account.data);
+    assert_eq!(config.admin, admin.pubkey());
+    assert_eq!(config.max_name_size, 64);
+
+    // The config can only be initialized once
+    let init_config_ix = instruction::init_config(
+        &tt.program_id,
+        &admin.pubkey(),
+        config_params(&admin.pubkey()),
+    );
+    assert!(tt
+        .bench
+        .process_transaction(&[init_config_ix], Some(&[&admin]))
+        .await
+        .is_err());
+
+    // Only the admin can update it
+    let update_config_ix = instruction::update_config(
+        &tt.program_id,
+        &alice.pubkey(),
+        config_params(&alice.pubkey()),
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[update_config_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ConfigUpdateNotAllowed as u32)
+    );
+
+    let transaction_error = tt
+        .update_config(ConfigParams {
+            max_name_size: STREAM_NAME_SIZE as u64 + 1,
+            ..config_params(&admin.pubkey())
+        })
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidConfig as u32)
+    );
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&strm_token_mint.pubkey(), &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: 0,
+        stream_name: "Configured".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: false,
+        acceptance_deadline: 0,
+        keeper_bounty: 0,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 0,
+        crank_interval: 0,
+    };
+
+    let cases = vec![
+        (
+            ConfigParams {
+                max_name_size: 8,
+                ..config_params(&admin.pubkey())
+            },
+            StreamFlowError::StreamNameTooLong,
+        ),
+        (
+            ConfigParams {
+                paused: true,
+                ..config_params(&admin.pubkey())
+            },
+            StreamFlowError::ProgramPaused,
+        ),
+        (
+            ConfigParams {
+                spl_token_allowed: false,
+                ..config_params(&admin.pubkey())
+            },
+            StreamFlowError::TokenProgramNotAllowed,
+        ),
+    ];
+
+    for (params, error) in cases {
+        tt.update_config(params).await?;
+
+        let metadata_kp = Keypair::new();
+        let create_stream_ix = instruction::create_stream(
+            &tt.program_id,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &metadata_kp.pubkey(),
+            &strm_token_mint.pubkey(),
+            &spl_token::id(),
+            &tt.admin.pubkey(),
+            None,
+            stream_ix.clone(),
+        );
+
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+            .await
+            .err()
+            .unwrap();
+
+        assert_eq!(transaction_error, ProgramError::Custom(error as u32));
+    }
+
+    // Hand the config over to bob, the old admin loses access
+    tt.update_config(config_params(&bob.pubkey())).await?;
+    let transaction_error = tt
+        .update_config(config_params(&admin.pubkey()))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ConfigUpdateNotAllowed as u32)
+    );
+
+    let update_config_ix =
+        instruction::update_config(&tt.program_id, &bob.pubkey(), config_params(&bob.pubkey()));
+    tt.bench
+        .process_transaction(&[update_config_ix], Some(&[&bob]))
+        .await?;
+
+    let metadata_kp = Keypair::new();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &bob.pubkey(),
+        None,
+        stream_ix,
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_fees() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = tt.admin.pubkey();
+    let partner = Keypair::new().pubkey();
+
+    tt.update_config(ConfigParams {
+        fee_basis_points: 100,
+        partner_fee_basis_points: 50,
+        ..config_params(&admin)
+    })
+    .await?;
+
+    let strm_token_mint = Keypair::new();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &strm_token_mint.pubkey());
+    let treasury_ass_token = get_associated_token_address(&admin, &strm_token_mint.pubkey());
+    let partner_ass_token = get_associated_token_address(&partner, &strm_token_mint.pubkey());
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    for owner in [&alice.pubkey(), &admin, &partner] {
+        tt.bench
+            .create_associated_token_account(&strm_token_mint.pubkey(), owner)
+            .await;
+    }
+
+    tt.bench
+        .mint_tokens(
+            &strm_token_mint.pubkey(),
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+    // 1% protocol and 0.5% partner fee
+    let deposit = spl_token::ui_amount_to_amount(10.0, 8);
+    let (protocol_fee, partner_fee) = (deposit / 100, deposit / 200);
+
+    let metadata_kp = Keypair::new();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &admin,
+        Some(&partner),
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: deposit,
+            total_amount: 2 * deposit,
+            period: 1,
+            cliff: 0,
+            cliff_amount: 0,
+            cancelable_by_sender: false,
+            cancelable_by_recipient: false,
+            withdrawal_public: false,
+            transferable_by_sender: false,
+            transferable_by_recipient: false,
+            release_rate: 0,
+            stream_name: "Fees".to_string(),
+            vesting_curve: VestingCurve::Linear,
+            pausable_by_sender: false,
+            requires_acceptance: false,
+            acceptance_deadline: 0,
+            keeper_bounty: 0,
+            keeper_bounty_in_tokens: false,
+            keeper_bounty_pool: 0,
+            crank_interval: 0,
+        },
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    let token_amount = |account: Account| -> u64 {
+        spl_token::state::Account::unpack_from_slice(&account.data)
+            .unwrap()
+            .amount
+    };
+
+    let metadata_acc = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
+    let metadata_data = TokenStreamData::load(&metadata_acc.data)?;
+    assert_eq!(metadata_data.partner_tokens, partner_ass_token);
+    assert_eq!(metadata_data.protocol_fee_basis_points, 100);
+    assert_eq!(metadata_data.partner_fee_basis_points, 50);
+    assert_eq!(metadata_data.protocol_fee_amount, protocol_fee);
+    assert_eq!(metadata_data.partner_fee_amount, partner_fee);
+    let escrowed = deposit - protocol_fee - partner_fee;
+    assert_eq!(metadata_data.ix.deposited_amount, escrowed);
+    let escrow = tt.bench.get_account(&metadata_data.escrow_tokens).await;
+    assert_eq!(token_amount(escrow.unwrap()), escrowed);
+    let treasury = tt.bench.get_account(&treasury_ass_token).await;
+    assert_eq!(token_amount(treasury.unwrap()), protocol_fee);
+
+    // Top-ups pay the fees to the stream's partner
+    let topup_ix = instruction::topup(
+        &tt.program_id,
+        &alice.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &admin,
+        None,
+        deposit,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[topup_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidTokenAccount as u32)
+    );
+
+    let topup_ix = instruction::topup(
+        &tt.program_id,
+        &alice.pubkey(),
+        &metadata_kp.pubkey(),
+        &strm_token_mint.pubkey(),
+        &spl_token::id(),
+        &admin,
+        Some(&partner),
+        deposit,
+    );
+    tt.bench
+        .process_transaction(&[topup_ix], Some(&[&alice]))
+        .await?;
+
+    let metadata_acc = tt.bench.get_account(&metadata_kp.pubkey()).await.unwrap();
+    let metadata_data = TokenStreamData::load(&metadata_acc.data)?;
+    assert_eq!(metadata_data.ix.deposited_amount, 2 * escrowed);
+    assert_eq!(metadata_data.protocol_fee_amount, 2 * protocol_fee);
+    assert_eq!(metadata_data.closable(), Ok(now + 1010));
+    let treasury = tt.bench.get_account(&treasury_ass_token).await;
+    assert_eq!(token_amount(treasury.unwrap()), 2 * protocol_fee);
+    let partner_tokens = tt.bench.get_account(&partner_ass_token).await;
+    assert_eq!(token_amount(partner_tokens.unwrap()), 2 * partner_fee);
+    let alice_tokens = tt.bench.get_account(&alice_ass_token).await;
+    assert_eq!(
+        token_amount(alice_tokens.unwrap()),
+        spl_token::ui_amount_to_amount(100.0, 8) - 2 * deposit
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_guardian_pause() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = tt.admin.pubkey();
+    let guardian = Keypair::new();
+
+    tt.update_config(ConfigParams {
+        guardian: guardian.pubkey(),
+        ..config_params(&admin)
+    })
+    .await?;
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: true,
+        cancelable_by_recipient: true,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: true,
+        release_rate: 0,
+        stream_name: "Guarded".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: false,
+        acceptance_deadline: 0,
+        keeper_bounty: 0,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 0,
+        crank_interval: 0,
+    };
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        &admin,
+        None,
+        stream_ix.clone(),
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    // Only the guardian and the admin can pause
+    let set_pause_ix = instruction::set_pause(&tt.program_id, &alice.pubkey(), PAUSE_ALL);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[set_pause_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ConfigUpdateNotAllowed as u32)
+    );
+
+    let set_pause_ix = instruction::set_pause(&tt.program_id, &guardian.pubkey(), PAUSE_ALL);
+    tt.bench
+        .process_transaction(&[set_pause_ix], Some(&[&guardian]))
+        .await?;
+
+    let new_metadata_kp = Keypair::new();
+    let blocked = vec![
+        (
+            instruction::create_stream(
+                &tt.program_id,
+                &alice.pubkey(),
+                &bob.pubkey(),
+                &new_metadata_kp.pubkey(),
+                &mint,
+                &spl_token::id(),
+                &admin,
+                None,
+                stream_ix,
+            ),
+            vec![&alice, &new_metadata_kp],
+        ),
+        (
+            instruction::withdraw(
+                &tt.program_id,
+                &bob.pubkey(),
+                &alice.pubkey(),
+                &bob.pubkey(),
+                &metadata,
+                &mint,
+                &spl_token::id(),
+                0,
+            ),
+            vec![&bob],
+        ),
+        (
+            instruction::cancel(
+                &tt.program_id,
+                &alice.pubkey(),
+                &alice.pubkey(),
+                &bob.pubkey(),
+                &metadata,
+                &mint,
+                &spl_token::id(),
+            ),
+            vec![&alice],
+        ),
+        (
+            instruction::transfer_recipient(
+                &tt.program_id,
+                &bob.pubkey(),
+                &payer.pubkey(),
+                &metadata,
+                &mint,
+                &spl_token::id(),
+            ),
+            vec![&bob],
+        ),
+        (
+            instruction::topup(
+                &tt.program_id,
+                &alice.pubkey(),
+                &metadata,
+                &mint,
+                &spl_token::id(),
+                &admin,
+                None,
+                spl_token::ui_amount_to_amount(1.0, 8),
+            ),
+            vec![&alice],
+        ),
+    ];
+
+    for (ix, signers) in blocked {
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&signers[..]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::InstructionPaused as u32)
+        );
+    }
+
+    // Senders can get their funds back while paused if the config allows
+    // it, even with the whole program paused
+    tt.update_config(ConfigParams {
+        guardian: guardian.pubkey(),
+        sender_cancel_while_paused: true,
+        paused: true,
+        ..config_params(&admin)
+    })
+    .await?;
+
+    // Once the stream is closable anyone may cancel it, but passing the
+    // sender's key without its signature doesn't get through the pause
+    tt.advance_clock_past_timestamp(now as i64 + 1100).await;
+
+    let mut cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    cancel_ix.accounts[0].is_signer = false;
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[cancel_ix], None)
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ProgramPaused as u32)
+    );
+
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[cancel_ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ProgramPaused as u32)
+    );
+
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    tt.bench
+        .process_transaction(&[cancel_ix], Some(&[&alice]))
+        .await?;
+    assert!(tt.get_metadata(&metadata).await.canceled_at > 0);
+
+    // Unpausing lets new streams through again
+    tt.update_config(ConfigParams {
+        guardian: guardian.pubkey(),
+        ..config_params(&admin)
+    })
+    .await?;
+    let set_pause_ix = instruction::set_pause(&tt.program_id, &guardian.pubkey(), 0);
+    tt.bench
+        .process_transaction(&[set_pause_ix], Some(&[&guardian]))
+        .await?;
+
+    let now = tt.bench.get_clock().await.unix_timestamp as u64;
+    let metadata_kp = Keypair::new();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+        &admin,
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+            period: 1,
+            stream_name: "Unpaused".to_string(),
+            ..Default::default()
+        },
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    Ok(())
+}
+
+#[tokio::test]
+#[cfg_attr(not(feature = "realloc"), ignore = "needs the realloc feature")]
+async fn timelock_program_test_mint_registry() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = clone_keypair(&tt.admin);
+
+    let mints = [Keypair::new(), Keypair::new()];
+    for mint in mints.iter() {
+        tt.bench.create_mint(mint, &payer.pubkey()).await;
+        tt.bench
+            .create_associated_token_account(&mint.pubkey(), &alice.pubkey())
+            .await;
+        tt.bench
+            .mint_tokens(
+                &mint.pubkey(),
+                &payer,
+                &get_associated_token_address(&alice.pubkey(), &mint.pubkey()),
+                spl_token::ui_amount_to_amount(100.0, 8),
+            )
+            .await;
+    }
+    let (listed, unlisted) = (mints[0].pubkey(), mints[1].pubkey());
+
+    // Only the admin manages the registry
+    let register_ix = instruction::register_mint(&tt.program_id, &alice.pubkey(), &listed);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[register_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::ConfigUpdateNotAllowed as u32)
+    );
+
+    for mint in [listed, unlisted, Keypair::new().pubkey()] {
+        let register_ix = instruction::register_mint(&tt.program_id, &admin.pubkey(), &mint);
+        tt.bench
+            .process_transaction(&[register_ix], Some(&[&admin]))
+            .await?;
+    }
+    let unregister_ix = instruction::unregister_mint(&tt.program_id, &admin.pubkey(), &unlisted);
+    tt.bench
+        .process_transaction(&[unregister_ix], Some(&[&admin]))
+        .await?;
+    let register_ix = instruction::register_mint(&tt.program_id, &admin.pubkey(), &listed);
+    assert_eq!(
+        tt.bench
+            .process_transaction(&[register_ix], Some(&[&admin]))
+            .await,
+        Err(ProgramError::InvalidArgument)
+    );
+
+    let registry = tt.bench.get_account(&tt.mint_registry).await.unwrap();
+    let registered = registry_mints(&registry.data)?;
+    assert_eq!(registered.len(), 2);
+    assert!(registered.contains(&listed));
+    assert!(!registered.contains(&unlisted));
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(20.0, 8),
+        period: 1,
+        stream_name: "Registry".to_string(),
+        ..Default::default()
+    };
+
+    let cases = vec![
+        (MintRegistryMode::Allowlist, listed, unlisted),
+        (MintRegistryMode::Denylist, unlisted, listed),
+    ];
+
+    for (mode, allowed, denied) in cases {
+        tt.update_config(ConfigParams {
+            mint_registry_mode: mode,
+            ..config_params(&admin.pubkey())
+        })
+        .await?;
+
+        let metadata_kp = Keypair::new();
+        let create_stream_ix = instruction::create_stream(
+            &tt.program_id,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &metadata_kp.pubkey(),
+            &denied,
+            &spl_token::id(),
+            &admin.pubkey(),
+            None,
+            stream_ix.clone(),
+        );
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::MintNotAllowed as u32)
+        );
+
+        let metadata_kp = Keypair::new();
+        let create_stream_ix = instruction::create_stream(
+            &tt.program_id,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &metadata_kp.pubkey(),
+            &allowed,
+            &spl_token::id(),
+            &admin.pubkey(),
+            None,
+            stream_ix.clone(),
+        );
+        tt.bench
+            .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+            .await?;
+
+        // Top-ups are checked too, once the mint leaves the allowlist
+        // or joins the denylist
+        let (unregister, register) = match mode {
+            MintRegistryMode::Allowlist => (Some(allowed), None),
+            _ => (None, Some(allowed)),
+        };
+        if let Some(mint) = unregister {
+            let ix = instruction::unregister_mint(&tt.program_id, &admin.pubkey(), &mint);
+            tt.bench.process_transaction(&[ix], Some(&[&admin])).await?;
+        }
+        if let Some(mint) = register {
+            let ix = instruction::register_mint(&tt.program_id, &admin.pubkey(), &mint);
+            tt.bench.process_transaction(&[ix], Some(&[&admin])).await?;
+        }
+
+        let topup_ix = instruction::topup(
+            &tt.program_id,
+            &alice.pubkey(),
+            &metadata_kp.pubkey(),
+            &allowed,
+            &spl_token::id(),
+            &admin.pubkey(),
+            None,
+            spl_token::ui_amount_to_amount(1.0, 8),
+        );
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[topup_ix], Some(&[&alice]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::MintNotAllowed as u32)
+        );
+
+        // Back to the registry the next case expects
+        if let Some(mint) = unregister {
+            let ix = instruction::register_mint(&tt.program_id, &admin.pubkey(), &mint);
+            tt.bench.process_transaction(&[ix], Some(&[&admin])).await?;
+        }
+        if let Some(mint) = register {
+            let ix = instruction::unregister_mint(&tt.program_id, &admin.pubkey(), &mint);
+            tt.bench.process_transaction(&[ix], Some(&[&admin])).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_acceptance() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = tt.admin.pubkey();
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+    let deposit = spl_token::ui_amount_to_amount(10.0, 8);
+    let token_amount = |account: Account| -> u64 {
+        spl_token::state::Account::unpack_from_slice(&account.data)
+            .unwrap()
+            .amount
+    };
+
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: deposit,
+        total_amount: deposit,
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: false,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: true,
+        release_rate: 0,
+        stream_name: "Grant".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: true,
+        acceptance_deadline: now + 100,
+        keeper_bounty: 0,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 0,
+        crank_interval: 0,
+    };
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        &admin,
+        None,
+        stream_ix.clone(),
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    // The sender doesn't pay for the recipient's token account
+    assert!(tt.bench.get_account(&bob_ass_token).await.is_none());
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert!(metadata_data.pending());
+    assert_eq!(metadata_data.status, StreamStatus::Pending);
+    assert_eq!(metadata_data.closable_at, now + 100);
+
+    tt.advance_clock_past_timestamp(now as i64 + 50).await;
+
+    let withdraw_ix = instruction::withdraw(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        0,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[withdraw_ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::StreamPending as u32)
+    );
+
+    // Only the recipient can accept
+    let accept_ix = instruction::accept(
+        &tt.program_id,
+        &alice.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[accept_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::MetadataAccountMismatch as u32)
+    );
+
+    let accept_ix = instruction::accept(
+        &tt.program_id,
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    tt.bench
+        .process_transaction(&[accept_ix.clone()], Some(&[&bob]))
+        .await?;
+
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert!(!metadata_data.pending());
+    assert_eq!(
+        metadata_data.status_at(metadata_data.accepted_at),
+        StreamStatus::Active
+    );
+    let accepted_at = metadata_data.accepted_at;
+    assert!(accepted_at >= now + 50);
+    // Vesting starts at the acceptance
+    assert_eq!(metadata_data.closable_at, accepted_at + 1000);
+    assert!(tt.bench.get_account(&bob_ass_token).await.is_some());
+
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[accept_ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::StreamNotPending as u32)
+    );
+
+    // The sender can't take an accepted stream back
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[cancel_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::SenderCancelNotAllowed as u32)
+    );
+
+    tt.advance_clock_past_timestamp(accepted_at as i64 + 100)
+        .await;
+    let withdraw_ix = instruction::withdraw(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        0,
+    );
+    tt.bench
+        .process_transaction(&[withdraw_ix], Some(&[&bob]))
+        .await?;
+    let bob_tokens = tt.bench.get_account(&bob_ass_token).await;
+    assert!(token_amount(bob_tokens.unwrap()) >= deposit / 10);
+
+    // Past the deadline a pending stream can't be accepted anymore, and
+    // the sender gets a full refund
+    let now = tt.bench.get_clock().await.unix_timestamp as u64;
+    let late_kp = Keypair::new();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &late_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+        &admin,
+        None,
+        StreamInstruction {
+            start_time: now + 10,
+            end_time: now + 1010,
+            acceptance_deadline: now + 20,
+            ..stream_ix
+        },
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &late_kp]))
+        .await?;
+    let alice_before = token_amount(tt.bench.get_account(&alice_ass_token).await.unwrap());
+
+    tt.advance_clock_past_timestamp(now as i64 + 30).await;
+    let accept_ix = instruction::accept(
+        &tt.program_id,
+        &bob.pubkey(),
+        &late_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[accept_ix], Some(&[&bob]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::AcceptanceExpired as u32)
+    );
+
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &late_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+    );
+    tt.bench
+        .process_transaction(&[cancel_ix], Some(&[&alice]))
+        .await?;
+    let alice_tokens = tt.bench.get_account(&alice_ass_token).await;
+    assert_eq!(token_amount(alice_tokens.unwrap()), alice_before + deposit);
+    let late_data = tt.get_metadata(&late_kp.pubkey()).await;
+    assert_eq!(late_data.status, StreamStatus::Canceled);
+
+    // Closed streams can't be topped up
+    let topup_ix = instruction::topup(
+        &tt.program_id,
+        &alice.pubkey(),
+        &late_kp.pubkey(),
+        &mint,
+        &spl_token::id(),
+        &admin,
+        None,
+        deposit,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[topup_ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::StreamClosed as u32)
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_withdraw_destination() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = tt.admin.pubkey();
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    // A vault owned by some PDA, and an account of another mint
+    let vault_kp = Keypair::new();
+    let vault = vault_kp.pubkey();
+    tt.bench
+        .create_empty_token_account(&vault_kp, &mint, &Pubkey::new_unique())
+        .await;
+    let other_mint = Keypair::new();
+    tt.bench
+        .create_mint(&other_mint, &tt.bench.payer.pubkey())
+        .await;
+    let other_kp = Keypair::new();
+    tt.bench
+        .create_empty_token_account(&other_kp, &other_mint.pubkey(), &bob.pubkey())
+        .await;
+
+    let token_amount = |account: Account| -> u64 {
+        spl_token::state::Account::unpack_from_slice(&account.data)
+            .unwrap()
+            .amount
+    };
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: true,
+        cancelable_by_recipient: false,
+        withdrawal_public: true,
+        transferable_by_sender: false,
+        transferable_by_recipient: true,
+        release_rate: 0,
+        stream_name: "Custody".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: false,
+        acceptance_deadline: 0,
+        keeper_bounty: 0,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 0,
+        crank_interval: 0,
+    };
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        &admin,
+        None,
+        stream_ix,
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+
+    tt.advance_clock_past_timestamp(now as i64 + 110).await;
+
+    let program_id = tt.program_id;
+    let withdraw_to = |authority: &Pubkey, destination: &Pubkey| {
+        instruction::withdraw_to(
+            &program_id,
+            authority,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &metadata,
+            &mint,
+            &spl_token::id(),
+            destination,
+            0,
+        )
+    };
+
+    // Only the recipient chooses where the funds go
+    let failures = vec![
+        (
+            withdraw_to(&payer.pubkey(), &vault),
+            &payer,
+            StreamFlowError::InvalidTokenAccount,
+        ),
+        (
+            withdraw_to(&bob.pubkey(), &other_kp.pubkey()),
+            &bob,
+            StreamFlowError::MintMismatch,
+        ),
+    ];
+    for (ix, signer, error) in failures {
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[ix], Some(&[signer]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(transaction_error, ProgramError::Custom(error as u32));
+    }
+
+    let ix = withdraw_to(&bob.pubkey(), &vault);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    let withdrawn_amount = tt.get_metadata(&metadata).await.withdrawn_amount;
+    assert!(withdrawn_amount > 0);
+    let vault_tokens = tt.bench.get_account(&vault).await;
+    assert_eq!(token_amount(vault_tokens.unwrap()), withdrawn_amount);
+    let bob_tokens = tt.bench.get_account(&bob_ass_token).await;
+    assert_eq!(token_amount(bob_tokens.unwrap()), 0);
+
+    // Public withdrawals follow the registered destination
+    let ix =
+        instruction::set_withdraw_destination(&tt.program_id, &bob.pubkey(), &metadata, &vault);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert_eq!(metadata_data.destination_tokens, vault);
+    assert_eq!(metadata_data.withdraw_destination(), vault);
+
+    tt.advance_clock_past_timestamp(now as i64 + 210).await;
+
+    let ix = withdraw_to(&payer.pubkey(), &bob_ass_token);
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&payer]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidTokenAccount as u32)
+    );
+
+    let ix = withdraw_to(&payer.pubkey(), &vault);
+    tt.bench.process_transaction(&[ix], Some(&[&payer])).await?;
+    let withdrawn_amount = tt.get_metadata(&metadata).await.withdrawn_amount;
+    let vault_tokens = tt.bench.get_account(&vault).await;
+    assert_eq!(token_amount(vault_tokens.unwrap()), withdrawn_amount);
+
+    // Only the recipient registers a destination, and setting their own
+    // account goes back to it
+    let ix = instruction::set_withdraw_destination(
+        &tt.program_id,
+        &alice.pubkey(),
+        &metadata,
+        &alice_ass_token,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::WithdrawNotAllowed as u32)
+    );
+
+    let ix = instruction::set_withdraw_destination(
+        &tt.program_id,
+        &bob.pubkey(),
+        &metadata,
+        &bob_ass_token,
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert_eq!(metadata_data.destination_tokens, Pubkey::default());
+    assert_eq!(metadata_data.withdraw_destination(), bob_ass_token);
+
+    // Cancels pay the recipient's share into the registered destination,
+    // or the recipient's own account in case the destination can't be used
+    let ix =
+        instruction::set_withdraw_destination(&tt.program_id, &bob.pubkey(), &metadata, &vault);
+    tt.bench.process_transaction(&[ix], Some(&[&bob])).await?;
+    let withdrawn_amount = tt.get_metadata(&metadata).await.withdrawn_amount;
+
+    tt.advance_clock_past_timestamp(now as i64 + 310).await;
+
+    let ix = instruction::cancel_to(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        &alice_ass_token,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[ix], Some(&[&alice]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidTokenAccount as u32)
+    );
+
+    let ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    tt.bench.process_transaction(&[ix], Some(&[&alice])).await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert!(metadata_data.canceled_at > 0);
+    let vault_tokens = tt.bench.get_account(&vault).await;
+    assert_eq!(token_amount(vault_tokens.unwrap()), withdrawn_amount);
+    let bob_tokens = tt.bench.get_account(&bob_ass_token).await;
+    assert_eq!(
+        token_amount(bob_tokens.unwrap()),
+        metadata_data.withdrawn_amount - withdrawn_amount
+    );
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn timelock_program_test_keeper_bounty() -> Result<()> {
+    let mut tt = TimelockProgramTest::start_new().await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = tt.admin.pubkey();
+
+    let strm_token_mint = Keypair::new();
+    let mint = strm_token_mint.pubkey();
+    let alice_ass_token = get_associated_token_address(&alice.pubkey(), &mint);
+    let bob_ass_token = get_associated_token_address(&bob.pubkey(), &mint);
+
+    tt.bench
+        .create_mint(&strm_token_mint, &tt.bench.payer.pubkey())
+        .await;
+
+    tt.bench
+        .create_associated_token_account(&mint, &alice.pubkey())
+        .await;
+
+    tt.bench
+        .mint_tokens(
+            &mint,
+            &payer,
+            &alice_ass_token,
+            spl_token::ui_amount_to_amount(100.0, 8),
+        )
+        .await;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+
+    let mut stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        total_amount: spl_token::ui_amount_to_amount(10.0, 8),
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: true,
+        cancelable_by_recipient: false,
+        withdrawal_public: true,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: 0,
+        stream_name: "Cranked".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: false,
+        acceptance_deadline: 0,
+        keeper_bounty: 5_000,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 12_000,
+        crank_interval: 100,
+    };
+
+    // Keepers can only crank public streams, and not more than once per interval
+    let invalid = vec![
+        StreamInstruction {
+            withdrawal_public: false,
+            ..stream_ix.clone()
+        },
+        StreamInstruction {
+            crank_interval: 0,
+            ..stream_ix.clone()
+        },
+    ];
+    for invalid_ix in invalid {
+        let invalid_kp = Keypair::new();
+        let create_stream_ix = instruction::create_stream(
+            &tt.program_id,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &invalid_kp.pubkey(),
+            &mint,
+            &spl_token::id(),
+            &admin,
+            None,
+            invalid_ix,
+        );
+        let transaction_error = tt
+            .bench
+            .process_transaction(&[create_stream_ix], Some(&[&alice, &invalid_kp]))
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(
+            transaction_error,
+            ProgramError::Custom(StreamFlowError::InvalidKeeperBounty as u32)
+        );
+    }
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        &admin,
+        None,
+        stream_ix.clone(),
+    );
+    tt.bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &metadata_kp]))
+        .await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert_eq!(metadata_data.keeper_bounty_left, 12_000);
+
+    tt.advance_clock_past_timestamp(now as i64 + 210).await;
+
+    let crank_ix = instruction::crank(
+        &tt.program_id,
+        &payer.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        &bob_ass_token,
+    );
+    // The lamport pool sits in the metadata account
+    let metadata_lamports = tt.bench.get_account(&metadata).await.unwrap().lamports;
+    tt.bench
+        .process_transaction(&[crank_ix.clone()], Some(&[&payer]))
+        .await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    let withdrawn_amount = metadata_data.withdrawn_amount;
+    assert!(withdrawn_amount > 0);
+    assert_eq!(metadata_data.keeper_bounty_left, 7_000);
+    let paid = metadata_lamports - tt.bench.get_account(&metadata).await.unwrap().lamports;
+    assert_eq!(paid, 5_000);
+
+    // Cranking again before the interval passes pays nothing
+    tt.advance_clock_past_timestamp(now as i64 + 250).await;
+    let metadata_lamports = tt.bench.get_account(&metadata).await.unwrap().lamports;
+    tt.bench
+        .process_transaction(&[crank_ix], Some(&[&payer]))
+        .await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert!(metadata_data.withdrawn_amount > withdrawn_amount);
+    assert_eq!(metadata_data.keeper_bounty_left, 7_000);
+    let metadata_account = tt.bench.get_account(&metadata).await.unwrap();
+    assert_eq!(metadata_account.lamports, metadata_lamports);
+
+    // Neither does leaving some of the available amount in the escrow
+    tt.advance_clock_past_timestamp(now as i64 + 400).await;
+    let withdraw_ix = instruction::withdraw(
+        &tt.program_id,
+        &payer.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+        1,
+    );
+    tt.bench
+        .process_transaction(&[withdraw_ix], Some(&[&payer]))
+        .await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert_eq!(metadata_data.keeper_bounty_left, 7_000);
+
+    // Whatever is left in the pool goes back to the sender on cancel
+    let metadata_lamports = tt.bench.get_account(&metadata).await.unwrap().lamports;
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &spl_token::id(),
+    );
+    tt.bench
+        .process_transaction(&[cancel_ix], Some(&[&alice]))
+        .await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert_eq!(metadata_data.keeper_bounty_left, 0);
+    let refunded = metadata_lamports - tt.bench.get_account(&metadata).await.unwrap().lamports;
+    assert_eq!(refunded, 7_000);
+
+    // Native SOL streams pay keepers in lamports only
+    stream_ix.keeper_bounty_in_tokens = true;
+    let native_kp = Keypair::new();
+    let create_stream_ix = instruction::create_stream(
+        &tt.program_id,
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &native_kp.pubkey(),
+        &spl_token::native_mint::id(),
+        &spl_token::id(),
+        &admin,
+        None,
+        stream_ix,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[create_stream_ix], Some(&[&alice, &native_kp]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::InvalidKeeperBounty as u32)
+    );
+
+    Ok(())
+}
+
+/// Needs BPF builds of Token-2022 and of an associated token account
+/// program supporting it, as `tests/fixtures/spl_token_2022.so` and
+/// `tests/fixtures/spl_associated_token_account.so`. Those aren't in the
+/// repo, so transfer fees are only covered by the unit tests of
+/// `src/token_2022.rs` for now.
+#[tokio::test]
+#[ignore = "Token-2022 fixtures aren't in the repo"]
+async fn timelock_program_test_transfer_fee_mint() -> Result<()> {
+    let programs = [
+        TestBenchProgram {
+            program_name: "spl_token_2022",
+            program_id: token_2022::id(),
+            process_instruction: None,
+        },
+        TestBenchProgram {
+            program_name: "spl_associated_token_account",
+            program_id: spl_associated_token_account::id(),
+            process_instruction: None,
+        },
+    ];
+    let mut tt =
+        TimelockProgramTest::start_with_programs(Keypair::new().pubkey(), &[], &programs).await;
+
+    let alice = clone_keypair(&tt.bench.alice);
+    let bob = clone_keypair(&tt.bench.bob);
+    let payer = clone_keypair(&tt.bench.payer);
+    let admin = tt.admin.pubkey();
+    let token_program = token_2022::id();
+
+    // Mint with a 1% transfer fee and no maximum: base mint padded to the
+    // account size, account type and the `TransferFeeConfig` extension
+    let mint_kp = Keypair::new();
+    let mint = mint_kp.pubkey();
+    let mint_len = spl_token::state::Account::LEN + 1 + 4 + 108;
+    let alice_tokens =
+        token_2022::get_associated_token_address(&alice.pubkey(), &mint, &token_program);
+    let bob_tokens = token_2022::get_associated_token_address(&bob.pubkey(), &mint, &token_program);
+
+    // TransferFeeExtension, InitializeTransferFeeConfig without authorities
+    let mut fee_config_data = vec![26, 0, 0, 0];
+    fee_config_data.extend_from_slice(&100u16.to_le_bytes());
+    fee_config_data.extend_from_slice(&u64::MAX.to_le_bytes());
+    // InitializeMint2 without a freeze authority
+    let mut init_mint_data = vec![20, 8];
+    init_mint_data.extend_from_slice(payer.pubkey().as_ref());
+    init_mint_data.push(0);
+    // MintTo
+    let mut mint_to_data = vec![7];
+    mint_to_data.extend_from_slice(&spl_token::ui_amount_to_amount(100.0, 8).to_le_bytes());
+
+    let setup_ixs = [
+        system_instruction::create_account(
+            &payer.pubkey(),
+            &mint,
+            tt.bench.rent.minimum_balance(mint_len),
+            mint_len as u64,
+            &token_program,
+        ),
+        Instruction::new_with_bytes(
+            token_program,
+            &fee_config_data,
+            vec![AccountMeta::new(mint, false)],
+        ),
+        Instruction::new_with_bytes(
+            token_program,
+            &init_mint_data,
+            vec![AccountMeta::new(mint, false)],
+        ),
+        token_2022::create_associated_token_account(
+            &payer.pubkey(),
+            &alice.pubkey(),
+            &mint,
+            &token_program,
+        ),
+        Instruction::new_with_bytes(
+            token_program,
+            &mint_to_data,
+            vec![
+                AccountMeta::new(mint, false),
+                AccountMeta::new(alice_tokens, false),
+                AccountMeta::new_readonly(payer.pubkey(), true),
+            ],
+        ),
+    ];
+    tt.bench
+        .process_transaction(&setup_ixs, Some(&[&mint_kp]))
+        .await?;
+
+    let clock = tt.bench.get_clock().await;
+    let now = clock.unix_timestamp as u64;
+    let total = spl_token::ui_amount_to_amount(10.0, 8);
+
+    let stream_ix = StreamInstruction {
+        start_time: now + 10,
+        end_time: now + 1010,
+        deposited_amount: total,
+        total_amount: total,
+        period: 1,
+        cliff: 0,
+        cliff_amount: 0,
+        cancelable_by_sender: true,
+        cancelable_by_recipient: false,
+        withdrawal_public: false,
+        transferable_by_sender: false,
+        transferable_by_recipient: false,
+        release_rate: 0,
+        stream_name: "Fee mint".to_string(),
+        vesting_curve: VestingCurve::Linear,
+        pausable_by_sender: false,
+        requires_acceptance: false,
+        acceptance_deadline: 0,
+        keeper_bounty: 0,
+        keeper_bounty_in_tokens: false,
+        keeper_bounty_pool: 0,
+        crank_interval: 0,
+    };
+
+    let metadata_kp = Keypair::new();
+    let metadata = metadata_kp.pubkey();
+    let canceled_kp = Keypair::new();
+    let canceled = canceled_kp.pubkey();
+    for metadata_kp in [&metadata_kp, &canceled_kp] {
+        let create_stream_ix = instruction::create_stream(
+            &tt.program_id,
+            &alice.pubkey(),
+            &bob.pubkey(),
+            &metadata_kp.pubkey(),
+            &mint,
+            &token_program,
+            &admin,
+            None,
+            stream_ix.clone(),
+        );
+        tt.bench
+            .process_transaction(&[create_stream_ix], Some(&[&alice, metadata_kp]))
+            .await?;
+    }
+
+    let token_amount = |account: Account| -> u64 {
+        spl_token::state::Account::unpack_from_slice(&account.data)
+            .unwrap()
+            .amount
+    };
+    let mint_data = tt.bench.get_account(&mint).await.unwrap().data;
+    let fee = |amount: u64| token_2022::transfer_fee(&mint_data, clock.epoch, amount).unwrap();
+
+    // The stream holds the deposit net of the withheld fee, and stays
+    // fully funded until its end
+    let deposit_fee = fee(total);
+    assert_eq!(deposit_fee, total / 100);
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert_eq!(metadata_data.ix.deposited_amount, total - deposit_fee);
+    assert_eq!(metadata_data.transfer_fee_amount, deposit_fee);
+    assert_eq!(metadata_data.gross_deposited(), total);
+    assert_eq!(metadata_data.closable_at, stream_ix.end_time);
+
+    tt.advance_clock_past_timestamp(now as i64 + 510).await;
+
+    // Nobody else can cancel a fully funded stream before its end
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &payer.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &canceled,
+        &mint,
+        &token_program,
+    );
+    let transaction_error = tt
+        .bench
+        .process_transaction(&[cancel_ix], Some(&[&payer]))
+        .await
+        .err()
+        .unwrap();
+    assert_eq!(
+        transaction_error,
+        ProgramError::Custom(StreamFlowError::CancelNotAllowed as u32)
+    );
+
+    let alice_before = token_amount(tt.bench.get_account(&alice_tokens).await.unwrap());
+    let cancel_ix = instruction::cancel(
+        &tt.program_id,
+        &alice.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &canceled,
+        &mint,
+        &token_program,
+    );
+    tt.bench
+        .process_transaction(&[cancel_ix], Some(&[&alice]))
+        .await?;
+    let canceled_data = tt.get_metadata(&canceled).await;
+    assert_eq!(canceled_data.status, StreamStatus::Canceled);
+    let recipient_amount = canceled_data.withdrawn_amount;
+    let sender_amount = canceled_data.ix.deposited_amount - recipient_amount;
+    assert!(recipient_amount > 0 && sender_amount > 0);
+    let bob_tokens_amount = token_amount(tt.bench.get_account(&bob_tokens).await.unwrap());
+    assert_eq!(bob_tokens_amount, recipient_amount - fee(recipient_amount));
+    let alice_after = token_amount(tt.bench.get_account(&alice_tokens).await.unwrap());
+    assert_eq!(
+        alice_after - alice_before,
+        sender_amount - fee(sender_amount)
+    );
+
+    tt.advance_clock_past_timestamp(now as i64 + 1010).await;
+
+    let withdraw_ix = instruction::withdraw(
+        &tt.program_id,
+        &bob.pubkey(),
+        &alice.pubkey(),
+        &bob.pubkey(),
+        &metadata,
+        &mint,
+        &token_program,
+        0,
+    );
+    tt.bench
+        .process_transaction(&[withdraw_ix], Some(&[&bob]))
+        .await?;
+    let metadata_data = tt.get_metadata(&metadata).await;
+    assert_eq!(metadata_data.withdrawn_amount, total - deposit_fee);
+    assert_eq!(metadata_data.status, StreamStatus::Completed);
+    let withdrawn = metadata_data.withdrawn_amount;
+    let received =
+        token_amount(tt.bench.get_account(&bob_tokens).await.unwrap()) - bob_tokens_amount;
+    assert_eq!(received, withdrawn - fee(withdrawn));
+
+    Ok(())
+}